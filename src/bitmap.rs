@@ -0,0 +1,83 @@
+//! A format-independent representation of decoded bitmap subtitles, which is
+//! what the preprocessor works on.
+
+use crate::idx;
+
+/// A rectangle on screen, in pixels.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Rect {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+/// A decoded bitmap subtitle.
+#[derive(Debug)]
+pub struct BitmapSubtitle {
+    /// Start time of subtitle, in seconds.
+    pub start_time: f64,
+    /// End time of subtitle, in seconds.
+    pub end_time: f64,
+    /// Should this subtitle be shown even when subtitles are off?
+    pub force: bool,
+    /// Where the bitmap is displayed on screen.
+    pub area: Rect,
+    /// sRGB color and alpha of each palette entry.
+    pub palette: Vec<[u8; 4]>,
+    /// Whether the palette entries are the four color roles of a DVD
+    /// subtitle: background, pattern, emphasis 1 and emphasis 2.
+    pub dvd_roles: bool,
+    /// Palette index of each pixel, in row-major order.
+    pub pixels: Vec<u8>,
+}
+
+impl BitmapSubtitle {
+    /// Convert a DVD subtitle, resolving its four colors through the 16-color
+    /// palette.
+    pub fn from_vobsub(subtitle: &vobsub::Subtitle, palette: &idx::Palette) -> Self {
+        let coordinates = subtitle.coordinates();
+        // The sub palette and alpha are stored in reverse order of the raw
+        // pixel values.
+        let palette = (0..4)
+            .map(|i| {
+                let [r, g, b] = palette[subtitle.palette()[3 - i] as usize];
+                let alpha = subtitle.alpha()[3 - i];
+                [r, g, b, alpha << 4 | alpha]
+            })
+            .collect();
+        Self {
+            start_time: subtitle.start_time(),
+            end_time: subtitle.end_time(),
+            force: subtitle.force(),
+            area: Rect {
+                x: coordinates.left() as u32,
+                y: coordinates.top() as u32,
+                width: coordinates.width() as u32,
+                height: coordinates.height() as u32,
+            },
+            palette,
+            dvd_roles: true,
+            pixels: subtitle.raw_image().to_vec(),
+        }
+    }
+}
+
+/// Convert a BT.601 studio-swing color, as used on DVDs and in SD
+/// broadcasts, to sRGB.
+pub fn ycrcb_to_rgb_bt601(y: u8, cr: u8, cb: u8) -> [u8; 3] {
+    let y = 1.164 * (y as f32 - 16.0);
+    let cr = cr as f32 - 128.0;
+    let cb = cb as f32 - 128.0;
+    [y + 1.596 * cr, y - 0.813 * cr - 0.391 * cb, y + 2.018 * cb]
+        .map(|channel| channel.round().clamp(0.0, 255.0) as u8)
+}
+
+/// Convert a BT.709 studio-swing color, as used on Blu-ray, to sRGB.
+pub fn ycrcb_to_rgb_bt709(y: u8, cr: u8, cb: u8) -> [u8; 3] {
+    let y = 1.164 * (y as f32 - 16.0);
+    let cr = cr as f32 - 128.0;
+    let cb = cb as f32 - 128.0;
+    [y + 1.793 * cr, y - 0.533 * cr - 0.213 * cb, y + 2.112 * cb]
+        .map(|channel| channel.round().clamp(0.0, 255.0) as u8)
+}
@@ -0,0 +1,323 @@
+//! An external OCR program as an OCR engine. Each line image is written to
+//! the program's standard input, or to a temporary file whose path is put in
+//! place of `{}` in its arguments, and the text is read from its standard
+//! output, either as plain UTF-8 text or as a JSON object with the text and
+//! its confidence.
+
+use crate::engine::{self, Recognition, Recognizer, RecognizerFactory, Word};
+use clap::ValueEnum;
+use image::{
+    codecs::pnm::{PnmSubtype, SampleEncoding},
+    DynamicImage, GrayImage, ImageOutputFormat,
+};
+use serde_json::Value;
+use snafu::{OptionExt, ResultExt, Snafu};
+use std::{
+    fs::{self, OpenOptions},
+    io::{self, Cursor, Write},
+    path::PathBuf,
+    process::{Command, ExitStatus, Stdio},
+    string::FromUtf8Error,
+    sync::atomic::{AtomicUsize, Ordering},
+    thread,
+};
+
+/// Confidence of text from programs which do not give one.
+const UNKNOWN_CONFIDENCE: f32 = 100.0;
+
+/// Placeholder for the path of the image file in the arguments.
+const IMAGE_PLACEHOLDER: &str = "{}";
+
+#[derive(Debug, Snafu)]
+pub enum Error {
+    #[snafu(display("Could not write image to memory: {}", source))]
+    EncodeImage { source: image::ImageError },
+
+    #[snafu(display("Could not write image file {}: {}", filename.display(), source))]
+    WriteImageFile {
+        filename: PathBuf,
+        source: io::Error,
+    },
+
+    #[snafu(display("Could not run OCR command {}: {}", program, source))]
+    RunCommand { program: String, source: io::Error },
+
+    #[snafu(display("OCR command {} failed with {}: {}", program, status, stderr))]
+    CommandFailed {
+        program: String,
+        status: ExitStatus,
+        stderr: String,
+    },
+
+    #[snafu(display("OCR command output is not UTF-8: {}", source))]
+    OutputText { source: FromUtf8Error },
+
+    #[snafu(display("Invalid JSON output of OCR command: {}", source))]
+    OutputJson { source: serde_json::Error },
+
+    #[snafu(display("JSON output of OCR command has no \"text\" string"))]
+    OutputJsonText,
+}
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+/// An image format to pass line images to an OCR command in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum ImageFormat {
+    Png,
+    /// Binary PGM, as given to Tesseract.
+    Pnm,
+}
+
+impl ImageFormat {
+    fn extension(self) -> &'static str {
+        match self {
+            ImageFormat::Png => "png",
+            ImageFormat::Pnm => "pgm",
+        }
+    }
+
+    fn output_format(self) -> ImageOutputFormat {
+        match self {
+            ImageFormat::Png => ImageOutputFormat::Png,
+            ImageFormat::Pnm => ImageOutputFormat::Pnm(PnmSubtype::Graymap(SampleEncoding::Binary)),
+        }
+    }
+}
+
+/// An external OCR program, run once for each line image.
+#[derive(Debug, Clone)]
+pub struct CommandEngine {
+    program: String,
+    args: Vec<String>,
+    format: ImageFormat,
+}
+
+impl CommandEngine {
+    pub fn new(program: &str, args: &[String], format: ImageFormat) -> Self {
+        CommandEngine {
+            program: program.to_owned(),
+            args: args.to_vec(),
+            format,
+        }
+    }
+
+    /// Run the command on an image and read what it recognized.
+    fn run(&self, image: GrayImage) -> Result<Recognition> {
+        let mut bytes = Cursor::new(Vec::new());
+        DynamicImage::ImageLuma8(image)
+            .write_to(&mut bytes, self.format.output_format())
+            .context(EncodeImageSnafu {})?;
+        let bytes = bytes.into_inner();
+
+        let mut command = Command::new(&self.program);
+        command.stdout(Stdio::piped()).stderr(Stdio::piped());
+        let image_file = if self.args.iter().any(|arg| arg == IMAGE_PLACEHOLDER) {
+            let image_file = TempFile::create(self.format.extension(), &bytes)?;
+            for arg in &self.args {
+                if arg == IMAGE_PLACEHOLDER {
+                    command.arg(&image_file.0);
+                } else {
+                    command.arg(arg);
+                }
+            }
+            command.stdin(Stdio::null());
+            Some(image_file)
+        } else {
+            command.args(&self.args).stdin(Stdio::piped());
+            None
+        };
+
+        let mut child = command.spawn().context(RunCommandSnafu {
+            program: &self.program,
+        })?;
+        // Write the image from another thread, so that a program which
+        // starts writing before it has read all of it does not block.
+        let writer = child.stdin.take().map(|mut stdin| {
+            thread::spawn(move || {
+                // A program may not read all of its input; that is up to it.
+                let _ = stdin.write_all(&bytes);
+            })
+        });
+        let output = child.wait_with_output().context(RunCommandSnafu {
+            program: &self.program,
+        })?;
+        if let Some(writer) = writer {
+            let _ = writer.join();
+        }
+        drop(image_file);
+
+        if !output.status.success() {
+            return Err(Error::CommandFailed {
+                program: self.program.clone(),
+                status: output.status,
+                stderr: String::from_utf8_lossy(&output.stderr).trim().to_owned(),
+            });
+        }
+        parse_output(&String::from_utf8(output.stdout).context(OutputTextSnafu {})?)
+    }
+}
+
+impl RecognizerFactory for CommandEngine {
+    fn recognizer(&self) -> engine::Result<Box<dyn Recognizer>> {
+        Ok(Box::new(self.clone()))
+    }
+}
+
+impl Recognizer for CommandEngine {
+    fn recognize(
+        &mut self,
+        image: GrayImage,
+        _dpi: i32,
+        _learn: bool,
+    ) -> engine::Result<Recognition> {
+        self.run(image).context(engine::CommandSnafu {})
+    }
+}
+
+/// A temporary file, removed when dropped.
+struct TempFile(PathBuf);
+
+impl TempFile {
+    /// Write data to a new temporary file. The file must not exist yet, so
+    /// that a file or symlink put in its place by another user of the
+    /// temporary directory is never written through; such names are skipped.
+    fn create(extension: &str, data: &[u8]) -> Result<Self> {
+        static COUNT: AtomicUsize = AtomicUsize::new(0);
+        loop {
+            let count = COUNT.fetch_add(1, Ordering::Relaxed);
+            let filename = std::env::temp_dir().join(format!(
+                "vobsubocr-{}-{}.{}",
+                std::process::id(),
+                count,
+                extension
+            ));
+            let mut file = match OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .open(&filename)
+            {
+                Ok(file) => file,
+                Err(e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+                Err(source) => return Err(Error::WriteImageFile { filename, source }),
+            };
+            let temp_file = TempFile(filename);
+            file.write_all(data).context(WriteImageFileSnafu {
+                filename: &temp_file.0,
+            })?;
+            return Ok(temp_file);
+        }
+    }
+}
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.0);
+    }
+}
+
+/// Read the output of an OCR command: a JSON object like
+/// `{"text": "Hello", "confidence": 93.5}` if it starts with `{`, where the
+/// confidence from 0 to 100 and a list of `words` with `text`, `confidence`,
+/// `x`, `y`, `width` and `height` are optional, or else plain text.
+fn parse_output(output: &str) -> Result<Recognition> {
+    if !output.trim_start().starts_with('{') {
+        return Ok(Recognition {
+            text: output.to_owned(),
+            confidence: UNKNOWN_CONFIDENCE,
+            words: None,
+        });
+    }
+    let json: Value = serde_json::from_str(output).context(OutputJsonSnafu {})?;
+    let text = json["text"]
+        .as_str()
+        .context(OutputJsonTextSnafu {})?
+        .to_owned();
+    let confidence = json["confidence"]
+        .as_f64()
+        .map_or(UNKNOWN_CONFIDENCE, |confidence| confidence as f32);
+    let words = json["words"].as_array().map(|words| {
+        words
+            .iter()
+            .filter_map(|word| {
+                let number = |key: &str| word[key].as_u64().map(|value| value as u32);
+                Some(Word {
+                    text: word["text"].as_str()?.to_owned(),
+                    confidence: word["confidence"]
+                        .as_f64()
+                        .map_or(confidence, |confidence| confidence as f32),
+                    x: number("x")?,
+                    y: number("y")?,
+                    width: number("width")?,
+                    height: number("height")?,
+                })
+            })
+            .collect()
+    });
+    Ok(Recognition {
+        text,
+        confidence,
+        words,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_plain_text() {
+        let recognition = parse_output("Hello there\n").unwrap();
+        assert_eq!(recognition.text, "Hello there\n");
+        assert_eq!(recognition.confidence, UNKNOWN_CONFIDENCE);
+        assert!(recognition.words.is_none());
+    }
+
+    #[test]
+    fn reads_json_with_confidence_and_words() {
+        let recognition = parse_output(
+            r#"{"text": "Hi you", "confidence": 87.5, "words": [
+                {"text": "Hi", "confidence": 90, "x": 0, "y": 1, "width": 10, "height": 12},
+                {"text": "you", "x": 14, "y": 1, "width": 16, "height": 12},
+                {"text": "lost"}
+            ]}"#,
+        )
+        .unwrap();
+        assert_eq!(recognition.text, "Hi you");
+        assert_eq!(recognition.confidence, 87.5);
+        let words = recognition.words.unwrap();
+        assert_eq!(words.len(), 2);
+        assert_eq!(words[0].text, "Hi");
+        assert_eq!(words[0].confidence, 90.0);
+        assert_eq!(
+            (words[1].x, words[1].y, words[1].width, words[1].height),
+            (14, 1, 16, 12)
+        );
+        // Words without a confidence take the one of the line.
+        assert_eq!(words[1].confidence, 87.5);
+    }
+
+    #[test]
+    fn reads_json_with_only_text() {
+        let recognition = parse_output(r#" {"text": "Hello"}"#).unwrap();
+        assert_eq!(recognition.text, "Hello");
+        assert_eq!(recognition.confidence, UNKNOWN_CONFIDENCE);
+        assert!(recognition.words.is_none());
+    }
+
+    #[test]
+    fn rejects_json_without_text() {
+        assert!(matches!(
+            parse_output(r#"{"confidence": 50}"#),
+            Err(Error::OutputJsonText)
+        ));
+        assert!(matches!(
+            parse_output(r#"{"text": 5}"#),
+            Err(Error::OutputJsonText)
+        ));
+        assert!(matches!(
+            parse_output("{not json"),
+            Err(Error::OutputJson { .. })
+        ));
+    }
+}
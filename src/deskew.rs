@@ -0,0 +1,230 @@
+//! Straightening line images: rotating lines of text which are not level,
+//! and shearing slanted text upright. Both are estimated from projection
+//! profiles, which are sharpest when the rows of text, or the vertical
+//! strokes of the glyphs, line up with the pixel grid.
+
+use image::{imageops, GrayImage, Luma};
+
+/// Largest rotation, in radians, to test lines of text for, and the step to
+/// test it in.
+const MAX_SKEW: f32 = 0.26;
+const SKEW_STEP: f32 = 0.005;
+
+/// Range of slants, in horizontal pixels per pixel of height, to test lines
+/// of text for, and the number of steps to test them in.
+const MIN_SLANT: f32 = -0.2;
+const MAX_SLANT: f32 = 0.5;
+const SLANT_STEPS: usize = 28;
+
+/// Coordinates of the text pixels of a binarized image.
+fn text_pixels(image: &GrayImage) -> Vec<(f32, f32)> {
+    image
+        .enumerate_pixels()
+        .filter(|(_, _, pixel)| pixel[0] < 128)
+        .map(|(x, y, _)| (x as f32, y as f32))
+        .collect()
+}
+
+/// Sum of squares of the counts of values in each bin of width one. With a
+/// fixed number of values, it grows with the variance of the counts, so it
+/// is greatest for the sharpest profile.
+fn profile_sharpness(values: impl Iterator<Item = f32>) -> usize {
+    let mut values: Vec<i64> = values.map(|value| value.round() as i64).collect();
+    values.sort_unstable();
+    values
+        .chunk_by(|a, b| a == b)
+        .map(|bin| bin.len() * bin.len())
+        .sum()
+}
+
+/// Estimate the rotation of a line of text, in radians clockwise, by trying
+/// out rotations which would level it. The right one lines up the baseline,
+/// the x-height and the top of the text with rows of pixels, which makes the
+/// row counts of the rotated pixels vary the most.
+pub fn estimate_skew(image: &GrayImage) -> f32 {
+    let pixels = text_pixels(image);
+    let steps = (MAX_SKEW / SKEW_STEP).round() as i32;
+    let mut best = (0, 0.0);
+    // Test the smallest rotations first, so that they win ties.
+    for step in (0..=steps).flat_map(|step| [step, -step]) {
+        let angle = step as f32 * SKEW_STEP;
+        let (sin, cos) = angle.sin_cos();
+        let score = profile_sharpness(pixels.iter().map(|&(x, y)| y * cos - x * sin));
+        if score > best.0 {
+            best = (score, angle);
+        }
+    }
+    best.1
+}
+
+/// Estimate the slant of the text on a line, as the horizontal shift per
+/// pixel of height, by trying out shears that would make it upright. The
+/// right one lines up the vertical strokes, which makes the column counts of
+/// the sheared pixels vary the most.
+pub fn estimate_slant(image: &GrayImage) -> f32 {
+    let pixels = text_pixels(image);
+    let bottom = image.height() as f32;
+    let mut best = (0, 0.0);
+    for step in 0..=SLANT_STEPS {
+        let slant = MIN_SLANT + (MAX_SLANT - MIN_SLANT) * step as f32 / SLANT_STEPS as f32;
+        let score = profile_sharpness(pixels.iter().map(|&(x, y)| x - slant * (bottom - y)));
+        if score > best.0 {
+            best = (score, slant);
+        }
+    }
+    best.1
+}
+
+/// Rotate a binarized image counterclockwise by an angle in radians, so that
+/// text skewed by that angle is level. The image is cropped to the text.
+pub fn rotate(image: &GrayImage, angle: f32) -> GrayImage {
+    if angle == 0.0 {
+        return image.clone();
+    }
+    let (sin, cos) = angle.sin_cos();
+    let (width, height) = (image.width() as f32, image.height() as f32);
+    let rotated_width = (width * cos.abs() + height * sin.abs()).ceil();
+    let rotated_height = (width * sin.abs() + height * cos.abs()).ceil();
+    let mut rotated =
+        GrayImage::from_pixel(rotated_width as u32, rotated_height as u32, Luma([255]));
+    for (x, y, pixel) in rotated.enumerate_pixels_mut() {
+        // Map the center of each pixel back onto the original image.
+        let dx = x as f32 + 0.5 - rotated_width / 2.0;
+        let dy = y as f32 + 0.5 - rotated_height / 2.0;
+        let source_x = dx * cos - dy * sin + width / 2.0;
+        let source_y = dx * sin + dy * cos + height / 2.0;
+        if source_x >= 0.0 && source_y >= 0.0 && source_x < width && source_y < height {
+            *pixel = *image.get_pixel(source_x as u32, source_y as u32);
+        }
+    }
+    crop_to_text(&rotated)
+}
+
+/// Crop a binarized image to the bounding box of its text pixels.
+fn crop_to_text(image: &GrayImage) -> GrayImage {
+    let pixels = text_pixels(image);
+    if pixels.is_empty() {
+        return image.clone();
+    }
+    let (left, right, top, bottom) = pixels.iter().fold(
+        (f32::MAX, 0.0, f32::MAX, 0.0),
+        |(left, right, top, bottom), &(x, y)| {
+            (
+                left.min(x),
+                f32::max(right, x),
+                top.min(y),
+                f32::max(bottom, y),
+            )
+        },
+    );
+    imageops::crop_imm(
+        image,
+        left as u32,
+        top as u32,
+        (right - left) as u32 + 1,
+        (bottom - top) as u32 + 1,
+    )
+    .to_image()
+}
+
+/// Shear a binarized image horizontally, so that text slanted by the given
+/// horizontal shift per pixel of height is upright. The image widens to
+/// hold all of it.
+pub fn shear(image: &GrayImage, slant: f32) -> GrayImage {
+    if slant == 0.0 {
+        return image.clone();
+    }
+    let (width, height) = image.dimensions();
+    let bottom = height as f32;
+    let extra = (slant.abs() * bottom).ceil();
+    // Upright text keeps its top row where it was for positive slants, and
+    // its bottom row for negative ones.
+    let offset = if slant > 0.0 { extra } else { 0.0 };
+    let mut sheared = GrayImage::from_pixel(width + extra as u32, height, Luma([255]));
+    for (x, y, pixel) in sheared.enumerate_pixels_mut() {
+        let source_x = x as f32 + 0.5 - offset + slant * (bottom - y as f32 - 0.5);
+        if source_x >= 0.0 && source_x < width as f32 {
+            *pixel = *image.get_pixel(source_x as u32, y);
+        }
+    }
+    sheared
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A line of 4x10 letters with a margin around it.
+    fn letters() -> GrayImage {
+        let mut image = GrayImage::from_pixel(90, 40, Luma([255]));
+        for x in (10..80).step_by(6) {
+            for dx in 0..4 {
+                for y in 15..25 {
+                    image.put_pixel(x + dx, y, Luma([0]));
+                }
+            }
+        }
+        image
+    }
+
+    /// An image of three 2x20 strokes, each leaning right by `slant` pixels
+    /// per pixel of height.
+    fn strokes(slant: f32) -> GrayImage {
+        let mut image = GrayImage::from_pixel(40, 20, Luma([255]));
+        for y in 0..20 {
+            let shift = (slant * (20 - y) as f32).round() as u32;
+            for stroke in 0..3 {
+                let x = 4 + stroke * 10 + shift;
+                image.put_pixel(x, y, Luma([0]));
+                image.put_pixel(x + 1, y, Luma([0]));
+            }
+        }
+        image
+    }
+
+    #[test]
+    fn recovers_a_rotation() {
+        assert_eq!(estimate_skew(&letters()), 0.0);
+        // Rotating clockwise skews the text by the same angle.
+        let skewed = rotate(&letters(), -0.1);
+        let skew = estimate_skew(&skewed);
+        assert!((skew - 0.1).abs() < 0.011, "{}", skew);
+        let level = estimate_skew(&rotate(&skewed, skew));
+        assert!(level.abs() < 0.011, "{}", level);
+    }
+
+    #[test]
+    fn recovers_a_shear() {
+        assert!(estimate_slant(&strokes(0.0)).abs() < 0.03);
+        let slant = estimate_slant(&strokes(0.25));
+        assert!((slant - 0.25).abs() < 0.03, "{}", slant);
+        let upright = estimate_slant(&shear(&strokes(0.25), slant));
+        assert!(upright.abs() < 0.03, "{}", upright);
+    }
+
+    #[test]
+    fn crops_to_the_text() {
+        let cropped = crop_to_text(&letters());
+        assert_eq!(cropped.dimensions(), (70, 10));
+        assert_eq!(text_pixels(&cropped).len(), text_pixels(&letters()).len());
+    }
+
+    #[test]
+    fn keeps_the_text_when_rotating() {
+        let original = text_pixels(&letters()).len() as f32;
+        for angle in [-0.2, -0.05, 0.05, 0.2] {
+            let rotated = rotate(&letters(), angle);
+            let pixels = text_pixels(&rotated);
+            // Nearest-neighbour sampling gains or loses a few pixels along
+            // the edges, but none are cut off by the crop.
+            assert!((pixels.len() as f32 - original).abs() < original * 0.1);
+            let (width, height) = rotated.dimensions();
+            assert!(pixels.iter().any(|&(x, _)| x == 0.0));
+            assert!(pixels.iter().any(|&(x, _)| x == (width - 1) as f32));
+            assert!(pixels.iter().any(|&(_, y)| y == 0.0));
+            assert!(pixels.iter().any(|&(_, y)| y == (height - 1) as f32));
+            // A rotated line of text is at least as tall as the letters.
+            assert!(height >= 10);
+        }
+    }
+}
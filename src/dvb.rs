@@ -0,0 +1,871 @@
+//! Decoding DVB bitmap subtitles (ETSI EN 300 743), as carried in broadcast
+//! transport streams.
+
+use crate::bitmap::{ycrcb_to_rgb_bt601, BitmapSubtitle, Rect};
+use snafu::{ensure, OptionExt, Snafu};
+use std::collections::HashMap;
+
+/// Data identifier at the start of every DVB subtitle PES payload.
+const DATA_IDENTIFIER: u8 = 0x20;
+const SEGMENT_SYNC: u8 = 0x0f;
+/// Frame size of streams without a display definition.
+const DEFAULT_FRAME_SIZE: (u32, u32) = (720, 576);
+/// How long to show a page which does not say, in seconds.
+const DEFAULT_TIMEOUT: f64 = 5.0;
+
+const PAGE_COMPOSITION: u8 = 0x10;
+const REGION_COMPOSITION: u8 = 0x11;
+const CLUT_DEFINITION: u8 = 0x12;
+const OBJECT_DATA: u8 = 0x13;
+const DISPLAY_DEFINITION: u8 = 0x14;
+const END_OF_DISPLAY_SET: u8 = 0x80;
+
+/// Page state which starts a new epoch, discarding all regions and color
+/// tables.
+const MODE_CHANGE: u8 = 0x02;
+/// Object coding method of bitmap objects, as opposed to character strings.
+const CODING_PIXELS: u8 = 0x00;
+
+const PIXELS_2BIT: u8 = 0x10;
+const PIXELS_4BIT: u8 = 0x11;
+const PIXELS_8BIT: u8 = 0x12;
+const MAP_2_TO_4: u8 = 0x20;
+const MAP_2_TO_8: u8 = 0x21;
+const MAP_4_TO_8: u8 = 0x22;
+const END_OF_LINE: u8 = 0xf0;
+
+#[derive(Debug, Snafu)]
+pub enum Error {
+    #[snafu(display("Not a DVB subtitle packet"))]
+    NotDvb,
+
+    #[snafu(display("Truncated DVB subtitle segment of type {:#04x}", kind))]
+    Truncated { kind: u8 },
+
+    #[snafu(display("Subtitle uses more than 256 colors"))]
+    TooManyColors,
+}
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+/// A DVB subtitle segment, along with the time it applies at.
+#[derive(Debug, Clone)]
+pub struct Segment {
+    seconds: f64,
+    kind: u8,
+    page_id: u16,
+    data: Vec<u8>,
+}
+
+/// Split the payload of a subtitle PES packet into segments.
+pub fn parse_segments(seconds: f64, payload: &[u8]) -> Result<Vec<Segment>> {
+    // The data identifier is followed by a subtitle stream ID, which is
+    // always zero.
+    ensure!(
+        payload.get(..2) == Some(&[DATA_IDENTIFIER, 0x00]),
+        NotDvbSnafu {}
+    );
+    let mut data = &payload[2..];
+    let mut segments = Vec::new();
+    // The segments are followed by an end marker and possibly stuffing.
+    while let [SEGMENT_SYNC, kind, page_hi, page_lo, size_hi, size_lo, rest @ ..] = data {
+        let size = u16::from_be_bytes([*size_hi, *size_lo]) as usize;
+        let segment = rest.get(..size).context(TruncatedSnafu { kind: *kind })?;
+        segments.push(Segment {
+            seconds,
+            kind: *kind,
+            page_id: u16::from_be_bytes([*page_hi, *page_lo]),
+            data: segment.to_vec(),
+        });
+        data = &rest[size..];
+    }
+    Ok(segments)
+}
+
+/// The frame size of the video, from the first display definition.
+pub fn frame_size(segments: &[Segment]) -> (u32, u32) {
+    segments
+        .iter()
+        .filter(|segment| segment.kind == DISPLAY_DEFINITION)
+        .find_map(|segment| {
+            // The size is stored as the maximum coordinates.
+            let size = segment.data.get(1..5)?;
+            Some((
+                u16::from_be_bytes([size[0], size[1]]) as u32 + 1,
+                u16::from_be_bytes([size[2], size[3]]) as u32 + 1,
+            ))
+        })
+        .unwrap_or(DEFAULT_FRAME_SIZE)
+}
+
+/// The number of subtitles on a page, i.e. the number of new versions of the
+/// page which show something.
+pub fn count_subtitles(segments: &[Segment], page_id: u16) -> usize {
+    let mut version = None;
+    segments
+        .iter()
+        .filter(|segment| segment.kind == PAGE_COMPOSITION && segment.page_id == page_id)
+        .filter(|segment| {
+            let new_version = segment.data.get(1).map(|flags| flags >> 4);
+            let changed = new_version != version;
+            version = new_version;
+            changed && segment.data.len() > 2
+        })
+        .count()
+}
+
+/// Decode the subtitles of a composition page, using the shared data on its
+/// ancillary page. A bad display set only loses its own subtitle, so errors
+/// are returned per subtitle.
+pub fn subtitles(
+    segments: &[Segment],
+    composition_page: u16,
+    ancillary_page: u16,
+) -> Vec<Result<BitmapSubtitle>> {
+    let mut decoder = Decoder::default();
+    for segment in segments {
+        if segment.page_id != composition_page && segment.page_id != ancillary_page {
+            continue;
+        }
+        // Shared data never includes page compositions.
+        if segment.kind == PAGE_COMPOSITION && segment.page_id != composition_page {
+            continue;
+        }
+        if let Err(e) = decoder.process(segment) {
+            decoder.subtitles.push(Err(e));
+        }
+    }
+    decoder.finish_display_set();
+    if let Some(subtitle) = decoder.showing.take() {
+        decoder.subtitles.push(Ok(subtitle));
+    }
+    decoder.subtitles
+}
+
+/// A page composition, which lists the regions to show and where.
+#[derive(Debug)]
+struct Page {
+    seconds: f64,
+    /// How long to show the page at most, in seconds.
+    timeout: f64,
+    regions: Vec<(u8, u16, u16)>,
+}
+
+/// A region of the screen, with its own pixel buffer which objects are drawn
+/// into.
+#[derive(Debug)]
+struct Region {
+    version: u8,
+    width: u16,
+    height: u16,
+    /// Bits per pixel: 2, 4 or 8.
+    depth: u8,
+    clut_id: u8,
+    /// The objects drawn into this region, and their positions.
+    objects: Vec<(u16, u16, u16)>,
+    pixels: Vec<u8>,
+}
+
+/// A color lookup table, with entries for each pixel depth as sRGBA.
+#[derive(Debug, Clone)]
+struct Clut {
+    two_bit: [[u8; 4]; 4],
+    four_bit: [[u8; 4]; 16],
+    eight_bit: [[u8; 4]; 256],
+}
+
+impl Default for Clut {
+    /// The default color tables defined by the standard.
+    fn default() -> Self {
+        let bit = |i: usize, mask: usize, value: u8| if i & mask != 0 { value } else { 0 };
+        let mut clut = Clut {
+            two_bit: [
+                [0; 4],
+                [255, 255, 255, 255],
+                [0, 0, 0, 255],
+                [127, 127, 127, 255],
+            ],
+            four_bit: [[0; 4]; 16],
+            eight_bit: [[0; 4]; 256],
+        };
+        for (i, color) in clut.four_bit.iter_mut().enumerate().skip(1) {
+            let value = if i < 8 { 255 } else { 127 };
+            *color = [bit(i, 1, value), bit(i, 2, value), bit(i, 4, value), 255];
+        }
+        for (i, color) in clut.eight_bit.iter_mut().enumerate().skip(1) {
+            // Each channel has a low bit and a high bit, four bits apart.
+            let level =
+                |mask: usize, low: u8, high: u8| bit(i, mask, low) + bit(i, mask << 4, high);
+            *color = if i < 8 {
+                [bit(i, 1, 255), bit(i, 2, 255), bit(i, 4, 255), 63]
+            } else {
+                match i & 0x88 {
+                    0x00 => [level(1, 85, 170), level(2, 85, 170), level(4, 85, 170), 255],
+                    0x08 => [level(1, 85, 170), level(2, 85, 170), level(4, 85, 170), 127],
+                    0x80 => [
+                        127 + level(1, 43, 85),
+                        127 + level(2, 43, 85),
+                        127 + level(4, 43, 85),
+                        255,
+                    ],
+                    _ => [level(1, 43, 85), level(2, 43, 85), level(4, 43, 85), 255],
+                }
+            };
+        }
+        clut
+    }
+}
+
+/// Tables mapping pixel codes to deeper regions.
+struct MapTables {
+    two_to_four: [u8; 4],
+    two_to_eight: [u8; 4],
+    four_to_eight: [u8; 16],
+}
+
+impl Default for MapTables {
+    fn default() -> Self {
+        let mut four_to_eight = [0; 16];
+        for (i, value) in four_to_eight.iter_mut().enumerate() {
+            *value = i as u8 * 0x11;
+        }
+        MapTables {
+            two_to_four: [0x0, 0x7, 0x8, 0xf],
+            two_to_eight: [0x00, 0x77, 0x88, 0xff],
+            four_to_eight,
+        }
+    }
+}
+
+/// The state carried from one display set to the next.
+#[derive(Default)]
+struct Decoder {
+    regions: HashMap<u8, Region>,
+    cluts: HashMap<u8, Clut>,
+    /// The page composition of the display set being received.
+    page: Option<Page>,
+    /// The subtitle on screen, which ends with the next change or its
+    /// timeout.
+    showing: Option<BitmapSubtitle>,
+    subtitles: Vec<Result<BitmapSubtitle>>,
+}
+
+impl Decoder {
+    fn process(&mut self, segment: &Segment) -> Result<()> {
+        let data = &segment.data;
+        let truncated = || TruncatedSnafu { kind: segment.kind };
+        match segment.kind {
+            PAGE_COMPOSITION => {
+                // Not every encoder sends end of display set segments.
+                self.finish_display_set();
+                let header = data.get(..2).context(truncated())?;
+                if header[1] >> 2 & 0x03 == MODE_CHANGE {
+                    self.regions.clear();
+                    self.cluts.clear();
+                }
+                self.page = Some(Page {
+                    seconds: segment.seconds,
+                    timeout: match header[0] {
+                        0 => DEFAULT_TIMEOUT,
+                        timeout => timeout as f64,
+                    },
+                    regions: data[2..]
+                        .chunks_exact(6)
+                        .map(|region| {
+                            (
+                                region[0],
+                                u16::from_be_bytes([region[2], region[3]]),
+                                u16::from_be_bytes([region[4], region[5]]),
+                            )
+                        })
+                        .collect(),
+                });
+            }
+            REGION_COMPOSITION => {
+                let header = data.get(..10).context(truncated())?;
+                let id = header[0];
+                let version = header[1] >> 4;
+                let fill = header[1] & 0x08 != 0;
+                let width = u16::from_be_bytes([header[2], header[3]]);
+                let height = u16::from_be_bytes([header[4], header[5]]);
+                let depth = match header[6] >> 2 & 0x07 {
+                    1 => 2,
+                    2 => 4,
+                    _ => 8,
+                };
+                let background = match depth {
+                    2 => header[9] >> 2 & 0x03,
+                    4 => header[9] >> 4,
+                    _ => header[8],
+                };
+                let mut objects = Vec::new();
+                let mut rest = &data[10..];
+                while let [id_hi, id_lo, x_hi, x_lo, y_hi, y_lo, more @ ..] = rest {
+                    objects.push((
+                        u16::from_be_bytes([*id_hi, *id_lo]),
+                        u16::from_be_bytes([x_hi & 0x0f, *x_lo]),
+                        u16::from_be_bytes([y_hi & 0x0f, *y_lo]),
+                    ));
+                    // Character objects carry foreground and background
+                    // colors as well.
+                    rest = match x_hi >> 6 {
+                        0x01 | 0x02 => more.get(2..).unwrap_or_default(),
+                        _ => more,
+                    };
+                }
+
+                let region = self.regions.entry(id).or_insert_with(|| Region {
+                    version,
+                    width,
+                    height,
+                    depth,
+                    clut_id: header[7],
+                    objects: Vec::new(),
+                    pixels: vec![background; width as usize * height as usize],
+                });
+                if (region.width, region.height, region.depth) != (width, height, depth) {
+                    region.width = width;
+                    region.height = height;
+                    region.depth = depth;
+                    region.pixels = vec![background; width as usize * height as usize];
+                } else if fill && region.version != version {
+                    region.pixels.fill(background);
+                }
+                region.version = version;
+                region.clut_id = header[7];
+                region.objects = objects;
+            }
+            CLUT_DEFINITION => {
+                let id = *data.first().context(truncated())?;
+                let clut = self.cluts.entry(id).or_default();
+                let mut rest = data.get(2..).unwrap_or_default();
+                while let [entry_id, flags, more @ ..] = rest {
+                    let (y, cr, cb, t) = if flags & 0x01 != 0 {
+                        let entry = more.get(..4).context(truncated())?;
+                        rest = &more[4..];
+                        (entry[0], entry[1], entry[2], entry[3])
+                    } else {
+                        // Reduced precision: 6 bits of Y, 4 of Cr and Cb, and
+                        // 2 of transparency.
+                        let entry = more.get(..2).context(truncated())?;
+                        rest = &more[2..];
+                        (
+                            entry[0] & 0xfc,
+                            (entry[0] << 6 | entry[1] >> 2) & 0xf0,
+                            entry[1] << 2 & 0xf0,
+                            (entry[1] & 0x03) * 85,
+                        )
+                    };
+                    // A luminance of zero means fully transparent.
+                    let [r, g, b] = ycrcb_to_rgb_bt601(y, cr, cb);
+                    let color = [r, g, b, if y == 0 { 0 } else { 255 - t }];
+                    let entry_id = *entry_id as usize;
+                    if flags & 0x80 != 0 && entry_id < 4 {
+                        clut.two_bit[entry_id] = color;
+                    }
+                    if flags & 0x40 != 0 && entry_id < 16 {
+                        clut.four_bit[entry_id] = color;
+                    }
+                    if flags & 0x20 != 0 {
+                        clut.eight_bit[entry_id] = color;
+                    }
+                }
+            }
+            OBJECT_DATA => {
+                let header = data.get(..3).context(truncated())?;
+                let id = u16::from_be_bytes([header[0], header[1]]);
+                if header[2] >> 2 & 0x03 != CODING_PIXELS {
+                    return Ok(());
+                }
+                let lengths = data.get(3..7).context(truncated())?;
+                let top_length = u16::from_be_bytes([lengths[0], lengths[1]]) as usize;
+                let bottom_length = u16::from_be_bytes([lengths[2], lengths[3]]) as usize;
+                let top = data.get(7..7 + top_length).context(truncated())?;
+                // Without bottom field data, the top field is repeated.
+                let bottom = match bottom_length {
+                    0 => top,
+                    _ => data
+                        .get(7 + top_length..7 + top_length + bottom_length)
+                        .context(truncated())?,
+                };
+                for region in self.regions.values_mut() {
+                    let placements: Vec<(u16, u16)> = region
+                        .objects
+                        .iter()
+                        .filter(|object| object.0 == id)
+                        .map(|&(_, x, y)| (x, y))
+                        .collect();
+                    for (x, y) in placements {
+                        draw_field(region, top, x as usize, y as usize);
+                        draw_field(region, bottom, x as usize, y as usize + 1);
+                    }
+                }
+            }
+            END_OF_DISPLAY_SET => self.finish_display_set(),
+            // Display definitions are not needed, since regions carry their
+            // own positions.
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// Show the display set that was just received, ending whatever was
+    /// shown before unless it is unchanged.
+    fn finish_display_set(&mut self) {
+        let page = match self.page.take() {
+            Some(page) => page,
+            None => return,
+        };
+        let rendered = match self.render(&page) {
+            Ok(rendered) => rendered,
+            Err(e) => {
+                self.subtitles.push(Err(e));
+                None
+            }
+        };
+        if let (Some(showing), Some(rendered)) = (&mut self.showing, &rendered) {
+            // Pages are often repeated so that viewers can tune in at any time.
+            if page.seconds <= showing.end_time
+                && showing.area == rendered.area
+                && showing.pixels == rendered.pixels
+                && showing.palette == rendered.palette
+            {
+                showing.end_time = showing.end_time.max(rendered.end_time);
+                return;
+            }
+        }
+        if let Some(mut subtitle) = self.showing.take() {
+            subtitle.end_time = subtitle.end_time.min(page.seconds);
+            self.subtitles.push(Ok(subtitle));
+        }
+        self.showing = rendered;
+    }
+
+    /// Draw all regions of a page into a single bitmap covering them, or
+    /// return `None` if the page shows nothing.
+    fn render(&self, page: &Page) -> Result<Option<BitmapSubtitle>> {
+        let regions: Vec<(&Region, u32, u32)> = page
+            .regions
+            .iter()
+            .filter_map(|&(id, x, y)| Some((self.regions.get(&id)?, x as u32, y as u32)))
+            .filter(|(region, ..)| region.width > 0 && region.height > 0)
+            .collect();
+        let left = regions.iter().map(|(_, x, _)| *x).min().unwrap_or(0);
+        let top = regions.iter().map(|(_, _, y)| *y).min().unwrap_or(0);
+        let right = regions
+            .iter()
+            .map(|(region, x, _)| x + region.width as u32)
+            .max()
+            .unwrap_or(0);
+        let bottom = regions
+            .iter()
+            .map(|(region, _, y)| y + region.height as u32)
+            .max()
+            .unwrap_or(0);
+        let width = (right - left) as usize;
+        let height = (bottom - top) as usize;
+
+        // Regions may use different color tables, so build a palette of just
+        // the colors that are used, with a transparent color first.
+        let mut palette = vec![[0; 4]];
+        let mut palette_indices: HashMap<[u8; 4], u8> = HashMap::new();
+        let mut canvas = vec![0; width * height];
+        let default_clut = Clut::default();
+        for (region, x, y) in regions {
+            let clut = self.cluts.get(&region.clut_id).unwrap_or(&default_clut);
+            let colors: &[[u8; 4]] = match region.depth {
+                2 => &clut.two_bit,
+                4 => &clut.four_bit,
+                _ => &clut.eight_bit,
+            };
+            for (row, pixels) in region.pixels.chunks(region.width as usize).enumerate() {
+                let dst = (y - top) as usize + row;
+                let dst = dst * width + (x - left) as usize;
+                for (i, &code) in pixels.iter().enumerate() {
+                    let color = colors[code as usize];
+                    if color[3] == 0 {
+                        continue;
+                    }
+                    let index = match palette_indices.get(&color) {
+                        Some(&index) => index,
+                        None => {
+                            ensure!(palette.len() < 256, TooManyColorsSnafu {});
+                            let index = palette.len() as u8;
+                            palette.push(color);
+                            palette_indices.insert(color, index);
+                            index
+                        }
+                    };
+                    canvas[dst + i] = index;
+                }
+            }
+        }
+        if palette.len() == 1 {
+            return Ok(None);
+        }
+
+        Ok(Some(BitmapSubtitle {
+            start_time: page.seconds,
+            end_time: page.seconds + page.timeout,
+            force: false,
+            area: Rect {
+                x: left,
+                y: top,
+                width: width as u32,
+                height: height as u32,
+            },
+            palette,
+            dvd_roles: false,
+            pixels: canvas,
+        }))
+    }
+}
+
+/// Draw one field of an object's pixel data into a region, starting at the
+/// given position and on every other line from there.
+fn draw_field(region: &mut Region, data: &[u8], x0: usize, y0: usize) {
+    let mut maps = MapTables::default();
+    let mut reader = BitReader { data, pos: 0 };
+    let (mut x, mut y) = (x0, y0);
+    while let Some(data_type) = reader.read(8) {
+        let bits = match data_type as u8 {
+            PIXELS_2BIT => 2,
+            PIXELS_4BIT => 4,
+            PIXELS_8BIT => 8,
+            MAP_2_TO_4 => {
+                for value in maps.two_to_four.iter_mut() {
+                    *value = reader.read(4).unwrap_or(0) as u8;
+                }
+                continue;
+            }
+            MAP_2_TO_8 => {
+                for value in maps.two_to_eight.iter_mut() {
+                    *value = reader.read(8).unwrap_or(0) as u8;
+                }
+                continue;
+            }
+            MAP_4_TO_8 => {
+                for value in maps.four_to_eight.iter_mut() {
+                    *value = reader.read(8).unwrap_or(0) as u8;
+                }
+                continue;
+            }
+            END_OF_LINE => {
+                x = x0;
+                y += 2;
+                continue;
+            }
+            _ => return,
+        };
+        let depth = region.depth;
+        let map = |code: u8| match (bits, depth) {
+            (2, 4) => maps.two_to_four[code as usize],
+            (2, 8) => maps.two_to_eight[code as usize],
+            (4, 8) => maps.four_to_eight[code as usize],
+            // Deeper codes in a shallower region keep their high bits.
+            _ if bits > depth => code >> (bits - depth),
+            _ => code,
+        };
+        let width = region.width as usize;
+        let height = region.height as usize;
+        let pixels = &mut region.pixels;
+        let mut put = |length: usize, code: u8| {
+            if y < height && x < width {
+                let end = (x + length).min(width);
+                pixels[y * width + x..y * width + end].fill(map(code));
+            }
+            x += length;
+        };
+        let complete = match bits {
+            2 => read_2bit_string(&mut reader, &mut put),
+            4 => read_4bit_string(&mut reader, &mut put),
+            _ => read_8bit_string(&mut reader, &mut put),
+        };
+        if complete.is_none() {
+            return;
+        }
+        reader.align();
+    }
+}
+
+/// Decode a string of 2-bit pixel codes, returning `None` if the data ends
+/// before the string does.
+fn read_2bit_string(reader: &mut BitReader, put: &mut impl FnMut(usize, u8)) -> Option<()> {
+    loop {
+        let code = reader.read(2)? as u8;
+        if code != 0 {
+            put(1, code);
+        } else if reader.read(1)? == 1 {
+            let length = reader.read(3)? as usize + 3;
+            put(length, reader.read(2)? as u8);
+        } else if reader.read(1)? == 1 {
+            put(1, 0);
+        } else {
+            match reader.read(2)? {
+                0 => return Some(()),
+                1 => put(2, 0),
+                2 => {
+                    let length = reader.read(4)? as usize + 12;
+                    put(length, reader.read(2)? as u8);
+                }
+                _ => {
+                    let length = reader.read(8)? as usize + 29;
+                    put(length, reader.read(2)? as u8);
+                }
+            }
+        }
+    }
+}
+
+/// Decode a string of 4-bit pixel codes, returning `None` if the data ends
+/// before the string does.
+fn read_4bit_string(reader: &mut BitReader, put: &mut impl FnMut(usize, u8)) -> Option<()> {
+    loop {
+        let code = reader.read(4)? as u8;
+        if code != 0 {
+            put(1, code);
+        } else if reader.read(1)? == 0 {
+            match reader.read(3)? {
+                0 => return Some(()),
+                length => put(length as usize + 2, 0),
+            }
+        } else if reader.read(1)? == 0 {
+            let length = reader.read(2)? as usize + 4;
+            put(length, reader.read(4)? as u8);
+        } else {
+            match reader.read(2)? {
+                0 => put(1, 0),
+                1 => put(2, 0),
+                2 => {
+                    let length = reader.read(4)? as usize + 9;
+                    put(length, reader.read(4)? as u8);
+                }
+                _ => {
+                    let length = reader.read(8)? as usize + 25;
+                    put(length, reader.read(4)? as u8);
+                }
+            }
+        }
+    }
+}
+
+/// Decode a string of 8-bit pixel codes, returning `None` if the data ends
+/// before the string does.
+fn read_8bit_string(reader: &mut BitReader, put: &mut impl FnMut(usize, u8)) -> Option<()> {
+    loop {
+        let code = reader.read(8)? as u8;
+        if code != 0 {
+            put(1, code);
+        } else if reader.read(1)? == 0 {
+            match reader.read(7)? {
+                0 => return Some(()),
+                length => put(length as usize, 0),
+            }
+        } else {
+            let length = reader.read(7)? as usize;
+            put(length, reader.read(8)? as u8);
+        }
+    }
+}
+
+/// Reads big-endian bit fields out of a byte slice.
+struct BitReader<'a> {
+    data: &'a [u8],
+    /// Position in bits.
+    pos: usize,
+}
+
+impl BitReader<'_> {
+    /// Read up to 16 bits, or return `None` at the end of the data.
+    fn read(&mut self, bits: usize) -> Option<u16> {
+        let mut value = 0;
+        for _ in 0..bits {
+            let byte = self.data.get(self.pos / 8)?;
+            value = value << 1 | (byte >> (7 - self.pos % 8) & 1) as u16;
+            self.pos += 1;
+        }
+        Some(value)
+    }
+
+    /// Skip to the start of the next byte.
+    fn align(&mut self) {
+        self.pos = self.pos.div_ceil(8) * 8;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Decode a pixel string, returning the runs of `(length, code)` and
+    /// whether the string was complete.
+    fn runs(bits: usize, data: &[u8]) -> (Vec<(usize, u8)>, bool) {
+        let mut runs = Vec::new();
+        let mut reader = BitReader { data, pos: 0 };
+        let mut put = |length, code| runs.push((length, code));
+        let complete = match bits {
+            2 => read_2bit_string(&mut reader, &mut put),
+            4 => read_4bit_string(&mut reader, &mut put),
+            _ => read_8bit_string(&mut reader, &mut put),
+        };
+        (runs, complete.is_some())
+    }
+
+    #[test]
+    fn decodes_2bit_strings() {
+        // Codes 1 and 2, a run of five 3s, and the end of the string.
+        let data = [0x62, 0xb0, 0x00];
+        assert_eq!(runs(2, &data), (vec![(1, 1), (1, 2), (5, 3)], true));
+        assert_eq!(runs(2, &data[..1]), (vec![(1, 1), (1, 2)], false));
+    }
+
+    #[test]
+    fn decodes_4bit_strings() {
+        // Code 5, a run of five 9s, and the end of the string.
+        let data = [0x50, 0x99, 0x00];
+        assert_eq!(runs(4, &data), (vec![(1, 5), (5, 9)], true));
+        assert_eq!(runs(4, &data[..2]), (vec![(1, 5), (5, 9)], false));
+    }
+
+    #[test]
+    fn decodes_8bit_strings() {
+        // Code 7, a run of ten 0x20s, three 0s, and the end of the string.
+        let data = [0x07, 0x00, 0x8a, 0x20, 0x00, 0x03, 0x00, 0x00];
+        assert_eq!(runs(8, &data), (vec![(1, 7), (10, 0x20), (3, 0)], true));
+        assert_eq!(
+            runs(8, &data[..6]),
+            (vec![(1, 7), (10, 0x20), (3, 0)], false)
+        );
+    }
+
+    fn clut_segment(entries: &[u8]) -> Segment {
+        Segment {
+            seconds: 0.0,
+            kind: CLUT_DEFINITION,
+            page_id: 1,
+            data: [&[0x00, 0x00][..], entries].concat(),
+        }
+    }
+
+    #[test]
+    fn reads_reduced_precision_clut_entries() {
+        let mut decoder = Decoder::default();
+        // 4-bit entries 1 and 2, with 6 bits of Y, 4 of Cr and Cb, and 2 of
+        // transparency.
+        let segment = clut_segment(&[0x01, 0x40, 0xea, 0x20, 0x02, 0x40, 0x40, 0x02]);
+        decoder.process(&segment).unwrap();
+        let clut = &decoder.cluts[&0];
+        let [r, g, b] = ycrcb_to_rgb_bt601(0xe8, 0x80, 0x80);
+        assert_eq!(clut.four_bit[1], [r, g, b, 255]);
+        let [r, g, b] = ycrcb_to_rgb_bt601(0x40, 0x00, 0x00);
+        assert_eq!(clut.four_bit[2], [r, g, b, 85]);
+        // Other depths keep their defaults.
+        assert_eq!(clut.two_bit[1], Clut::default().two_bit[1]);
+    }
+
+    #[test]
+    fn rejects_truncated_clut_entries() {
+        let mut decoder = Decoder::default();
+        // A full precision entry with only two bytes.
+        let segment = clut_segment(&[0x01, 0x41, 0xeb, 0x80]);
+        assert!(matches!(
+            decoder.process(&segment),
+            Err(Error::Truncated {
+                kind: CLUT_DEFINITION
+            })
+        ));
+    }
+
+    fn segment(kind: u8, data: &[u8]) -> Vec<u8> {
+        let mut segment = vec![SEGMENT_SYNC, kind, 0x00, 0x01];
+        segment.extend_from_slice(&(data.len() as u16).to_be_bytes());
+        segment.extend_from_slice(data);
+        segment
+    }
+
+    /// A PES payload showing a 4x2 region at (100, 500) on page 1.
+    fn payload() -> Vec<u8> {
+        // Top field: four pixels of code 1. Bottom field: two of code 2 and
+        // two of code 0.
+        let top = [PIXELS_4BIT, 0x11, 0x11, 0x00, END_OF_LINE];
+        let bottom = [PIXELS_4BIT, 0x22, 0x0c, 0x0c, 0x00, END_OF_LINE];
+        let object = [
+            &[
+                0x00,
+                0x00,
+                0x00,
+                0x00,
+                top.len() as u8,
+                0x00,
+                bottom.len() as u8,
+            ][..],
+            &top,
+            &bottom,
+        ]
+        .concat();
+        [
+            &[DATA_IDENTIFIER, 0x00][..],
+            &segment(
+                PAGE_COMPOSITION,
+                &[0x00, 0x08, 0x00, 0x00, 0x00, 0x64, 0x01, 0xf4],
+            ),
+            &segment(
+                REGION_COMPOSITION,
+                &[
+                    0x00, 0x08, 0x00, 0x04, 0x00, 0x02, 0x08, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+                    0x00, 0x00, 0x00,
+                ],
+            ),
+            &segment(
+                CLUT_DEFINITION,
+                &[0x00, 0x00, 0x01, 0x41, 0xeb, 0x80, 0x80, 0x00],
+            ),
+            &segment(OBJECT_DATA, &object),
+            &segment(END_OF_DISPLAY_SET, &[]),
+            &[0xff],
+        ]
+        .concat()
+    }
+
+    #[test]
+    fn decodes_display_sets() {
+        let segments = parse_segments(1.0, &payload()).unwrap();
+        assert_eq!(segments.len(), 5);
+        assert_eq!(frame_size(&segments), DEFAULT_FRAME_SIZE);
+        assert_eq!(count_subtitles(&segments, 1), 1);
+
+        let subtitles = subtitles(&segments, 1, 1);
+        assert_eq!(subtitles.len(), 1);
+        let subtitle = subtitles[0].as_ref().unwrap();
+        assert_eq!(subtitle.start_time, 1.0);
+        assert_eq!(subtitle.end_time, 1.0 + DEFAULT_TIMEOUT);
+        assert_eq!(
+            (
+                subtitle.area.x,
+                subtitle.area.y,
+                subtitle.area.width,
+                subtitle.area.height
+            ),
+            (100, 500, 4, 2)
+        );
+        assert_eq!(subtitle.pixels, [1, 1, 1, 1, 2, 2, 0, 0]);
+        let [r, g, b] = ycrcb_to_rgb_bt601(0xeb, 0x80, 0x80);
+        assert_eq!(subtitle.palette[1], [r, g, b, 255]);
+        assert_eq!(subtitle.palette[2], Clut::default().four_bit[2]);
+    }
+
+    #[test]
+    fn rejects_truncated_payloads() {
+        let mut data = payload();
+        // Cut into the object data segment.
+        data.truncate(data.len() - 12);
+        assert!(matches!(
+            parse_segments(1.0, &data),
+            Err(Error::Truncated { kind: OBJECT_DATA })
+        ));
+        assert!(matches!(
+            parse_segments(1.0, &data[1..]),
+            Err(Error::NotDvb)
+        ));
+    }
+}
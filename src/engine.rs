@@ -0,0 +1,117 @@
+//! OCR engines, which recognize the text of a single line image. Each OCR
+//! thread gets its own recognizer from the engine, so that engines which are
+//! not thread safe, like Tesseract, need no locking.
+
+use crate::{
+    command::{self, CommandEngine},
+    glyphs::{self, GlyphEngine},
+    opt::Opt,
+    tesseract::{self, TesseractEngine},
+};
+use clap::ValueEnum;
+use image::GrayImage;
+use snafu::{OptionExt, ResultExt, Snafu};
+
+#[derive(Debug, Snafu)]
+#[snafu(visibility(pub(crate)))]
+pub enum Error {
+    #[snafu(display("{}", source))]
+    Tesseract { source: tesseract::Error },
+
+    #[snafu(display("{}", source))]
+    Glyphs { source: glyphs::Error },
+
+    #[snafu(display("{}", source))]
+    Command { source: command::Error },
+
+    #[snafu(display(
+        "No Tesseract language known for this subtitle stream; specify one with --lang or --lang-map"
+    ))]
+    NoLanguage,
+
+    #[snafu(display("The glyphs engine needs a database given with --glyph-database"))]
+    NoGlyphDatabase,
+
+    #[snafu(display("The command engine needs a command given after `--`"))]
+    NoOcrCommand,
+}
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+/// An OCR engine to recognize text with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Engine {
+    /// Tesseract, for the language given by `--lang`.
+    Tesseract,
+    /// Match glyphs against the database given by `--glyph-database`.
+    Glyphs,
+    /// Run the external program given after `--`.
+    Command,
+}
+
+/// Recognized text of a line, with its confidences from 0 to 100.
+#[derive(Debug)]
+pub struct Recognition {
+    pub text: String,
+    /// Mean confidence over the whole line.
+    pub confidence: f32,
+    /// The words of the line, if the engine tells them apart.
+    pub words: Option<Vec<Word>>,
+}
+
+/// A recognized word, with where it is in the line image.
+#[derive(Debug)]
+pub struct Word {
+    pub text: String,
+    pub confidence: f32,
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+/// Recognizes the text of line images, on a single thread.
+pub trait Recognizer {
+    /// Recognize the text of a line image, with black text on white. The DPI
+    /// is only a hint, which engines may ignore. Engines which learn from the
+    /// images they see only do so if `learn` is set, so that images which
+    /// are just tried out do not teach them anything.
+    fn recognize(&mut self, image: GrayImage, dpi: i32, learn: bool) -> Result<Recognition>;
+}
+
+/// An OCR engine, shared by all OCR threads.
+pub trait RecognizerFactory: Sync {
+    /// Set up a recognizer for the calling thread.
+    fn recognizer(&self) -> Result<Box<dyn Recognizer>>;
+
+    /// Finish up once all text is recognized.
+    fn finish(&self) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// Set up the OCR engine chosen with `--engine`. Only Tesseract needs the
+/// language, which may be unknown for the other engines.
+pub fn new_engine(opt: &Opt, lang: Option<&str>) -> Result<Box<dyn RecognizerFactory>> {
+    Ok(match opt.engine {
+        Engine::Tesseract => Box::new(TesseractEngine::new(
+            opt.tessdata_dir.as_deref(),
+            lang.context(NoLanguageSnafu {})?,
+            &opt.config,
+        )),
+        Engine::Glyphs => {
+            let filename = opt
+                .glyph_database
+                .as_deref()
+                .context(NoGlyphDatabaseSnafu {})?;
+            Box::new(GlyphEngine::open(filename).context(GlyphsSnafu {})?)
+        }
+        Engine::Command => {
+            let (program, args) = opt
+                .ocr_command
+                .split_first()
+                .context(NoOcrCommandSnafu {})?;
+            Box::new(CommandEngine::new(program, args, opt.ocr_image_format))
+        }
+    })
+}
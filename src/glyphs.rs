@@ -0,0 +1,638 @@
+//! Recognizing text by matching its glyphs against a database of known glyph
+//! bitmaps, like the binary image compare of Subtitle Edit. A disc uses a
+//! single font, so once its glyphs are known, matching them beats general
+//! OCR on stylized fonts. Unknown glyphs are asked for when running in a
+//! terminal, or else added to the database unnamed, to be named by editing
+//! the file, which can be reused for every disc of a series.
+
+use crate::{
+    engine::{self, Recognition, Recognizer, RecognizerFactory, Word},
+    segment::{connected_components, text_height, Component, Mask},
+};
+use image::GrayImage;
+use log::info;
+use snafu::{ResultExt, Snafu};
+use std::{
+    fs,
+    io::{self, BufRead, IsTerminal, Write},
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+};
+
+/// First line of a glyph database file.
+const HEADER: &str = "# vobsubocr glyph database";
+
+/// Largest share of differing pixels for a glyph to match a known one.
+const MAX_DIFFERENCE: f32 = 0.15;
+
+/// Horizontal gap, relative to the text height, which separates words.
+const WORD_GAP: f32 = 0.3;
+
+/// Stands in for glyphs which have not been named yet.
+const UNNAMED: &str = "\u{fffd}";
+
+#[derive(Debug, Snafu)]
+pub enum Error {
+    #[snafu(display("Could not read glyph database {}: {}", filename.display(), source))]
+    ReadDatabase {
+        filename: PathBuf,
+        source: io::Error,
+    },
+
+    #[snafu(display("Invalid glyph database {} at line {}: {}", filename.display(), line, message))]
+    ParseDatabase {
+        filename: PathBuf,
+        line: usize,
+        message: String,
+    },
+
+    #[snafu(display("Could not write glyph database {}: {}", filename.display(), source))]
+    WriteDatabase {
+        filename: PathBuf,
+        source: io::Error,
+    },
+
+    #[snafu(display("Could not ask for the text of a glyph: {}", source))]
+    AskGlyph { source: io::Error },
+}
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+/// A binary image of a glyph, where `true` represents a text pixel.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Bitmap {
+    width: usize,
+    height: usize,
+    pixels: Vec<bool>,
+}
+
+impl Bitmap {
+    fn from_components(components: &[Component]) -> Self {
+        let left = components.iter().map(|c| c.x.start).min().unwrap_or(0);
+        let right = components.iter().map(|c| c.x.end).max().unwrap_or(0);
+        let top = components.iter().map(|c| c.y.start).min().unwrap_or(0);
+        let bottom = components.iter().map(|c| c.y.end).max().unwrap_or(0);
+        let (width, height) = (right - left, bottom - top);
+        let mut pixels = vec![false; width * height];
+        for component in components {
+            for &(x, y) in &component.pixels {
+                pixels[(y - top) * width + x - left] = true;
+            }
+        }
+        Bitmap {
+            width,
+            height,
+            pixels,
+        }
+    }
+
+    /// Whether the pixel at a position, which may be outside the bitmap, is
+    /// a text pixel.
+    fn get(&self, x: i64, y: i64) -> bool {
+        x >= 0
+            && y >= 0
+            && (x as usize) < self.width
+            && (y as usize) < self.height
+            && self.pixels[y as usize * self.width + x as usize]
+    }
+
+    fn area(&self) -> usize {
+        self.pixels.iter().filter(|&&pixel| pixel).count()
+    }
+
+    /// Whether another bitmap is close enough in size to be the same glyph.
+    fn similar_size(&self, other: &Bitmap) -> bool {
+        let close = |a: usize, b: usize| a.abs_diff(b) <= 1 + a.max(b) / 10;
+        close(self.width, other.width) && close(self.height, other.height)
+    }
+
+    /// The share of pixels which differ from another bitmap, with their
+    /// centers lined up, shifted by up to a pixel either way to find the
+    /// best fit.
+    fn difference(&self, other: &Bitmap) -> f32 {
+        let center_x = (self.width as i64 - other.width as i64) / 2;
+        let center_y = (self.height as i64 - other.height as i64) / 2;
+        let mut best = usize::MAX;
+        for offset_y in center_y - 1..=center_y + 1 {
+            for offset_x in center_x - 1..=center_x + 1 {
+                let mut differences = 0;
+                for y in offset_y.min(0)..(self.height as i64).max(offset_y + other.height as i64) {
+                    for x in offset_x.min(0)..(self.width as i64).max(offset_x + other.width as i64)
+                    {
+                        if self.get(x, y) != other.get(x - offset_x, y - offset_y) {
+                            differences += 1;
+                        }
+                    }
+                }
+                best = best.min(differences);
+            }
+        }
+        best as f32 / self.area().max(other.area()).max(1) as f32
+    }
+}
+
+/// A known glyph.
+#[derive(Debug, Clone, PartialEq)]
+struct Glyph {
+    bitmap: Bitmap,
+    /// Height of the bottom of the glyph above the baseline, which tells
+    /// apart glyphs like `,` and `'`.
+    rise: i64,
+    /// The text the glyph stands for, if it has been named.
+    text: Option<String>,
+}
+
+impl Glyph {
+    /// Whether a glyph at this height above the baseline could be this one.
+    fn similar_rise(&self, rise: i64, text_height: usize) -> bool {
+        self.rise.abs_diff(rise) as usize <= (text_height / 8).max(2)
+    }
+}
+
+/// The glyph matching engine, which shares one database between all threads.
+#[derive(Clone)]
+pub struct GlyphEngine {
+    database: Arc<GlyphDatabase>,
+}
+
+impl GlyphEngine {
+    /// Read a glyph database, or start an empty one if the file does not
+    /// exist yet.
+    pub fn open(filename: &Path) -> Result<Self> {
+        Ok(GlyphEngine {
+            database: Arc::new(GlyphDatabase::open(filename)?),
+        })
+    }
+}
+
+impl RecognizerFactory for GlyphEngine {
+    fn recognizer(&self) -> engine::Result<Box<dyn Recognizer>> {
+        Ok(Box::new(self.clone()))
+    }
+
+    /// Write the glyphs learned along the way back to the database.
+    fn finish(&self) -> engine::Result<()> {
+        self.database.save().context(engine::GlyphsSnafu {})
+    }
+}
+
+impl Recognizer for GlyphEngine {
+    fn recognize(
+        &mut self,
+        image: GrayImage,
+        _dpi: i32,
+        learn: bool,
+    ) -> engine::Result<Recognition> {
+        self.database
+            .recognize(&image, learn)
+            .context(engine::GlyphsSnafu {})
+    }
+}
+
+/// The known glyphs of a font, which grow as unknown glyphs are found.
+struct GlyphDatabase {
+    filename: PathBuf,
+    /// The glyphs, and whether any were added since the file was read.
+    glyphs: RwLock<(Vec<Glyph>, bool)>,
+    /// Whether to ask for the text of unknown glyphs.
+    interactive: bool,
+}
+
+impl GlyphDatabase {
+    fn open(filename: &Path) -> Result<Self> {
+        let glyphs = match fs::read_to_string(filename) {
+            Ok(text) => parse_database(&text, filename)?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                info!("Starting new glyph database {}", filename.display());
+                Vec::new()
+            }
+            Err(source) => {
+                return Err(Error::ReadDatabase {
+                    filename: filename.to_owned(),
+                    source,
+                })
+            }
+        };
+        Ok(GlyphDatabase {
+            filename: filename.to_owned(),
+            glyphs: RwLock::new((glyphs, false)),
+            interactive: io::stdin().is_terminal() && io::stderr().is_terminal(),
+        })
+    }
+
+    /// Write the database back to its file, if glyphs were added.
+    fn save(&self) -> Result<()> {
+        let (glyphs, changed) = &*self.glyphs.read().unwrap();
+        if !changed {
+            return Ok(());
+        }
+        let unnamed = glyphs.iter().filter(|glyph| glyph.text.is_none()).count();
+        info!(
+            "Writing {} glyphs, {} of them unnamed, to {}",
+            glyphs.len(),
+            unnamed,
+            self.filename.display()
+        );
+        fs::write(&self.filename, format_database(glyphs)).context(WriteDatabaseSnafu {
+            filename: &self.filename,
+        })
+    }
+
+    /// Recognize the text of a line image glyph by glyph. Glyphs are matched
+    /// with a confidence from 100 down to 85 depending on how many pixels
+    /// differ, and unknown or unnamed ones with a confidence of 0. Unknown
+    /// glyphs are only learned if `learn` is set.
+    fn recognize(&self, image: &GrayImage, learn: bool) -> Result<Recognition> {
+        let mask = Mask {
+            width: image.width() as usize,
+            height: image.height() as usize,
+            pixels: image.pixels().map(|pixel| pixel[0] < 128).collect(),
+        };
+        let components = connected_components(&mask);
+        if components.is_empty() {
+            return Ok(Recognition {
+                text: String::new(),
+                confidence: 0.0,
+                words: Some(Vec::new()),
+            });
+        }
+        let text_height = text_height(&components);
+        let glyphs = group_glyphs(components);
+        let baseline = baseline(&glyphs, text_height);
+
+        // Words, as the text, confidence and box of each of their glyphs.
+        let mut words: Vec<Vec<Word>> = Vec::new();
+        let mut text = String::new();
+        let mut right = None;
+        for components in &glyphs {
+            let left = components.iter().map(|c| c.x.start).min().unwrap_or(0);
+            let bitmap = Bitmap::from_components(components);
+            let top = components.iter().map(|c| c.y.start).min().unwrap_or(0);
+            let new_word = match right {
+                Some(right) => left.saturating_sub(right) as f32 >= WORD_GAP * text_height as f32,
+                None => true,
+            };
+            if new_word {
+                if right.is_some() {
+                    text.push(' ');
+                }
+                words.push(Vec::new());
+            }
+            right = Some(left + bitmap.width);
+
+            let (width, height) = (bitmap.width as u32, bitmap.height as u32);
+            let rise = baseline as i64 - (top + bitmap.height) as i64;
+            let (glyph_text, confidence) =
+                self.match_glyph(bitmap, rise, text_height, &text, learn)?;
+            text.push_str(&glyph_text);
+            words.last_mut().unwrap().push(Word {
+                text: glyph_text,
+                confidence,
+                x: left as u32,
+                y: top as u32,
+                width,
+                height,
+            });
+        }
+
+        let mean = |glyphs: &[&Word]| {
+            glyphs.iter().map(|glyph| glyph.confidence).sum::<f32>() / glyphs.len() as f32
+        };
+        let confidence = mean(&words.iter().flatten().collect::<Vec<_>>());
+        let words = words
+            .iter()
+            .map(|glyphs| {
+                let glyphs: Vec<&Word> = glyphs.iter().collect();
+                let left = glyphs.iter().map(|glyph| glyph.x).min().unwrap_or(0);
+                let top = glyphs.iter().map(|glyph| glyph.y).min().unwrap_or(0);
+                let right = glyphs.iter().map(|g| g.x + g.width).max().unwrap_or(0);
+                let bottom = glyphs.iter().map(|g| g.y + g.height).max().unwrap_or(0);
+                Word {
+                    text: glyphs.iter().map(|glyph| glyph.text.as_str()).collect(),
+                    confidence: mean(&glyphs),
+                    x: left,
+                    y: top,
+                    width: right - left,
+                    height: bottom - top,
+                }
+            })
+            .collect();
+        Ok(Recognition {
+            text,
+            confidence,
+            words: Some(words),
+        })
+    }
+
+    /// Find the text of a glyph and the confidence of the match. If asked to
+    /// learn, the glyph is added if it is unknown, or named if it is unnamed.
+    fn match_glyph(
+        &self,
+        bitmap: Bitmap,
+        rise: i64,
+        text_height: usize,
+        line_so_far: &str,
+        learn: bool,
+    ) -> Result<(String, f32)> {
+        {
+            let (glyphs, _) = &*self.glyphs.read().unwrap();
+            match best_match(glyphs, &bitmap, rise, text_height) {
+                Some((index, difference))
+                    if glyphs[index].text.is_some() || !self.interactive || !learn =>
+                {
+                    return Ok(match_result(&glyphs[index], difference))
+                }
+                None if !learn => return Ok((UNNAMED.to_owned(), 0.0)),
+                _ => {}
+            }
+        }
+        // Another thread may have learned the glyph while we were waiting
+        // for the lock, and asking happens one glyph at a time.
+        let (glyphs, changed) = &mut *self.glyphs.write().unwrap();
+        let found = best_match(glyphs, &bitmap, rise, text_height);
+        match found {
+            Some((index, difference)) if glyphs[index].text.is_some() || !self.interactive => {
+                return Ok(match_result(&glyphs[index], difference))
+            }
+            _ => {}
+        }
+        let text = if self.interactive {
+            ask_glyph(&bitmap, line_so_far)?
+        } else {
+            None
+        };
+        match found {
+            Some((index, _)) => glyphs[index].text = text.clone(),
+            None => glyphs.push(Glyph {
+                bitmap,
+                rise,
+                text: text.clone(),
+            }),
+        }
+        *changed |= found.is_none() || text.is_some();
+        Ok(match text {
+            Some(text) => (text, 100.0),
+            None => (UNNAMED.to_owned(), 0.0),
+        })
+    }
+}
+
+/// The index of the known glyph most like a bitmap, and the share of pixels
+/// which differ, if any is close enough.
+fn best_match(
+    glyphs: &[Glyph],
+    bitmap: &Bitmap,
+    rise: i64,
+    text_height: usize,
+) -> Option<(usize, f32)> {
+    glyphs
+        .iter()
+        .enumerate()
+        .filter(|(_, glyph)| {
+            glyph.bitmap.similar_size(bitmap) && glyph.similar_rise(rise, text_height)
+        })
+        .map(|(index, glyph)| (index, glyph.bitmap.difference(bitmap)))
+        .filter(|&(_, difference)| difference <= MAX_DIFFERENCE)
+        .min_by(|a, b| a.1.total_cmp(&b.1))
+}
+
+/// The text of a matched glyph, and the confidence of the match.
+fn match_result(glyph: &Glyph, difference: f32) -> (String, f32) {
+    match &glyph.text {
+        Some(text) => (text.clone(), 100.0 * (1.0 - difference)),
+        None => (UNNAMED.to_owned(), 0.0),
+    }
+}
+
+/// Group the components of a line into glyphs from left to right. Components
+/// which mostly overlap horizontally, like the dot and stem of an `i`, make
+/// up one glyph, while slanted neighbours which barely overlap do not.
+fn group_glyphs(mut components: Vec<Component>) -> Vec<Vec<Component>> {
+    components.sort_by_key(|component| component.x.start);
+    let mut glyphs: Vec<Vec<Component>> = Vec::new();
+    for component in components {
+        match glyphs.last_mut() {
+            Some(glyph)
+                if glyph.iter().any(|other| {
+                    let overlap = other.x.end.min(component.x.end) as i64
+                        - other.x.start.max(component.x.start) as i64;
+                    overlap * 2 >= other.x.len().min(component.x.len()) as i64
+                }) =>
+            {
+                glyph.push(component)
+            }
+            _ => glyphs.push(vec![component]),
+        }
+    }
+    glyphs
+}
+
+/// Find the baseline of a line, as the median bottom of its letters.
+fn baseline(glyphs: &[Vec<Component>], text_height: usize) -> usize {
+    let mut bottoms: Vec<usize> = glyphs
+        .iter()
+        .filter(|glyph| glyph.iter().any(|c| c.height() * 2 >= text_height))
+        .map(|glyph| glyph.iter().map(|c| c.y.end).max().unwrap_or(0))
+        .collect();
+    bottoms.sort_unstable();
+    bottoms.get(bottoms.len() / 2).copied().unwrap_or(0)
+}
+
+/// Show an unknown glyph on the terminal and ask for its text. Returns
+/// `None` if no text was given.
+fn ask_glyph(bitmap: &Bitmap, line_so_far: &str) -> Result<Option<String>> {
+    let mut stderr = io::stderr().lock();
+    writeln!(stderr, "Unknown glyph after \"{}\":", line_so_far).context(AskGlyphSnafu {})?;
+    for row in bitmap.pixels.chunks(bitmap.width) {
+        let row: String = row
+            .iter()
+            .map(|&pixel| if pixel { '#' } else { '.' })
+            .collect();
+        writeln!(stderr, "    {}", row).context(AskGlyphSnafu {})?;
+    }
+    write!(stderr, "Text of the glyph (empty to leave it unnamed): ").context(AskGlyphSnafu {})?;
+    stderr.flush().context(AskGlyphSnafu {})?;
+    let mut answer = String::new();
+    io::stdin()
+        .lock()
+        .read_line(&mut answer)
+        .context(AskGlyphSnafu {})?;
+    let answer = answer.trim_end_matches(['\r', '\n']);
+    Ok((!answer.is_empty()).then(|| answer.to_owned()))
+}
+
+/// Parse a glyph database. After a comment line, each glyph is a line
+/// `glyph WIDTH HEIGHT RISE TEXT`, where the text is left out for unnamed
+/// glyphs, followed by a row of `#` and `.` for each line of pixels.
+fn parse_database(text: &str, filename: &Path) -> Result<Vec<Glyph>> {
+    let mut glyphs = Vec::new();
+    let mut lines = text.lines().enumerate();
+    let invalid = |line: usize, message: &str| Error::ParseDatabase {
+        filename: filename.to_owned(),
+        line: line + 1,
+        message: message.to_owned(),
+    };
+    while let Some((number, line)) = lines.next() {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let mut fields = line.splitn(5, ' ');
+        if fields.next() != Some("glyph") {
+            return Err(invalid(number, "expected a glyph"));
+        }
+        let mut number_field = || fields.next().and_then(|field| field.parse::<i64>().ok());
+        let (width, height, rise) = match (number_field(), number_field(), number_field()) {
+            (Some(width), Some(height), Some(rise)) if width > 0 && height > 0 => {
+                (width as usize, height as usize, rise)
+            }
+            _ => return Err(invalid(number, "expected a width, height and rise")),
+        };
+        let text = fields.next().map(str::to_owned);
+        let mut pixels = Vec::with_capacity(width * height);
+        for _ in 0..height {
+            let (number, row) = lines
+                .next()
+                .ok_or_else(|| invalid(number, "missing rows of pixels"))?;
+            if row.chars().count() != width || !row.chars().all(|c| c == '#' || c == '.') {
+                return Err(invalid(number, "expected a row of `#` and `.`"));
+            }
+            pixels.extend(row.chars().map(|c| c == '#'));
+        }
+        glyphs.push(Glyph {
+            bitmap: Bitmap {
+                width,
+                height,
+                pixels,
+            },
+            rise,
+            text,
+        });
+    }
+    Ok(glyphs)
+}
+
+/// Write a glyph database in the format read by `parse_database`.
+fn format_database(glyphs: &[Glyph]) -> String {
+    let mut text = format!("{}\n", HEADER);
+    for glyph in glyphs {
+        text.push('\n');
+        text.push_str(&format!(
+            "glyph {} {} {}",
+            glyph.bitmap.width, glyph.bitmap.height, glyph.rise
+        ));
+        if let Some(glyph_text) = &glyph.text {
+            text.push(' ');
+            text.push_str(glyph_text);
+        }
+        text.push('\n');
+        for row in glyph.bitmap.pixels.chunks(glyph.bitmap.width) {
+            text.extend(row.iter().map(|&pixel| if pixel { '#' } else { '.' }));
+            text.push('\n');
+        }
+    }
+    text
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bitmap(rows: &[&str]) -> Bitmap {
+        Bitmap {
+            width: rows[0].len(),
+            height: rows.len(),
+            pixels: rows
+                .iter()
+                .flat_map(|row| row.chars().map(|c| c == '#'))
+                .collect(),
+        }
+    }
+
+    fn parse_error_line(text: &str) -> usize {
+        match parse_database(text, Path::new("glyphs.txt")) {
+            Err(Error::ParseDatabase { line, .. }) => line,
+            result => panic!("expected a parse error, got {:?}", result.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn round_trips_the_database() {
+        let glyphs = vec![
+            Glyph {
+                bitmap: bitmap(&["#.", "##"]),
+                rise: 0,
+                text: Some("L".to_owned()),
+            },
+            Glyph {
+                bitmap: bitmap(&["#.#"]),
+                rise: 3,
+                text: Some(". .".to_owned()),
+            },
+            Glyph {
+                bitmap: bitmap(&["#", "#"]),
+                rise: -2,
+                text: None,
+            },
+        ];
+        let text = format_database(&glyphs);
+        assert!(text.starts_with(HEADER));
+        assert!(text.contains("\nglyph 3 1 3 . .\n#.#\n"));
+        assert!(text.contains("\nglyph 1 2 -2\n#\n#\n"));
+        let parsed = parse_database(&text, Path::new("glyphs.txt")).unwrap();
+        assert_eq!(parsed, glyphs);
+    }
+
+    #[test]
+    fn reports_the_line_of_errors() {
+        let header = format!("{}\n\nglyph 2 2 0 x\n#.\n.#\n\n", HEADER);
+        assert_eq!(parse_error_line(&format!("{}glyph 2\n", header)), 7);
+        assert_eq!(parse_error_line(&format!("{}blob 1 1 0\n#\n", header)), 7);
+        assert_eq!(
+            parse_error_line(&format!("{}glyph 2 2 0 y\n##\n#\n", header)),
+            9
+        );
+        assert_eq!(
+            parse_error_line(&format!("{}glyph 2 2 0 y\n#x\n##\n", header)),
+            8
+        );
+        // Missing rows are reported at the glyph they belong to.
+        assert_eq!(
+            parse_error_line(&format!("{}glyph 2 3 0 y\n##\n", header)),
+            7
+        );
+    }
+
+    #[test]
+    fn tolerates_a_shift_by_a_pixel() {
+        let glyph = bitmap(&[".##.", "#..#", "#..#", ".##."]);
+        let shifted = bitmap(&["..##.", ".#..#", ".#..#", "..##."]);
+        assert_eq!(glyph.difference(&shifted), 0.0);
+        let other = bitmap(&["####", "#...", "#...", "####"]);
+        assert!(glyph.difference(&other) > MAX_DIFFERENCE);
+    }
+
+    #[test]
+    fn groups_the_dot_of_an_i_with_its_stem() {
+        // An `i` followed by an `l`, whose components start at the dot.
+        let mask = Mask {
+            width: 6,
+            height: 8,
+            pixels: [
+                "#...#.", //
+                "....#.", //
+                "#...#.", //
+                "#...#.", //
+                "#...#.", //
+                "#...#.", //
+                "#...#.", //
+                "#...#.", //
+            ]
+            .iter()
+            .flat_map(|row| row.chars().map(|c| c == '#'))
+            .collect(),
+        };
+        let glyphs = group_glyphs(connected_components(&mask));
+        let sizes: Vec<usize> = glyphs.iter().map(Vec::len).collect();
+        assert_eq!(sizes, [2, 1]);
+    }
+}
@@ -0,0 +1,206 @@
+//! Parsing for the plain-text header of `*.idx` files. The `vobsub` crate only
+//! exposes the palette, and Matroska stores this same header as the private
+//! codec data of VobSub tracks.
+
+use log::warn;
+use snafu::{ensure, OptionExt, Snafu};
+
+#[derive(Debug, Snafu)]
+pub enum Error {
+    #[snafu(display("Invalid frame size: {}", value))]
+    InvalidSize { value: String },
+
+    #[snafu(display("Invalid palette: {}", value))]
+    InvalidPalette { value: String },
+
+    #[snafu(display("Invalid stream declaration: {}", value))]
+    StreamDeclaration { value: String },
+}
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+/// The 16-color palette used by the subtitles, as sRGB triplets.
+pub type Palette = [[u8; 3]; 16];
+
+/// The fields of an `*.idx` header we care about.
+#[derive(Debug, Default)]
+pub struct IdxHeader {
+    /// Frame size of the video the subtitles belong to.
+    pub size: Option<(u32, u32)>,
+    /// The colors used for the subtitles.
+    pub palette: Option<Palette>,
+    /// Index of the stream to show by default.
+    pub langidx: Option<u64>,
+    /// The subtitle streams declared in the file.
+    pub streams: Vec<IdxStream>,
+}
+
+/// A subtitle stream declared with an `id: xx, index: N` line.
+#[derive(Debug)]
+pub struct IdxStream {
+    /// Two-letter language code.
+    pub language: String,
+    /// Index of the stream; its packets are in substream `0x20 + index`.
+    pub index: u64,
+}
+
+/// Parse the `key: value` lines of an `*.idx` header, ignoring keys we do not
+/// understand.
+pub fn parse_header(text: &str) -> Result<IdxHeader> {
+    let mut header = IdxHeader::default();
+    for line in text.lines() {
+        let (key, value) = match line.split_once(':') {
+            Some((key, value)) => (key.trim(), value.trim()),
+            None => continue,
+        };
+        match key {
+            // Without a size, the frame size is guessed from the subtitles,
+            // just as when the line is missing.
+            "size" => match parse_size(value) {
+                Ok(size) => header.size = Some(size),
+                Err(e) => warn!("Ignoring frame size of VobSub index: {}", e),
+            },
+            // A broken palette can be replaced with `--palette`, so it
+            // should not stop us from reading the rest.
+            "palette" => match parse_palette(value) {
+                Ok(palette) => header.palette = Some(palette),
+                Err(e) => warn!("Ignoring palette of VobSub index: {}", e),
+            },
+            // Like the `vobsub` crate, skip declarations we cannot read
+            // rather than the whole file.
+            "langidx" => match value.parse() {
+                Ok(langidx) => header.langidx = Some(langidx),
+                Err(_) => warn!("Ignoring default stream of VobSub index: {}", value),
+            },
+            "id" => match parse_stream(value) {
+                Ok(stream) => header.streams.push(stream),
+                Err(e) => warn!("Ignoring stream of VobSub index: {}", e),
+            },
+            _ => {}
+        }
+    }
+    Ok(header)
+}
+
+/// Parse a frame size like `720x480`.
+fn parse_size(value: &str) -> Result<(u32, u32)> {
+    value
+        .split_once('x')
+        .and_then(|(w, h)| Some((w.trim().parse().ok()?, h.trim().parse().ok()?)))
+        .context(InvalidSizeSnafu { value })
+}
+
+/// Parse the rest of a stream declaration like `id: en, index: 0`.
+fn parse_stream(value: &str) -> Result<IdxStream> {
+    value
+        .split_once(',')
+        .and_then(|(language, index)| {
+            let index = index.trim().strip_prefix("index:")?.trim().parse().ok()?;
+            Some(IdxStream {
+                language: language.trim().to_owned(),
+                index,
+            })
+        })
+        .context(StreamDeclarationSnafu { value })
+}
+
+/// Parse 16 comma-separated hex colors like `000000, f0f0f0, ...`.
+pub fn parse_palette(value: &str) -> Result<Palette> {
+    let colors = value
+        .split(',')
+        .map(|color| parse_rgb(color.trim()))
+        .collect::<Option<Vec<[u8; 3]>>>()
+        .context(InvalidPaletteSnafu { value })?;
+    ensure!(colors.len() == 16, InvalidPaletteSnafu { value });
+    let mut palette = [[0; 3]; 16];
+    palette.copy_from_slice(&colors);
+    Ok(palette)
+}
+
+/// Parse a 6-digit hex color, with or without a leading `#`.
+fn parse_rgb(color: &str) -> Option<[u8; 3]> {
+    let color = color.strip_prefix('#').unwrap_or(color);
+    if color.len() != 6 || !color.is_ascii() {
+        return None;
+    }
+    let channel = |i: usize| u8::from_str_radix(&color[i * 2..i * 2 + 2], 16).ok();
+    Some([channel(0)?, channel(1)?, channel(2)?])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PALETTE: &str = "000000, f0f0f0, cccccc, 999999, 3333fa, 1111bb, fa3333, bb1111, \
+        33fa33, 11bb11, fafa33, bbbb11, fa33fa, bb11bb, 33fafa, 11bbbb";
+
+    #[test]
+    fn parses_header() {
+        let text = format!(
+            "# VobSub index file, v7 (do not modify this line!)\n\
+             size: 720x576\n\
+             palette: {}\n\
+             langidx: 1\n\
+             id: en, index: 0\n\
+             timestamp: 00:00:01:000, filepos: 000000000\n\
+             id: de, index: 1\n",
+            PALETTE
+        );
+        let header = parse_header(&text).unwrap();
+        assert_eq!(header.size, Some((720, 576)));
+        let palette = header.palette.unwrap();
+        assert_eq!(palette[1], [0xf0, 0xf0, 0xf0]);
+        assert_eq!(palette[15], [0x11, 0xbb, 0xbb]);
+        assert_eq!(header.langidx, Some(1));
+        assert_eq!(header.streams.len(), 2);
+        assert_eq!(header.streams[1].language, "de");
+        assert_eq!(header.streams[1].index, 1);
+    }
+
+    #[test]
+    fn skips_malformed_declarations() {
+        let header = parse_header(
+            "langidx: \n\
+             id: en, index:\n\
+             id: fr\n\
+             id: de, index: 1\n",
+        )
+        .unwrap();
+        assert_eq!(header.langidx, None);
+        assert_eq!(header.streams.len(), 1);
+        assert_eq!(header.streams[0].language, "de");
+    }
+
+    #[test]
+    fn skips_truncated_size() {
+        let header = parse_header(&format!("size: 720x\npalette: {}\n", PALETTE)).unwrap();
+        assert_eq!(header.size, None);
+        assert!(header.palette.is_some());
+    }
+
+    #[test]
+    fn skips_broken_palette() {
+        let header =
+            parse_header("size: 720x576\npalette: 000000, ffffff\nid: en, index: 0\n").unwrap();
+        assert_eq!(header.palette, None);
+        assert_eq!(header.size, Some((720, 576)));
+        assert_eq!(header.streams.len(), 1);
+    }
+
+    #[test]
+    fn rejects_truncated_palette() {
+        let truncated = &PALETTE[..PALETTE.len() - 8];
+        assert!(matches!(
+            parse_palette(truncated),
+            Err(Error::InvalidPalette { .. })
+        ));
+    }
+
+    #[test]
+    fn parses_colors() {
+        assert_eq!(parse_rgb("#0a0B0c"), Some([0x0a, 0x0b, 0x0c]));
+        assert_eq!(parse_rgb("0a0b0"), None);
+        assert_eq!(parse_rgb("0a0b0g"), None);
+        assert_eq!(parse_rgb("0a0b\u{e9}"), None);
+    }
+}
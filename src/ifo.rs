@@ -0,0 +1,212 @@
+//! Reading the subpicture palette and stream languages out of DVD
+//! `VTS_xx_0.IFO` files, which is where this information lives for a `*.vob`
+//! file's subtitles.
+
+use crate::{bitmap::ycrcb_to_rgb_bt601, idx::Palette};
+use snafu::{ensure, OptionExt, ResultExt, Snafu};
+use std::{collections::HashMap, fs, io, path::Path};
+
+const VTS_MAGIC: &[u8] = b"DVDVIDEO-VTS";
+const SECTOR_SIZE: usize = 2048;
+/// Offset of the sector pointer to the title set's program chain table.
+const VTS_PGCIT_SECTOR: usize = 0xcc;
+/// Offset of the video attributes of the title set.
+const VTS_VIDEO_ATTRIBUTES: usize = 0x200;
+/// Offset of the number of subpicture streams in the title set.
+const VTS_SUBPICTURE_COUNT: usize = 0x254;
+/// Offset of the 6-byte attributes of each subpicture stream.
+const VTS_SUBPICTURE_ATTRIBUTES: usize = 0x256;
+/// Offset of the playback time within a program chain.
+const PGC_PLAYBACK_TIME: usize = 0x04;
+/// Offset of the subpicture stream control table within a program chain.
+const PGC_SUBPICTURE_CONTROL: usize = 0x1c;
+/// Offset of the 16-color YCrCb palette within a program chain.
+const PGC_PALETTE: usize = 0xa4;
+
+#[derive(Debug, Snafu)]
+pub enum Error {
+    #[snafu(display("Could not read IFO file: {}", source))]
+    Io { source: io::Error },
+
+    #[snafu(display("Not a DVD video title set IFO file"))]
+    NotVts,
+
+    #[snafu(display("IFO file is truncated"))]
+    Truncated,
+
+    #[snafu(display("IFO file contains no program chains"))]
+    NoProgramChain,
+}
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+/// The subtitle information of a DVD title set.
+#[derive(Debug)]
+pub struct TitleSet {
+    /// Frame size which subpicture coordinates are relative to.
+    pub frame_size: (u32, u32),
+    pub palette: Palette,
+    /// Language codes by private stream 1 substream ID.
+    pub languages: HashMap<u8, String>,
+}
+
+/// Read the subtitle information of the longest program chain in a title
+/// set, which is almost always the main feature.
+pub fn read_title_set<P: AsRef<Path>>(path: P) -> Result<TitleSet> {
+    let data = fs::read(path).context(IoSnafu {})?;
+    ensure!(data.starts_with(VTS_MAGIC), NotVtsSnafu {});
+
+    let pgcit = read_u32(&data, VTS_PGCIT_SECTOR)? as usize * SECTOR_SIZE;
+    let pgc_count = read_u16(&data, pgcit)? as usize;
+    let mut best: Option<(u32, usize)> = None;
+    for i in 0..pgc_count {
+        // Each search pointer is a 4-byte category followed by a 4-byte
+        // offset relative to the start of the table.
+        let pgc = pgcit + read_u32(&data, pgcit + 8 + i * 8 + 4)? as usize;
+        let duration = playback_time_to_seconds(read_u32(&data, pgc + PGC_PLAYBACK_TIME)?);
+        let longer = match best {
+            Some((best_duration, _)) => duration > best_duration,
+            None => true,
+        };
+        if longer {
+            best = Some((duration, pgc));
+        }
+    }
+    let (_, pgc) = best.context(NoProgramChainSnafu {})?;
+
+    let mut palette = [[0; 3]; 16];
+    for (i, color) in palette.iter_mut().enumerate() {
+        let offset = pgc + PGC_PALETTE + i * 4;
+        let entry = data.get(offset..offset + 4).context(TruncatedSnafu {})?;
+        // The first byte of each entry is unused.
+        *color = ycrcb_to_rgb_bt601(entry[1], entry[2], entry[3]);
+    }
+
+    // Subpictures always cover a full-size frame of the video standard.
+    let frame_size = match read_u16(&data, VTS_VIDEO_ATTRIBUTES)? >> 12 & 0x03 {
+        1 => (720, 576),
+        _ => (720, 480),
+    };
+
+    // Each subpicture stream may be stored as up to four substreams, one for
+    // each display mode (4:3, widescreen, letterbox and pan-scan).
+    let mut languages = HashMap::new();
+    let stream_count = read_u16(&data, VTS_SUBPICTURE_COUNT)?.min(32) as usize;
+    for i in 0..stream_count {
+        let attributes = VTS_SUBPICTURE_ATTRIBUTES + i * 6;
+        let code = data
+            .get(attributes + 2..attributes + 4)
+            .context(TruncatedSnafu {})?;
+        if data[attributes] & 0x03 != 1 || !code.iter().all(u8::is_ascii_alphabetic) {
+            continue;
+        }
+        let language = String::from_utf8_lossy(code).to_ascii_lowercase();
+        let control = read_u32(&data, pgc + PGC_SUBPICTURE_CONTROL + i * 4)?;
+        if control & 0x8000_0000 == 0 {
+            continue;
+        }
+        for shift in [24, 16, 8, 0] {
+            let substream_id = 0x20 + (control >> shift & 0x1f) as u8;
+            languages
+                .entry(substream_id)
+                .or_insert_with(|| language.clone());
+        }
+    }
+
+    Ok(TitleSet {
+        frame_size,
+        palette,
+        languages,
+    })
+}
+
+/// Convert a BCD playback time (hours, minutes, seconds, frames) to whole
+/// seconds.
+fn playback_time_to_seconds(time: u32) -> u32 {
+    let bcd = |byte: u32| (byte >> 4 & 0xf) * 10 + (byte & 0xf);
+    let [hours, minutes, seconds, _frames] = time.to_be_bytes().map(u32::from);
+    bcd(hours) * 3600 + bcd(minutes) * 60 + bcd(seconds)
+}
+
+fn read_u16(data: &[u8], offset: usize) -> Result<u16> {
+    let bytes = data.get(offset..offset + 2).context(TruncatedSnafu {})?;
+    Ok(u16::from_be_bytes([bytes[0], bytes[1]]))
+}
+
+fn read_u32(data: &[u8], offset: usize) -> Result<u32> {
+    let bytes = data.get(offset..offset + 4).context(TruncatedSnafu {})?;
+    Ok(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    /// A title set with a 10 minute and a 90 minute program chain, where only
+    /// the longer one has white in its palette and shows German subtitles
+    /// from substream 0x21.
+    fn title_set() -> Vec<u8> {
+        let mut data = vec![0; 2 * SECTOR_SIZE];
+        data[..VTS_MAGIC.len()].copy_from_slice(VTS_MAGIC);
+        data[VTS_PGCIT_SECTOR..VTS_PGCIT_SECTOR + 4].copy_from_slice(&1u32.to_be_bytes());
+        let pgcit = SECTOR_SIZE;
+        data[pgcit..pgcit + 2].copy_from_slice(&2u16.to_be_bytes());
+        for (i, &(offset, time, y)) in [(0x100, 0x0010_0000, 0x80), (0x300, 0x0130_0000, 0xeb)]
+            .iter()
+            .enumerate()
+        {
+            let pointer = pgcit + 8 + i * 8 + 4;
+            data[pointer..pointer + 4].copy_from_slice(&(offset as u32).to_be_bytes());
+            let pgc = pgcit + offset;
+            data[pgc + PGC_PLAYBACK_TIME..pgc + PGC_PLAYBACK_TIME + 4]
+                .copy_from_slice(&(time as u32).to_be_bytes());
+            let entry = pgc + PGC_PALETTE + 4;
+            data[entry..entry + 4].copy_from_slice(&[0, y, 0x80, 0x80]);
+        }
+        data[VTS_SUBPICTURE_COUNT + 1] = 1;
+        data[VTS_SUBPICTURE_ATTRIBUTES] = 0x01;
+        data[VTS_SUBPICTURE_ATTRIBUTES + 2..VTS_SUBPICTURE_ATTRIBUTES + 4].copy_from_slice(b"DE");
+        let control = SECTOR_SIZE + 0x300 + PGC_SUBPICTURE_CONTROL;
+        data[control..control + 4].copy_from_slice(&0x8101_0101u32.to_be_bytes());
+        data
+    }
+
+    fn write_temp(name: &str, data: &[u8]) -> PathBuf {
+        let path =
+            std::env::temp_dir().join(format!("vobsubocr-{}-{}.IFO", std::process::id(), name));
+        fs::write(&path, data).unwrap();
+        path
+    }
+
+    #[test]
+    fn reads_longest_program_chain() {
+        let path = write_temp("title-set", &title_set());
+        let title_set = read_title_set(&path);
+        fs::remove_file(&path).unwrap();
+        let title_set = title_set.unwrap();
+        assert_eq!(title_set.palette[1], [255, 255, 255]);
+        assert_eq!(title_set.languages.len(), 1);
+        assert_eq!(title_set.languages[&0x21], "de");
+    }
+
+    #[test]
+    fn rejects_truncated_and_foreign_files() {
+        let mut data = title_set();
+        data.truncate(SECTOR_SIZE + 0x300 + PGC_PALETTE);
+        let path = write_temp("truncated", &data);
+        let truncated = read_title_set(&path);
+        fs::remove_file(&path).unwrap();
+        assert!(matches!(truncated, Err(Error::Truncated)));
+
+        let path = write_temp("foreign", b"DVDVIDEO-VMG");
+        let foreign = read_title_set(&path);
+        fs::remove_file(&path).unwrap();
+        assert!(matches!(foreign, Err(Error::NotVts)));
+    }
+
+    #[test]
+    fn converts_playback_times() {
+        assert_eq!(playback_time_to_seconds(0x0130_2540), 5425);
+    }
+}
@@ -0,0 +1,588 @@
+//! Reading subtitle streams out of the supported input formats. DVD formats
+//! are reduced to subpicture units, which the `vobsub` crate then decodes,
+//! Blu-ray formats to PGS segments, and broadcasts to DVB segments.
+
+use crate::{bitmap::BitmapSubtitle, dvb, idx, ifo, mkv, opt::Opt, pgs, ps, ts};
+use log::{info, warn};
+use snafu::{OptionExt, ResultExt, Snafu};
+use std::{
+    collections::{BTreeMap, HashMap},
+    convert::TryFrom,
+    fs::{self, File},
+    io::{self, BufReader, Read},
+    path::{Path, PathBuf},
+};
+
+/// Matroska codec ID of VobSub tracks.
+const VOBSUB_CODEC_ID: &str = "S_VOBSUB";
+
+/// Matroska codec ID of PGS tracks.
+const PGS_CODEC_ID: &str = "S_HDMV/PGS";
+
+/// Substream ID of the first DVD subpicture stream.
+const FIRST_SUBPICTURE_STREAM: u8 = 0x20;
+
+#[derive(Debug, Snafu)]
+pub enum Error {
+    #[snafu(display("Could not open input file: {}", source))]
+    Open { source: io::Error },
+
+    #[snafu(display("Could not read {}: {}", filename.display(), source))]
+    ReadFile {
+        filename: PathBuf,
+        source: io::Error,
+    },
+
+    #[snafu(display("Could not parse VobSub index: {}", source))]
+    ParseIndex { source: idx::Error },
+
+    #[snafu(display("{}", source))]
+    ReadMatroska { source: mkv::Error },
+
+    #[snafu(display("Could not parse header of VobSub track {}: {}", track, source))]
+    ParseTrackHeader { track: u64, source: idx::Error },
+
+    #[snafu(display("Could not read PGS subtitles: {}", source))]
+    ReadPgs { source: pgs::Error },
+
+    #[snafu(display("Could not read IFO file {}: {}", filename.display(), source))]
+    ReadIfo {
+        filename: PathBuf,
+        source: ifo::Error,
+    },
+
+    #[snafu(display("No subtitle streams found"))]
+    NoStreams,
+
+    #[snafu(display("No subtitle stream with index {}", index))]
+    NoSuchStream { index: u64 },
+
+    #[snafu(display("No subtitle stream with language {}", language))]
+    NoStreamWithLanguage { language: String },
+
+    #[snafu(display("No subtitle stream with PID {}", pid))]
+    NoStreamWithPid { pid: u16 },
+
+    #[snafu(display("No palette for subtitle stream {}; specify one with --palette", index))]
+    MissingPalette { index: u64 },
+}
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+/// A subtitle stream, before decoding.
+pub struct SubtitleStream {
+    /// The `index:` of a stream in an `*.idx` file, the subpicture stream
+    /// number in a program stream, the Matroska track number, or the position
+    /// of the stream in a transport stream.
+    pub index: u64,
+    /// The PID carrying the stream, for transport streams.
+    pub pid: Option<u16>,
+    pub language: Option<String>,
+    /// Frame size of the video, which subtitle positions are relative to.
+    pub frame_size: Option<(u32, u32)>,
+    /// Whether the input marks this stream as the one to show by default.
+    pub default: bool,
+    data: StreamData,
+}
+
+/// The undecoded subtitles of a stream.
+enum StreamData {
+    /// DVD subpicture units, and the palette they use.
+    VobSub {
+        palette: Option<idx::Palette>,
+        subpictures: Vec<ps::Subpicture>,
+    },
+    /// Blu-ray presentation graphics segments.
+    Pgs(Vec<pgs::Segment>),
+    /// DVB subtitle segments, which may hold several pages.
+    Dvb {
+        segments: Vec<dvb::Segment>,
+        composition_page: u16,
+        ancillary_page: u16,
+    },
+}
+
+#[cfg(test)]
+impl SubtitleStream {
+    /// A stream without subtitles, for testing code that only looks at its
+    /// description.
+    pub fn empty(index: u64, language: Option<&str>) -> Self {
+        SubtitleStream {
+            index,
+            pid: None,
+            language: language.map(str::to_owned),
+            default: false,
+            frame_size: None,
+            data: StreamData::Pgs(Vec::new()),
+        }
+    }
+}
+
+impl SubtitleStream {
+    /// The number of subtitles in the stream.
+    pub fn len(&self) -> usize {
+        match &self.data {
+            StreamData::VobSub { subpictures, .. } => subpictures.len(),
+            StreamData::Pgs(segments) => pgs::count_subtitles(segments),
+            StreamData::Dvb {
+                segments,
+                composition_page,
+                ..
+            } => dvb::count_subtitles(segments, *composition_page),
+        }
+    }
+
+    /// Decode the subtitles of this stream.
+    pub fn decode(&self) -> Result<Vec<BitmapSubtitle>> {
+        let subtitles = match &self.data {
+            StreamData::VobSub {
+                palette,
+                subpictures,
+            } => {
+                let palette = palette.context(MissingPaletteSnafu { index: self.index })?;
+
+                // Repackage the subpictures as a program stream containing
+                // only this stream, which is what the `vobsub` crate expects.
+                let mut sub_data = Vec::new();
+                for subpicture in subpictures {
+                    ps::write_subpicture(
+                        &mut sub_data,
+                        FIRST_SUBPICTURE_STREAM,
+                        subpicture.seconds,
+                        &subpicture.data,
+                    );
+                }
+                vobsub::subtitles(&sub_data)
+                    .map(|sub| {
+                        sub.map(|sub| BitmapSubtitle::from_vobsub(&sub, &palette))
+                            .map_err(|e| e.to_string())
+                    })
+                    .collect()
+            }
+            StreamData::Pgs(segments) => pgs::subtitles(segments)
+                .into_iter()
+                .map(|sub| sub.map_err(|e| e.to_string()))
+                .collect::<Vec<_>>(),
+            StreamData::Dvb {
+                segments,
+                composition_page,
+                ancillary_page,
+            } => dvb::subtitles(segments, *composition_page, *ancillary_page)
+                .into_iter()
+                .map(|sub| sub.map_err(|e| e.to_string()))
+                .collect(),
+        };
+        Ok(subtitles
+            .into_iter()
+            .filter_map(|sub| match sub {
+                Ok(sub) => Some(sub),
+                Err(e) => {
+                    warn!(
+                        "warning: unable to read subtitle: {}. (This can usually be safely ignored.)",
+                        e
+                    );
+                    None
+                }
+            })
+            .collect())
+    }
+}
+
+/// Read all subtitle streams from the input file.
+pub fn read_streams(opt: &Opt) -> Result<Vec<SubtitleStream>> {
+    if mkv::is_matroska(&opt.input).context(OpenSnafu {})? {
+        read_matroska(opt)
+    } else if pgs::is_sup(&opt.input).context(OpenSnafu {})? {
+        read_sup(opt)
+    } else if let Some(packet_size) = ts::packet_size(&opt.input).context(OpenSnafu {})? {
+        read_transport_stream(opt, packet_size)
+    } else if ps::is_program_stream(&opt.input).context(OpenSnafu {})? {
+        read_program_stream(opt)
+    } else {
+        read_index(opt)
+    }
+}
+
+/// Pick the stream requested with `--stream`, `--track-language` or `--pid`,
+/// or else the default one.
+pub fn select_stream(streams: Vec<SubtitleStream>, opt: &Opt) -> Result<SubtitleStream> {
+    let mut streams = streams.into_iter();
+    let stream = if let Some(index) = opt.stream {
+        streams
+            .find(|stream| stream.index == index)
+            .context(NoSuchStreamSnafu { index })?
+    } else if let Some(language) = &opt.track_language {
+        streams
+            .find(|stream| {
+                stream
+                    .language
+                    .as_ref()
+                    .is_some_and(|l| l.eq_ignore_ascii_case(language))
+            })
+            .context(NoStreamWithLanguageSnafu { language })?
+    } else if let Some(pid) = opt.pid {
+        streams
+            .find(|stream| stream.pid == Some(pid))
+            .context(NoStreamWithPidSnafu { pid })?
+    } else {
+        let streams: Vec<SubtitleStream> = streams.collect();
+        let default = streams
+            .iter()
+            .position(|stream| stream.default)
+            .unwrap_or(0);
+        streams
+            .into_iter()
+            .nth(default)
+            .context(NoStreamsSnafu {})?
+    };
+    info!(
+        "Using subtitle stream {} ({})",
+        stream.index,
+        stream.language.as_deref().unwrap_or("unknown language")
+    );
+    Ok(stream)
+}
+
+/// Read the streams of an `*.idx`/`*.sub` pair.
+fn read_index(opt: &Opt) -> Result<Vec<SubtitleStream>> {
+    let text = fs::read(&opt.input).context(ReadFileSnafu {
+        filename: opt.input.clone(),
+    })?;
+    let header = idx::parse_header(&String::from_utf8_lossy(&text)).context(ParseIndexSnafu {})?;
+
+    let sub_path = opt.input.with_extension("sub");
+    let mut subpictures = group_by_substream(read_subpictures(&[sub_path], false)?);
+
+    // Include streams which are declared but empty, as well as streams which
+    // are present but not declared.
+    let mut streams: Vec<SubtitleStream> = header
+        .streams
+        .iter()
+        .map(|stream| SubtitleStream {
+            index: stream.index,
+            pid: None,
+            language: Some(stream.language.clone()),
+            frame_size: header.size,
+            default: header.langidx == Some(stream.index),
+            data: StreamData::VobSub {
+                palette: opt.palette.or(header.palette),
+                subpictures: u8::try_from(stream.index)
+                    .ok()
+                    .and_then(|index| FIRST_SUBPICTURE_STREAM.checked_add(index))
+                    .and_then(|substream_id| subpictures.remove(&substream_id))
+                    .unwrap_or_default(),
+            },
+        })
+        .collect();
+    streams.extend(subpictures.into_iter().map(|(substream_id, subpictures)| {
+        let index = (substream_id - FIRST_SUBPICTURE_STREAM) as u64;
+        SubtitleStream {
+            index,
+            pid: None,
+            language: None,
+            frame_size: header.size,
+            default: header.langidx == Some(index),
+            data: StreamData::VobSub {
+                palette: opt.palette.or(header.palette),
+                subpictures,
+            },
+        }
+    }));
+    Ok(streams)
+}
+
+/// Read the VobSub and PGS tracks of a Matroska file.
+fn read_matroska(opt: &Opt) -> Result<Vec<SubtitleStream>> {
+    let mkv = mkv::read(&opt.input, |track| {
+        track.codec_id == VOBSUB_CODEC_ID || track.codec_id == PGS_CODEC_ID
+    })
+    .context(ReadMatroskaSnafu {})?;
+    let mut blocks: HashMap<u64, Vec<mkv::Block>> = HashMap::new();
+    for block in mkv.blocks {
+        blocks.entry(block.track).or_default().push(block);
+    }
+    mkv.tracks
+        .into_iter()
+        .map(|track| {
+            let blocks = blocks.remove(&track.number).unwrap_or_default();
+            let (data, frame_size) = if track.codec_id == PGS_CODEC_ID {
+                let mut segments = Vec::new();
+                for block in blocks {
+                    segments.extend(
+                        pgs::parse_segments(block.timestamp, &block.data)
+                            .context(ReadPgsSnafu {})?,
+                    );
+                }
+                let frame_size = pgs::frame_size(&segments);
+                (StreamData::Pgs(segments), frame_size)
+            } else {
+                let header = idx::parse_header(&String::from_utf8_lossy(&track.codec_private))
+                    .context(ParseTrackHeaderSnafu {
+                        track: track.number,
+                    })?;
+                let data = StreamData::VobSub {
+                    palette: opt.palette.or(header.palette),
+                    subpictures: blocks
+                        .into_iter()
+                        .map(|block| ps::Subpicture {
+                            substream_id: FIRST_SUBPICTURE_STREAM,
+                            seconds: block.timestamp,
+                            data: block.data,
+                        })
+                        .collect(),
+                };
+                (data, header.size)
+            };
+            Ok(SubtitleStream {
+                index: track.number,
+                pid: None,
+                language: Some(track.language),
+                frame_size,
+                default: track.default,
+                data,
+            })
+        })
+        .collect()
+}
+
+/// Read the single stream of a Blu-ray `*.sup` file.
+fn read_sup(opt: &Opt) -> Result<Vec<SubtitleStream>> {
+    let segments = pgs::read_sup(&opt.input).context(ReadPgsSnafu {})?;
+    Ok(vec![SubtitleStream {
+        index: 0,
+        pid: None,
+        language: None,
+        frame_size: pgs::frame_size(&segments),
+        default: true,
+        data: StreamData::Pgs(segments),
+    }])
+}
+
+/// Read the subpicture streams of a DVD program stream, such as a `*.vob`
+/// file, with the palette and languages from its IFO file if there is one.
+/// With `--palette`, the IFO file only adds the languages, so one which
+/// cannot be read is skipped.
+fn read_program_stream(opt: &Opt) -> Result<Vec<SubtitleStream>> {
+    let title_set = match find_ifo(&opt.input) {
+        Some(filename) => {
+            info!("Reading palette and languages from {}", filename.display());
+            match ifo::read_title_set(&filename) {
+                Ok(title_set) => Some(title_set),
+                Err(e) if opt.palette.is_some() => {
+                    warn!("Ignoring IFO file {}: {}", filename.display(), e);
+                    None
+                }
+                Err(source) => return Err(Error::ReadIfo { filename, source }),
+            }
+        }
+        None => None,
+    };
+    let palette = opt
+        .palette
+        .or_else(|| title_set.as_ref().map(|title_set| title_set.palette));
+
+    let parts = title_set_parts(&opt.input);
+    if parts.len() > 1 {
+        info!(
+            "Reading {} VOB files of the title set, from {} to {}",
+            parts.len(),
+            parts[0].display(),
+            parts[parts.len() - 1].display()
+        );
+    }
+    Ok(group_by_substream(read_subpictures(&parts, true)?)
+        .into_iter()
+        .map(|(substream_id, subpictures)| SubtitleStream {
+            index: (substream_id - FIRST_SUBPICTURE_STREAM) as u64,
+            pid: None,
+            language: title_set
+                .as_ref()
+                .and_then(|title_set| title_set.languages.get(&substream_id).cloned()),
+            frame_size: title_set.as_ref().map(|title_set| title_set.frame_size),
+            default: false,
+            data: StreamData::VobSub {
+                palette,
+                subpictures,
+            },
+        })
+        .collect())
+}
+
+/// Read the DVB subtitle services of a transport stream, such as a `*.ts`
+/// broadcast recording.
+fn read_transport_stream(opt: &Opt, packet_size: usize) -> Result<Vec<SubtitleStream>> {
+    let file = File::open(&opt.input).context(OpenSnafu {})?;
+    let stream = ts::demux_subtitles(BufReader::new(file), packet_size).context(ReadFileSnafu {
+        filename: opt.input.clone(),
+    })?;
+    let mut segments: HashMap<u16, Vec<dvb::Segment>> = HashMap::new();
+    for pes in stream.packets {
+        match dvb::parse_segments(pes.seconds, &pes.data) {
+            Ok(parsed) => segments.entry(pes.pid).or_default().extend(parsed),
+            Err(e) => warn!("Skipping subtitle packet on PID {}: {}", pes.pid, e),
+        }
+    }
+    Ok(stream
+        .services
+        .into_iter()
+        .enumerate()
+        .map(|(index, service)| {
+            // Services on the same PID share its segments.
+            let segments = segments.get(&service.pid).cloned().unwrap_or_default();
+            SubtitleStream {
+                index: index as u64,
+                pid: Some(service.pid),
+                language: Some(service.language),
+                frame_size: Some(dvb::frame_size(&segments)),
+                default: false,
+                data: StreamData::Dvb {
+                    segments,
+                    composition_page: service.composition_page,
+                    ancillary_page: service.ancillary_page,
+                },
+            }
+        })
+        .collect())
+}
+
+/// Demultiplex all subpictures from program stream files, read one after
+/// another as a single stream. See `ps::demux_subpictures` for
+/// `follow_clock`.
+fn read_subpictures(paths: &[PathBuf], follow_clock: bool) -> Result<Vec<ps::Subpicture>> {
+    let mut reader: Box<dyn Read> = Box::new(io::empty());
+    for path in paths {
+        let file = File::open(path).context(ReadFileSnafu {
+            filename: path.clone(),
+        })?;
+        reader = Box::new(reader.chain(file));
+    }
+    ps::demux_subpictures(BufReader::new(reader), follow_clock).context(ReadFileSnafu {
+        filename: paths[0].clone(),
+    })
+}
+
+/// Split subpictures up by substream, in order of substream ID.
+fn group_by_substream(subpictures: Vec<ps::Subpicture>) -> BTreeMap<u8, Vec<ps::Subpicture>> {
+    let mut streams: BTreeMap<u8, Vec<ps::Subpicture>> = BTreeMap::new();
+    for subpicture in subpictures {
+        streams
+            .entry(subpicture.substream_id)
+            .or_default()
+            .push(subpicture);
+    }
+    streams
+}
+
+/// The VOB files of a title set to read, starting with the given one: for
+/// `VTS_01_1.VOB`, it and `VTS_01_2.VOB`, `VTS_01_3.VOB` and so on, as long
+/// as they exist. Any other file is read on its own.
+fn title_set_parts(input: &Path) -> Vec<PathBuf> {
+    let mut parts = vec![input.to_owned()];
+    let (stem, extension) = match (
+        input.file_stem().and_then(|stem| stem.to_str()),
+        input.extension().and_then(|extension| extension.to_str()),
+    ) {
+        (Some(stem), Some(extension)) => (stem, extension),
+        _ => return parts,
+    };
+    let part = match (stem.get(..4), stem.get(7..)) {
+        (Some(prefix), Some(part)) if stem.len() == 8 && prefix.eq_ignore_ascii_case("VTS_") => {
+            part.parse::<u8>().ok()
+        }
+        _ => None,
+    };
+    // Part 0 is the menu, which is not part of the title.
+    if let Some(first) = part.filter(|&part| part > 0) {
+        parts.extend(
+            (first + 1..=9)
+                .map(|part| input.with_file_name(format!("{}{}.{}", &stem[..7], part, extension)))
+                .take_while(|path| path.is_file()),
+        );
+    }
+    parts
+}
+
+/// Find the IFO file of the title set a VOB file belongs to, e.g.
+/// `VTS_01_0.IFO` for `VTS_01_1.VOB`, falling back to an IFO file with the
+/// same name as the input.
+fn find_ifo(input: &Path) -> Option<PathBuf> {
+    let stem = input.file_stem()?.to_str()?;
+    let mut stems = Vec::new();
+    match (stem.get(..4), stem.get(..7)) {
+        (Some(prefix), Some(title_set))
+            if stem.len() == 8 && prefix.eq_ignore_ascii_case("VTS_") =>
+        {
+            stems.push(format!("{}0", title_set))
+        }
+        _ => {}
+    }
+    stems.push(stem.to_owned());
+    stems
+        .iter()
+        .flat_map(|stem| {
+            ["IFO", "ifo", "BUP", "bup"]
+                .map(|ext| input.with_file_name(format!("{}.{}", stem, ext)))
+        })
+        .find(|path| path.is_file())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+
+    #[test]
+    fn keeps_index_timestamps_minutes_apart() {
+        let dir = std::env::temp_dir().join(format!("vobsubocr-{}-index", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let idx_path = dir.join("movie.idx");
+        fs::write(&idx_path, "id: en, index: 0\n").unwrap();
+        // Subpicture units of just their size, which is all the demuxer
+        // looks at.
+        let mut sub = Vec::new();
+        for &seconds in &[5.0, 185.0, 1805.0] {
+            ps::write_subpicture(&mut sub, FIRST_SUBPICTURE_STREAM, seconds, &[0, 4, 0, 0]);
+        }
+        fs::write(dir.join("movie.sub"), &sub).unwrap();
+
+        let opt = Opt::parse_from([
+            "vobsubocr".as_ref(),
+            "-l".as_ref(),
+            "eng".as_ref(),
+            idx_path.as_os_str(),
+        ]);
+        let streams = read_index(&opt);
+        fs::remove_dir_all(&dir).unwrap();
+        let streams = streams.unwrap();
+        assert_eq!(streams.len(), 1);
+        let seconds: Vec<f64> = match &streams[0].data {
+            StreamData::VobSub { subpictures, .. } => subpictures
+                .iter()
+                .map(|subpicture| subpicture.seconds)
+                .collect(),
+            _ => panic!("expected a VobSub stream"),
+        };
+        assert_eq!(seconds, [5.0, 185.0, 1805.0]);
+    }
+
+    #[test]
+    fn finds_title_set_parts() {
+        let dir = std::env::temp_dir().join(format!("vobsubocr-{}-vts", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        for name in [
+            "VTS_01_0.VOB",
+            "VTS_01_1.VOB",
+            "VTS_01_2.VOB",
+            "VTS_01_4.VOB",
+        ] {
+            fs::write(dir.join(name), []).unwrap();
+        }
+        let first = title_set_parts(&dir.join("VTS_01_1.VOB"));
+        let menu = title_set_parts(&dir.join("VTS_01_0.VOB"));
+        let other = title_set_parts(&dir.join("movie.vob"));
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(first, [dir.join("VTS_01_1.VOB"), dir.join("VTS_01_2.VOB")]);
+        assert_eq!(menu, [dir.join("VTS_01_0.VOB")]);
+        assert_eq!(other, [dir.join("movie.vob")]);
+    }
+}
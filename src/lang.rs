@@ -0,0 +1,140 @@
+//! Mapping the language codes found in subtitle streams to Tesseract
+//! language names.
+
+/// Tesseract languages for ISO 639-1 codes (as used in `*.idx` files and on
+/// DVDs) and for ISO 639-2/B codes (as used in Matroska files) which differ
+/// from Tesseract's names. Other three-letter codes are passed through as-is.
+const LANGUAGES: &[(&str, &str)] = &[
+    ("af", "afr"),
+    ("ar", "ara"),
+    ("be", "bel"),
+    ("bg", "bul"),
+    ("bn", "ben"),
+    ("bs", "bos"),
+    ("ca", "cat"),
+    ("cs", "ces"),
+    ("cy", "cym"),
+    ("da", "dan"),
+    ("de", "deu"),
+    ("el", "ell"),
+    ("en", "eng"),
+    ("eo", "epo"),
+    ("es", "spa"),
+    ("et", "est"),
+    ("eu", "eus"),
+    ("fa", "fas"),
+    ("fi", "fin"),
+    ("fr", "fra"),
+    ("ga", "gle"),
+    ("gl", "glg"),
+    ("he", "heb"),
+    ("hi", "hin"),
+    ("hr", "hrv"),
+    ("hu", "hun"),
+    ("hy", "hye"),
+    ("id", "ind"),
+    ("is", "isl"),
+    ("it", "ita"),
+    ("iw", "heb"),
+    ("ja", "jpn"),
+    ("ka", "kat"),
+    ("kk", "kaz"),
+    ("ko", "kor"),
+    ("lt", "lit"),
+    ("lv", "lav"),
+    ("mk", "mkd"),
+    ("ms", "msa"),
+    ("mt", "mlt"),
+    ("nb", "nor"),
+    ("nl", "nld"),
+    ("nn", "nor"),
+    ("no", "nor"),
+    ("pl", "pol"),
+    ("pt", "por"),
+    ("ro", "ron"),
+    ("ru", "rus"),
+    ("sk", "slk"),
+    ("sl", "slv"),
+    ("sq", "sqi"),
+    ("sr", "srp"),
+    ("sv", "swe"),
+    ("ta", "tam"),
+    ("th", "tha"),
+    ("tl", "tgl"),
+    ("tr", "tur"),
+    ("uk", "ukr"),
+    ("ur", "urd"),
+    ("vi", "vie"),
+    ("zh", "chi_sim"),
+    ("alb", "sqi"),
+    ("arm", "hye"),
+    ("baq", "eus"),
+    ("chi", "chi_sim"),
+    ("cze", "ces"),
+    ("dut", "nld"),
+    ("fre", "fra"),
+    ("geo", "kat"),
+    ("ger", "deu"),
+    ("gre", "ell"),
+    ("ice", "isl"),
+    ("mac", "mkd"),
+    ("may", "msa"),
+    ("nob", "nor"),
+    ("nno", "nor"),
+    ("per", "fas"),
+    ("rum", "ron"),
+    ("slo", "slk"),
+    ("wel", "cym"),
+    ("zho", "chi_sim"),
+];
+
+/// ISO 639-2 codes which do not name an actual language.
+const SPECIAL_CODES: &[&str] = &["mis", "mul", "und", "zxx"];
+
+/// Find the Tesseract language for a stream's language code, preferring the
+/// user's mapping over the built-in one.
+pub fn tesseract_language(code: &str, mapping: &[(String, String)]) -> Option<String> {
+    let code = code.trim().to_ascii_lowercase();
+    if let Some((_, lang)) = mapping
+        .iter()
+        .find(|(from, _)| from.eq_ignore_ascii_case(&code))
+    {
+        return Some(lang.clone());
+    }
+    if let Some((_, lang)) = LANGUAGES.iter().find(|(from, _)| *from == code) {
+        return Some((*lang).to_owned());
+    }
+    if code.len() == 3
+        && code.bytes().all(|b| b.is_ascii_lowercase())
+        && !SPECIAL_CODES.contains(&code.as_str())
+    {
+        return Some(code);
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn maps_language_codes() {
+        assert_eq!(tesseract_language("en", &[]).as_deref(), Some("eng"));
+        assert_eq!(tesseract_language(" ZH ", &[]).as_deref(), Some("chi_sim"));
+        assert_eq!(tesseract_language("ger", &[]).as_deref(), Some("deu"));
+        assert_eq!(tesseract_language("fin", &[]).as_deref(), Some("fin"));
+        assert_eq!(tesseract_language("und", &[]), None);
+        assert_eq!(tesseract_language("xx", &[]), None);
+        assert_eq!(tesseract_language("", &[]), None);
+    }
+
+    #[test]
+    fn prefers_user_mapping() {
+        let mapping = [("zh".to_owned(), "chi_tra".to_owned())];
+        assert_eq!(
+            tesseract_language("zh", &mapping).as_deref(),
+            Some("chi_tra")
+        );
+        assert_eq!(tesseract_language("en", &mapping).as_deref(), Some("eng"));
+    }
+}
@@ -1,36 +1,63 @@
 #![doc = include_str!("../README.md")]
 
+mod bitmap;
+mod command;
+mod deskew;
+mod dvb;
+mod engine;
+mod glyphs;
+mod idx;
+mod ifo;
+mod input;
+mod lang;
+mod mkv;
 mod ocr;
 mod opt;
+mod output;
+mod pgs;
 mod preprocessor;
+mod ps;
+mod segment;
+mod tesseract;
+mod ts;
+mod upscale;
 
-use crate::opt::Opt;
-use clap::Parser;
-use log::{warn, LevelFilter};
+use crate::{
+    bitmap::BitmapSubtitle,
+    engine::Engine,
+    input::SubtitleStream,
+    ocr::LowConfidence,
+    opt::Opt,
+    output::{Format, Subtitle},
+};
+use clap::{Parser, ValueEnum};
+use log::{info, warn, LevelFilter};
 use snafu::{ErrorCompat, ResultExt, Snafu};
 use std::{
     fs::File,
     io::{self, Write},
-    path::PathBuf,
+    path::{Path, PathBuf},
 };
-use subparse::{timetypes::TimeSpan, SrtFile, SubtitleFile};
 
 #[derive(Debug, Snafu)]
 enum Error {
-    #[snafu(display("Could not parse VOB subtitles from {}: {}", filename.display(), source))]
+    #[snafu(display("Could not read subtitles from {}: {}", filename.display(), source))]
     ReadSubtitles {
         filename: PathBuf,
-        source: vobsub::Error,
+        source: input::Error,
     },
 
+    #[snafu(display("Could not set up OCR engine: {}", source))]
+    Engine { source: engine::Error },
+
     #[snafu(display("Could not perform OCR on subtitles: {}", source))]
     Ocr { source: ocr::Error },
 
-    #[snafu(display("Could not generate SRT file: {}", message))]
-    GenerateSrt { message: String },
+    #[snafu(display("{}", source))]
+    GenerateOutput { source: output::Error },
 
-    #[snafu(display("Could not write SRT file {}: {}", filename.display(), source))]
-    WriteSrt {
+    #[snafu(display("Could not write subtitle file {}: {}", filename.display(), source))]
+    WriteOutput {
         filename: PathBuf,
         source: io::Error,
     },
@@ -45,25 +72,106 @@ enum Error {
 type Result<T, E = Error> = std::result::Result<T, E>;
 
 fn run(opt: Opt) -> Result<i32> {
-    let vobsubs = preprocessor::preprocess_subtitles(&opt).context(ReadSubtitlesSnafu {
+    let streams = input::read_streams(&opt).context(ReadSubtitlesSnafu {
         filename: opt.input.clone(),
     })?;
 
+    if opt.list_streams {
+        for stream in &streams {
+            let pid = match stream.pid {
+                Some(pid) => format!("PID {}, ", pid),
+                None => String::new(),
+            };
+            println!(
+                "{}: {} ({}{} subtitles)",
+                stream.index,
+                stream.language.as_deref().unwrap_or("unknown"),
+                pid,
+                stream.len()
+            );
+        }
+        return Ok(0);
+    }
+
+    if opt.all_streams {
+        // Convert every stream, carrying on past failures.
+        let outputs = stream_output_paths(&streams, &opt);
+        let mut return_code = 0;
+        for (stream, output) in streams.iter().zip(outputs) {
+            let dump_prefix = format!("{}-", stream.index);
+            match convert_stream(stream, Some(&output), &dump_prefix, &opt) {
+                Ok(code) => return_code = return_code.max(code),
+                Err(e) => {
+                    warn!("Could not convert subtitle stream {}: {}", stream.index, e);
+                    return_code = 1;
+                }
+            }
+        }
+        return Ok(return_code);
+    }
+
+    let stream = input::select_stream(streams, &opt).context(ReadSubtitlesSnafu {
+        filename: opt.input.clone(),
+    })?;
+    convert_stream(&stream, opt.output.as_deref(), "", &opt)
+}
+
+/// OCR a single subtitle stream and write it to the given file, or stdout.
+fn convert_stream(
+    stream: &SubtitleStream,
+    output: Option<&Path>,
+    dump_prefix: &str,
+    opt: &Opt,
+) -> Result<i32> {
+    // Only Tesseract needs to know the language.
+    let lang = match opt.engine {
+        Engine::Tesseract => opt.lang.clone().or_else(|| {
+            stream
+                .language
+                .as_deref()
+                .and_then(|code| lang::tesseract_language(code, &opt.lang_map))
+        }),
+        _ => None,
+    };
+    let engine = engine::new_engine(opt, lang.as_deref()).context(EngineSnafu {})?;
+    match &lang {
+        Some(lang) => info!(
+            "Converting subtitle stream {} with language {}",
+            stream.index, lang
+        ),
+        None => info!(
+            "Converting subtitle stream {} with the {} engine",
+            stream.index,
+            opt.engine
+                .to_possible_value()
+                .map_or_else(String::new, |value| value.get_name().to_owned())
+        ),
+    }
+
+    let mut subtitles = stream.decode().context(ReadSubtitlesSnafu {
+        filename: opt.input.clone(),
+    })?;
+    keep_forced_only(&mut subtitles, opt);
+    let (vobsubs, style) = preprocessor::preprocess_subtitles(&subtitles, opt);
+
     // Dump images if requested.
     if opt.dump {
         for (i, sub) in vobsubs.iter().enumerate() {
-            for (j, image) in sub.images.iter().enumerate() {
-                let filename = format!("{:06}-{:02}.png", i, j);
-                image.save(&filename).context(DumpImageSnafu { filename })?;
+            for (j, line) in sub.lines.iter().enumerate() {
+                let filename = format!("{}{:06}-{:02}.png", dump_prefix, i, j);
+                line.image
+                    .save(&filename)
+                    .context(DumpImageSnafu { filename })?;
             }
         }
     }
 
-    let subtitles = ocr::process(vobsubs, &opt).context(OcrSnafu {})?;
+    let subtitles =
+        ocr::process(vobsubs, &subtitles, &style, engine.as_ref(), opt).context(OcrSnafu {})?;
 
     // Log errors and remove bad results.
     let mut return_code = 0;
-    let subtitles: Vec<(TimeSpan, String)> = subtitles
+    let mut subtitles: Vec<Subtitle> = subtitles
         .into_iter()
         .filter_map(|maybe_subtitle| match maybe_subtitle {
             Ok(subtitle) => Some(subtitle),
@@ -75,41 +183,183 @@ fn run(opt: Opt) -> Result<i32> {
         })
         .collect();
 
-    // Create subtitle file.
-    let subtitles = SubtitleFile::SubRipFile(SrtFile::create(subtitles).map_err(|e| {
-        GenerateSrtSnafu {
-            message: e.to_string(),
+    if let Some(min_confidence) = opt.min_confidence {
+        if flag_low_confidence(&mut subtitles, min_confidence, opt) {
+            return_code = 1;
+        }
+    }
+
+    // Create subtitle files.
+    let generate = |subtitles: &[Subtitle]| {
+        output::generate(
+            output_format(opt),
+            subtitles,
+            stream.frame_size,
+            stream.language.as_deref(),
+        )
+        .context(GenerateOutputSnafu {})
+    };
+    write_output(&generate(&subtitles)?, output)?;
+
+    if let Some((forced, forced_output)) = forced_track(subtitles, output, opt) {
+        info!(
+            "Writing {} forced subtitles to {}",
+            forced.len(),
+            forced_output.display()
+        );
+        write_output(&generate(&forced)?, Some(&forced_output))?;
+    }
+
+    Ok(return_code)
+}
+
+/// Warn about subtitles with a line recognized with less than the minimum
+/// confidence, or with no text recognized at all, and mark their uncertain
+/// lines if asked to. Returns whether this should fail the conversion.
+fn flag_low_confidence(subtitles: &mut [Subtitle], min_confidence: f32, opt: &Opt) -> bool {
+    let mut flagged = 0;
+    for subtitle in subtitles {
+        let mut low = subtitle.lines.is_empty();
+        if low {
+            warn!(
+                "No text recognized for subtitle at {:.3}s",
+                subtitle.time_span.start.msecs() as f64 / 1000.0
+            );
         }
-        .build()
-    })?);
-    let subtitle_data = subtitles.to_data().map_err(|e| {
-        GenerateSrtSnafu {
-            message: e.to_string(),
+        for line in &mut subtitle.lines {
+            if line.confidence >= min_confidence {
+                continue;
+            }
+            low = true;
+            warn!(
+                "Low OCR confidence {:.0} (words {:?}) for subtitle at {:.3}s: {}",
+                line.confidence,
+                line.word_confidences,
+                subtitle.time_span.start.msecs() as f64 / 1000.0,
+                line.text
+            );
+            if opt.low_confidence == LowConfidence::Mark {
+                line.text.insert_str(0, &opt.low_confidence_mark);
+            }
         }
-        .build()
-    })?;
+        if low {
+            flagged += 1;
+        }
+    }
+    if flagged > 0 {
+        warn!(
+            "{} subtitles have lines below the minimum OCR confidence of {}",
+            flagged, min_confidence
+        );
+    }
+    flagged > 0 && opt.low_confidence == LowConfidence::Fail
+}
 
-    match opt.output {
+/// Write subtitle data to the given file, or stdout.
+fn write_output(subtitle_data: &[u8], output: Option<&Path>) -> Result<()> {
+    match output {
         Some(output) => {
             // Write to file.
-            let mut subtitle_file = File::create(&output).context(WriteSrtSnafu {
-                filename: output.clone(),
+            let mut subtitle_file = File::create(output).context(WriteOutputSnafu {
+                filename: output.to_owned(),
             })?;
             subtitle_file
-                .write_all(&subtitle_data)
-                .context(WriteSrtSnafu { filename: output })?;
+                .write_all(subtitle_data)
+                .context(WriteOutputSnafu { filename: output })?;
         }
         None => {
             // Write to stdout.
             io::stdout()
-                .write_all(&subtitle_data)
-                .context(WriteSrtSnafu {
+                .write_all(subtitle_data)
+                .context(WriteOutputSnafu {
                     filename: "<stdout>",
                 })?;
         }
     }
+    Ok(())
+}
+
+/// Drop all but the forced subtitles, if `--forced-only` is given.
+fn keep_forced_only(subtitles: &mut Vec<BitmapSubtitle>, opt: &Opt) {
+    if opt.forced_only {
+        subtitles.retain(|subtitle| subtitle.force);
+        info!("Keeping {} forced subtitles", subtitles.len());
+    }
+}
 
-    Ok(return_code)
+/// Pick the forced subtitles and the file to write them to, if
+/// `--forced-track` is given.
+fn forced_track(
+    subtitles: Vec<Subtitle>,
+    output: Option<&Path>,
+    opt: &Opt,
+) -> Option<(Vec<Subtitle>, PathBuf)> {
+    if !opt.forced_track {
+        return None;
+    }
+    let forced = subtitles
+        .into_iter()
+        .filter(|subtitle| subtitle.force)
+        .collect();
+    Some((forced, forced_output_path(output, opt)))
+}
+
+/// Name the file of forced subtitles after the output file (or the input
+/// file), with `forced` before the extension.
+fn forced_output_path(output: Option<&Path>, opt: &Opt) -> PathBuf {
+    let base = output
+        .map(Path::to_owned)
+        .unwrap_or_else(|| opt.input.with_extension(output_format(opt).extension()));
+    let extension = base
+        .extension()
+        .map(|extension| extension.to_string_lossy().into_owned())
+        .unwrap_or_else(|| output_format(opt).extension().to_owned());
+    base.with_extension(format!("forced.{}", extension))
+}
+
+/// The format requested with `--format`, or else the one matching the output
+/// file's extension.
+fn output_format(opt: &Opt) -> Format {
+    opt.format
+        .or_else(|| opt.output.as_deref().and_then(Format::from_path))
+        .unwrap_or(Format::Srt)
+}
+
+/// Name the output file of each stream after the output file (or the input
+/// file), with the stream's language code before the extension. The stream
+/// index is added as well if the language code is missing or ambiguous.
+fn stream_output_paths(streams: &[SubtitleStream], opt: &Opt) -> Vec<PathBuf> {
+    let base = opt
+        .output
+        .clone()
+        .unwrap_or_else(|| opt.input.with_extension(output_format(opt).extension()));
+    let stem = base
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let extension = base
+        .extension()
+        .map(|extension| extension.to_string_lossy().into_owned())
+        .unwrap_or_else(|| output_format(opt).extension().to_owned());
+    streams
+        .iter()
+        .map(|stream| {
+            let tag = match &stream.language {
+                Some(language)
+                    if streams
+                        .iter()
+                        .filter(|other| other.language.as_ref() == Some(language))
+                        .count()
+                        == 1 =>
+                {
+                    language.clone()
+                }
+                Some(language) => format!("{}.{}", language, stream.index),
+                None => stream.index.to_string(),
+            };
+            base.with_file_name(format!("{}.{}.{}", stem, tag, extension))
+        })
+        .collect()
 }
 
 fn main() {
@@ -131,3 +381,196 @@ fn main() {
     };
     std::process::exit(code);
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{bitmap::Rect, output::TextLine};
+    use subparse::timetypes::{TimePoint, TimeSpan};
+
+    fn bitmap_subtitle(start_time: f64, force: bool) -> BitmapSubtitle {
+        BitmapSubtitle {
+            start_time,
+            end_time: start_time + 1.0,
+            force,
+            area: Rect {
+                x: 0,
+                y: 0,
+                width: 1,
+                height: 1,
+            },
+            palette: vec![[0; 4]],
+            dvd_roles: false,
+            pixels: vec![0],
+        }
+    }
+
+    fn subtitle(seconds: i64, force: bool) -> Subtitle {
+        Subtitle {
+            time_span: TimeSpan::new(
+                TimePoint::from_secs(seconds),
+                TimePoint::from_secs(seconds + 1),
+            ),
+            force,
+            area: Rect {
+                x: 0,
+                y: 0,
+                width: 1,
+                height: 1,
+            },
+            lines: vec![TextLine {
+                text: seconds.to_string(),
+                italic: false,
+                color: None,
+                confidence: 100.0,
+                word_confidences: Vec::new(),
+            }],
+        }
+    }
+
+    #[test]
+    fn keeps_forced_subtitles_only_when_asked() {
+        let mut subtitles = vec![bitmap_subtitle(1.0, false), bitmap_subtitle(2.0, true)];
+        keep_forced_only(&mut subtitles, &Opt::parse_from(["vobsubocr", "movie.idx"]));
+        assert_eq!(subtitles.len(), 2);
+
+        let opt = Opt::parse_from(["vobsubocr", "--forced-only", "movie.idx"]);
+        keep_forced_only(&mut subtitles, &opt);
+        let times: Vec<f64> = subtitles
+            .iter()
+            .map(|subtitle| subtitle.start_time)
+            .collect();
+        assert_eq!(times, [2.0]);
+    }
+
+    #[test]
+    fn writes_forced_track_next_to_output() {
+        let subtitles = || vec![subtitle(1, true), subtitle(2, false), subtitle(3, true)];
+        let opt = Opt::parse_from(["vobsubocr", "movie.idx"]);
+        assert!(forced_track(subtitles(), None, &opt).is_none());
+
+        let opt = Opt::parse_from(["vobsubocr", "--forced-track", "dir/movie.idx"]);
+        let (forced, path) = forced_track(subtitles(), None, &opt).unwrap();
+        let texts: Vec<&str> = forced
+            .iter()
+            .map(|subtitle| subtitle.lines[0].text.as_str())
+            .collect();
+        assert_eq!(texts, ["1", "3"]);
+        assert_eq!(path, PathBuf::from("dir/movie.forced.srt"));
+
+        let (_, path) = forced_track(subtitles(), Some(Path::new("out/subs.vtt")), &opt).unwrap();
+        assert_eq!(path, PathBuf::from("out/subs.forced.vtt"));
+
+        let opt = Opt::parse_from([
+            "vobsubocr",
+            "--forced-track",
+            "--format",
+            "ass",
+            "movie.idx",
+        ]);
+        let (_, path) = forced_track(subtitles(), None, &opt).unwrap();
+        assert_eq!(path, PathBuf::from("movie.forced.ass"));
+    }
+
+    #[test]
+    fn names_stream_outputs_by_language() {
+        let streams = [
+            SubtitleStream::empty(0, Some("en")),
+            SubtitleStream::empty(1, Some("de")),
+            SubtitleStream::empty(2, Some("de")),
+            SubtitleStream::empty(3, None),
+        ];
+        let opt = Opt::parse_from(["vobsubocr", "--all-streams", "dir/movie.idx"]);
+        assert_eq!(
+            stream_output_paths(&streams, &opt),
+            [
+                PathBuf::from("dir/movie.en.srt"),
+                PathBuf::from("dir/movie.de.1.srt"),
+                PathBuf::from("dir/movie.de.2.srt"),
+                PathBuf::from("dir/movie.3.srt"),
+            ]
+        );
+
+        let opt = Opt::parse_from([
+            "vobsubocr",
+            "--all-streams",
+            "-o",
+            "out/subs.txt",
+            "movie.idx",
+        ]);
+        assert_eq!(
+            stream_output_paths(&streams[..1], &opt),
+            [PathBuf::from("out/subs.en.txt")]
+        );
+    }
+
+    /// Subtitles with one line each, of the given confidences. A confidence
+    /// of `None` stands for a subtitle in which no text was recognized.
+    fn recognized(confidences: &[Option<f32>]) -> Vec<Subtitle> {
+        confidences
+            .iter()
+            .enumerate()
+            .map(|(i, &confidence)| {
+                let mut subtitle = subtitle(i as i64, false);
+                match confidence {
+                    Some(confidence) => subtitle.lines[0].confidence = confidence,
+                    None => subtitle.lines.clear(),
+                }
+                subtitle
+            })
+            .collect()
+    }
+
+    fn texts(subtitles: &[Subtitle]) -> Vec<&str> {
+        subtitles
+            .iter()
+            .flat_map(|subtitle| &subtitle.lines)
+            .map(|line| line.text.as_str())
+            .collect()
+    }
+
+    #[test]
+    fn logs_low_confidence_without_changes() {
+        let mut subtitles = recognized(&[Some(90.0), Some(40.0)]);
+        let opt = Opt::parse_from(["vobsubocr", "--min-confidence", "60", "movie.idx"]);
+        assert!(!flag_low_confidence(&mut subtitles, 60.0, &opt));
+        assert_eq!(texts(&subtitles), ["0", "1"]);
+    }
+
+    #[test]
+    fn marks_low_confidence_lines() {
+        let mut subtitles = recognized(&[Some(90.0), Some(40.0), Some(60.0)]);
+        let opt = Opt::parse_from(["vobsubocr", "--low-confidence", "mark", "movie.idx"]);
+        assert!(!flag_low_confidence(&mut subtitles, 60.0, &opt));
+        assert_eq!(texts(&subtitles), ["0", "[?] 1", "2"]);
+    }
+
+    #[test]
+    fn fails_on_low_confidence() {
+        let opt = Opt::parse_from(["vobsubocr", "--low-confidence", "fail", "movie.idx"]);
+        let mut subtitles = recognized(&[Some(90.0), Some(60.0)]);
+        assert!(!flag_low_confidence(&mut subtitles, 60.0, &opt));
+        let mut subtitles = recognized(&[Some(90.0), Some(40.0)]);
+        assert!(flag_low_confidence(&mut subtitles, 60.0, &opt));
+    }
+
+    #[test]
+    fn counts_subtitles_without_text_as_low_confidence() {
+        let opt = Opt::parse_from(["vobsubocr", "--low-confidence", "fail", "movie.idx"]);
+        let mut subtitles = recognized(&[Some(90.0), None]);
+        assert!(flag_low_confidence(&mut subtitles, 60.0, &opt));
+    }
+
+    #[test]
+    fn marks_inside_italics_and_colors() {
+        let mut subtitles = recognized(&[Some(40.0)]);
+        subtitles[0].lines[0].italic = true;
+        subtitles[0].lines[0].color = Some([255, 255, 0]);
+        let opt = Opt::parse_from(["vobsubocr", "--low-confidence", "mark", "movie.idx"]);
+        flag_low_confidence(&mut subtitles, 60.0, &opt);
+        let srt = output::generate(output::Format::Srt, &subtitles, None, None).unwrap();
+        assert!(String::from_utf8(srt)
+            .unwrap()
+            .contains(r##"<font color="#ffff00"><i>[?] 0</i></font>"##));
+    }
+}
@@ -0,0 +1,572 @@
+//! A minimal Matroska demuxer which only understands enough of the format to
+//! pull subtitle tracks out of `*.mkv` files.
+//!
+//! The file is scanned front to back. Master elements we need to descend into
+//! are treated as transparent, so we can handle clusters of unknown size as
+//! produced by live muxers, and large video blocks are skipped without being
+//! read into memory.
+
+use flate2::read::ZlibDecoder;
+use log::warn;
+use snafu::{ensure, OptionExt, ResultExt, Snafu};
+use std::{
+    cmp::Reverse,
+    convert::TryFrom,
+    fs::File,
+    io::{self, BufReader, Read, Seek},
+    path::Path,
+};
+
+const EBML: u32 = 0x1a45dfa3;
+const SEGMENT: u32 = 0x18538067;
+const INFO: u32 = 0x1549a966;
+const TIMESTAMP_SCALE: u32 = 0x2ad7b1;
+const TRACKS: u32 = 0x1654ae6b;
+const TRACK_ENTRY: u32 = 0xae;
+const TRACK_NUMBER: u32 = 0xd7;
+const CODEC_ID: u32 = 0x86;
+const CODEC_PRIVATE: u32 = 0x63a2;
+const LANGUAGE: u32 = 0x22b59c;
+const NAME: u32 = 0x536e;
+const FLAG_DEFAULT: u32 = 0x88;
+const CONTENT_ENCODINGS: u32 = 0x6d80;
+const CONTENT_ENCODING: u32 = 0x6240;
+const CONTENT_ENCODING_ORDER: u32 = 0x5031;
+const CONTENT_ENCODING_SCOPE: u32 = 0x5032;
+const CONTENT_COMPRESSION: u32 = 0x5034;
+const CONTENT_COMP_ALGO: u32 = 0x4254;
+const CONTENT_COMP_SETTINGS: u32 = 0x4255;
+const CONTENT_ENCRYPTION: u32 = 0x5035;
+const CLUSTER: u32 = 0x1f43b675;
+const TIMESTAMP: u32 = 0xe7;
+const BLOCK_GROUP: u32 = 0xa0;
+const BLOCK: u32 = 0xa1;
+const SIMPLE_BLOCK: u32 = 0xa3;
+
+/// Matroska's default timestamp scale, in nanoseconds per tick.
+const DEFAULT_TIMESTAMP_SCALE: u64 = 1_000_000;
+
+#[derive(Debug, Snafu)]
+pub enum Error {
+    #[snafu(display("Could not read Matroska data: {}", source))]
+    Io { source: io::Error },
+
+    #[snafu(display("Not a Matroska file"))]
+    NotMatroska,
+
+    #[snafu(display("Invalid EBML variable-length integer"))]
+    InvalidVint,
+
+    #[snafu(display("Element 0x{:x} is truncated", id))]
+    Truncated { id: u32 },
+
+    #[snafu(display("Element 0x{:x} has an unknown size", id))]
+    UnknownSize { id: u32 },
+
+    #[snafu(display("Track {} is encrypted", track))]
+    Encrypted { track: u64 },
+
+    #[snafu(display("Track {} uses unsupported compression algorithm {}", track, algo))]
+    UnsupportedCompression { track: u64, algo: u64 },
+
+    #[snafu(display("Could not decompress block of track {}: {}", track, source))]
+    Decompress { track: u64, source: io::Error },
+}
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+/// A track entry.
+#[derive(Debug)]
+pub struct Track {
+    pub number: u64,
+    pub codec_id: String,
+    /// Private codec data, already decompressed if need be.
+    pub codec_private: Vec<u8>,
+    pub language: String,
+    pub name: Option<String>,
+    /// Whether the track is eligible for automatic selection.
+    pub default: bool,
+    encodings: Vec<ContentEncoding>,
+}
+
+/// A single frame of a track.
+#[derive(Debug)]
+pub struct Block {
+    pub track: u64,
+    /// Presentation timestamp in seconds.
+    pub timestamp: f64,
+    /// Frame data, already decompressed if need be.
+    pub data: Vec<u8>,
+}
+
+/// The tracks and frames read from a Matroska file.
+#[derive(Debug)]
+pub struct Matroska {
+    pub tracks: Vec<Track>,
+    pub blocks: Vec<Block>,
+}
+
+#[derive(Debug)]
+struct ContentEncoding {
+    order: u64,
+    scope: u64,
+    compression: Option<Compression>,
+}
+
+#[derive(Debug)]
+enum Compression {
+    Zlib,
+    HeaderStripping(Vec<u8>),
+    Unsupported(u64),
+}
+
+/// Check for the EBML magic number at the start of the file.
+pub fn is_matroska<P: AsRef<Path>>(path: P) -> io::Result<bool> {
+    let mut magic = [0; 4];
+    match File::open(path)?.read_exact(&mut magic) {
+        Ok(()) => Ok(u32::from_be_bytes(magic) == EBML),
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+/// Read a Matroska file, keeping only the tracks accepted by `want_track` and
+/// their frames.
+pub fn read<P: AsRef<Path>>(path: P, want_track: impl FnMut(&Track) -> bool) -> Result<Matroska> {
+    let file = File::open(path).context(IoSnafu {})?;
+    let length = file.metadata().context(IoSnafu {})?.len();
+    read_from(BufReader::new(file), length, want_track)
+}
+
+/// Read Matroska data of the given length from a reader.
+fn read_from<R: Read + Seek>(
+    mut reader: BufReader<R>,
+    length: u64,
+    mut want_track: impl FnMut(&Track) -> bool,
+) -> Result<Matroska> {
+    match read_element_header(&mut reader)? {
+        Some((EBML, Some(size))) => skip(&mut reader, EBML, size, length)?,
+        _ => return NotMatroskaSnafu {}.fail(),
+    }
+
+    let mut timestamp_scale = DEFAULT_TIMESTAMP_SCALE;
+    let mut cluster_timestamp = 0;
+    let mut tracks: Vec<Track> = Vec::new();
+    let mut blocks = Vec::new();
+    while let Some((id, size)) = read_element_header(&mut reader)? {
+        match id {
+            // Master elements whose children we read as if they were
+            // siblings.
+            SEGMENT | BLOCK_GROUP => {}
+            CLUSTER => cluster_timestamp = 0,
+            INFO => {
+                let data = read_body(&mut reader, id, size)?;
+                for child in children(&data) {
+                    let (child_id, child_data) = child?;
+                    if child_id == TIMESTAMP_SCALE {
+                        timestamp_scale = parse_uint(child_data);
+                    }
+                }
+            }
+            TRACKS => {
+                let data = read_body(&mut reader, id, size)?;
+                for child in children(&data) {
+                    let (child_id, child_data) = child?;
+                    if child_id == TRACK_ENTRY {
+                        let track = parse_track_entry(child_data)?;
+                        if want_track(&track) {
+                            tracks.push(track);
+                        }
+                    }
+                }
+            }
+            TIMESTAMP => cluster_timestamp = parse_uint(&read_body(&mut reader, id, size)?),
+            SIMPLE_BLOCK | BLOCK => {
+                let size = size.context(UnknownSizeSnafu { id })?;
+                let (track_number, track_number_len) =
+                    read_vint(&mut reader)?.context(TruncatedSnafu { id })?;
+                let rest = size
+                    .checked_sub(track_number_len as u64)
+                    .context(TruncatedSnafu { id })?;
+                match tracks.iter().find(|track| track.number == track_number) {
+                    Some(track) => {
+                        let data = read_body(&mut reader, id, Some(rest))?;
+                        ensure!(data.len() >= 3, TruncatedSnafu { id });
+                        let relative = i16::from_be_bytes([data[0], data[1]]);
+                        let lacing = data[2] & 0x06;
+                        if lacing != 0 {
+                            warn!("Skipping laced block in track {}", track_number);
+                            continue;
+                        }
+                        let ticks = cluster_timestamp as i64 + relative as i64;
+                        blocks.push(Block {
+                            track: track_number,
+                            timestamp: ticks as f64 * timestamp_scale as f64 / 1e9,
+                            data: decode(track, 1, data[3..].to_vec())?,
+                        });
+                    }
+                    None => skip(&mut reader, id, rest, length)?,
+                }
+            }
+            _ => skip(
+                &mut reader,
+                id,
+                size.context(UnknownSizeSnafu { id })?,
+                length,
+            )?,
+        }
+    }
+
+    Ok(Matroska { tracks, blocks })
+}
+
+fn parse_track_entry(data: &[u8]) -> Result<Track> {
+    let mut track = Track {
+        number: 0,
+        codec_id: String::new(),
+        codec_private: Vec::new(),
+        // This is the default according to the specification.
+        language: "eng".to_owned(),
+        name: None,
+        default: true,
+        encodings: Vec::new(),
+    };
+    for child in children(data) {
+        let (id, data) = child?;
+        match id {
+            TRACK_NUMBER => track.number = parse_uint(data),
+            CODEC_ID => track.codec_id = parse_string(data),
+            CODEC_PRIVATE => track.codec_private = data.to_vec(),
+            LANGUAGE => track.language = parse_string(data),
+            NAME => track.name = Some(parse_string(data)),
+            FLAG_DEFAULT => track.default = parse_uint(data) != 0,
+            CONTENT_ENCODINGS => {
+                for encoding in children(data) {
+                    let (encoding_id, encoding_data) = encoding?;
+                    if encoding_id == CONTENT_ENCODING {
+                        track
+                            .encodings
+                            .push(parse_content_encoding(track.number, encoding_data)?);
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+    // Encodings must be undone from the highest order to the lowest.
+    track
+        .encodings
+        .sort_by_key(|encoding| Reverse(encoding.order));
+    let codec_private = std::mem::take(&mut track.codec_private);
+    track.codec_private = decode(&track, 2, codec_private)?;
+    Ok(track)
+}
+
+fn parse_content_encoding(track: u64, data: &[u8]) -> Result<ContentEncoding> {
+    let mut encoding = ContentEncoding {
+        order: 0,
+        scope: 1,
+        compression: None,
+    };
+    for child in children(data) {
+        let (id, data) = child?;
+        match id {
+            CONTENT_ENCODING_ORDER => encoding.order = parse_uint(data),
+            CONTENT_ENCODING_SCOPE => encoding.scope = parse_uint(data),
+            CONTENT_ENCRYPTION => return EncryptedSnafu { track }.fail(),
+            CONTENT_COMPRESSION => {
+                let mut algo = 0;
+                let mut settings = Vec::new();
+                for child in children(data) {
+                    let (id, data) = child?;
+                    match id {
+                        CONTENT_COMP_ALGO => algo = parse_uint(data),
+                        CONTENT_COMP_SETTINGS => settings = data.to_vec(),
+                        _ => {}
+                    }
+                }
+                encoding.compression = Some(match algo {
+                    0 => Compression::Zlib,
+                    3 => Compression::HeaderStripping(settings),
+                    _ => Compression::Unsupported(algo),
+                });
+            }
+            _ => {}
+        }
+    }
+    Ok(encoding)
+}
+
+/// Undo the track's content encodings which apply to the given scope (1 for
+/// frames, 2 for private codec data).
+fn decode(track: &Track, scope: u64, mut data: Vec<u8>) -> Result<Vec<u8>> {
+    for encoding in &track.encodings {
+        if encoding.scope & scope == 0 {
+            continue;
+        }
+        data = match &encoding.compression {
+            None => data,
+            Some(Compression::Zlib) => {
+                let mut decompressed = Vec::new();
+                ZlibDecoder::new(&data[..])
+                    .read_to_end(&mut decompressed)
+                    .context(DecompressSnafu {
+                        track: track.number,
+                    })?;
+                decompressed
+            }
+            Some(Compression::HeaderStripping(header)) => {
+                let mut restored = header.clone();
+                restored.extend_from_slice(&data);
+                restored
+            }
+            Some(Compression::Unsupported(algo)) => {
+                return UnsupportedCompressionSnafu {
+                    track: track.number,
+                    algo: *algo,
+                }
+                .fail()
+            }
+        };
+    }
+    Ok(data)
+}
+
+/// Read an element ID and size, or `None` at the end of the file. A size of
+/// `None` means the size is unknown.
+fn read_element_header<R: Read>(reader: &mut R) -> Result<Option<(u32, Option<u64>)>> {
+    let (id, id_len) = match read_vint(reader)? {
+        Some(vint) => vint,
+        None => return Ok(None),
+    };
+    // IDs keep their length marker.
+    let id = (id | 1 << (7 * id_len)) as u32;
+    let (size, size_len) = read_vint(reader)?.context(TruncatedSnafu { id })?;
+    Ok(Some((id, unknown_size_to_none(size, size_len))))
+}
+
+/// Read a variable-length integer with its length marker removed, along with
+/// its length in bytes, or `None` at the end of the file.
+fn read_vint<R: Read>(reader: &mut R) -> Result<Option<(u64, usize)>> {
+    let mut buf = [0; 8];
+    match reader.read_exact(&mut buf[..1]) {
+        Ok(()) => {}
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e).context(IoSnafu {}),
+    }
+    let len = vint_len(buf[0])?;
+    reader.read_exact(&mut buf[1..len]).context(IoSnafu {})?;
+    Ok(Some((vint_value(&buf[..len]), len)))
+}
+
+/// Parse a variable-length integer from the start of a slice, returning the
+/// value with its length marker removed, and its length in bytes.
+fn parse_vint(data: &[u8]) -> Result<(u64, usize)> {
+    let len = vint_len(*data.first().context(InvalidVintSnafu {})?)?;
+    ensure!(data.len() >= len, InvalidVintSnafu {});
+    Ok((vint_value(&data[..len]), len))
+}
+
+fn vint_len(first: u8) -> Result<usize> {
+    ensure!(first != 0, InvalidVintSnafu {});
+    Ok(first.leading_zeros() as usize + 1)
+}
+
+fn vint_value(bytes: &[u8]) -> u64 {
+    let marker = 0x80 >> (bytes.len() - 1);
+    bytes[1..]
+        .iter()
+        .fold((bytes[0] & !marker) as u64, |value, &byte| {
+            value << 8 | byte as u64
+        })
+}
+
+/// A size with all value bits set means "unknown".
+fn unknown_size_to_none(size: u64, len: usize) -> Option<u64> {
+    if size == (1 << (7 * len)) - 1 {
+        None
+    } else {
+        Some(size)
+    }
+}
+
+/// Iterate over the child elements contained in a master element's body.
+fn children(mut data: &[u8]) -> impl Iterator<Item = Result<(u32, &[u8])>> {
+    std::iter::from_fn(move || {
+        if data.is_empty() {
+            return None;
+        }
+        match parse_child(data) {
+            Ok((id, body, rest)) => {
+                data = rest;
+                Some(Ok((id, body)))
+            }
+            Err(e) => {
+                // Don't keep producing the same error.
+                data = &[];
+                Some(Err(e))
+            }
+        }
+    })
+}
+
+/// Parse the child element at the start of a slice, returning its ID, its body
+/// and the remaining data.
+fn parse_child(data: &[u8]) -> Result<(u32, &[u8], &[u8])> {
+    let (id, id_len) = parse_vint(data)?;
+    let id = (id | 1 << (7 * id_len)) as u32;
+    let (size, size_len) = parse_vint(&data[id_len..])?;
+    let start = id_len + size_len;
+    let size = unknown_size_to_none(size, size_len).context(UnknownSizeSnafu { id })?;
+    let end = usize::try_from(size)
+        .ok()
+        .and_then(|size| start.checked_add(size))
+        .filter(|&end| end <= data.len())
+        .context(TruncatedSnafu { id })?;
+    Ok((id, &data[start..end], &data[end..]))
+}
+
+fn read_body<R: Read>(reader: &mut R, id: u32, size: Option<u64>) -> Result<Vec<u8>> {
+    let size = size.context(UnknownSizeSnafu { id })?;
+    let mut data = Vec::new();
+    reader
+        .take(size)
+        .read_to_end(&mut data)
+        .context(IoSnafu {})?;
+    ensure!(data.len() as u64 == size, TruncatedSnafu { id });
+    Ok(data)
+}
+
+/// Skip the body of an element in a stream of the given length. Seeking past
+/// the end would succeed, so an element running past it is caught here.
+fn skip<R: Read + Seek>(reader: &mut BufReader<R>, id: u32, size: u64, length: u64) -> Result<()> {
+    let position = reader.stream_position().context(IoSnafu {})?;
+    let end = position.checked_add(size).context(TruncatedSnafu { id })?;
+    ensure!(end <= length, TruncatedSnafu { id });
+    // Seeking relatively keeps the buffer around for small skips.
+    reader.seek_relative(size as i64).context(IoSnafu {})
+}
+
+fn parse_uint(data: &[u8]) -> u64 {
+    data.iter().fold(0, |value, &byte| value << 8 | byte as u64)
+}
+
+fn parse_string(data: &[u8]) -> String {
+    // Strings may be padded with zeros.
+    let end = data.iter().position(|&b| b == 0).unwrap_or(data.len());
+    String::from_utf8_lossy(&data[..end]).into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    const VOID: u32 = 0xec;
+
+    fn element(id: u32, body: &[u8]) -> Vec<u8> {
+        let mut data: Vec<u8> = id
+            .to_be_bytes()
+            .iter()
+            .copied()
+            .skip_while(|&byte| byte == 0)
+            .collect();
+        // An 8-byte size fits any body.
+        data.push(0x01);
+        data.extend_from_slice(&(body.len() as u64).to_be_bytes()[1..]);
+        data.extend_from_slice(body);
+        data
+    }
+
+    fn read_bytes(data: Vec<u8>) -> Result<Matroska> {
+        let length = data.len() as u64;
+        read_from(BufReader::new(Cursor::new(data)), length, |track| {
+            track.codec_id == "S_VOBSUB"
+        })
+    }
+
+    fn file(cluster: &[u8]) -> Vec<u8> {
+        let track = |number: u8, codec_id: &[u8]| {
+            element(
+                TRACK_ENTRY,
+                &[
+                    element(TRACK_NUMBER, &[number]),
+                    element(CODEC_ID, codec_id),
+                    element(LANGUAGE, b"ger"),
+                ]
+                .concat(),
+            )
+        };
+        let mut data = element(EBML, &[]);
+        // A segment of unknown size, as written by live muxers.
+        data.extend_from_slice(&[0x18, 0x53, 0x80, 0x67, 0xff]);
+        data.extend(element(
+            TRACKS,
+            &[track(1, b"V_MPEG2"), track(2, b"S_VOBSUB")].concat(),
+        ));
+        data.extend(element(
+            CLUSTER,
+            &[element(TIMESTAMP, &[0x03, 0xe8]), cluster.to_vec()].concat(),
+        ));
+        data
+    }
+
+    #[test]
+    fn reads_blocks_of_wanted_tracks() {
+        let cluster = [
+            element(SIMPLE_BLOCK, &[0x81, 0x00, 0x00, 0x80, 9, 9, 9]),
+            element(SIMPLE_BLOCK, &[0x82, 0x01, 0xf4, 0x80, 1, 2, 3]),
+        ]
+        .concat();
+        let matroska = read_bytes(file(&cluster)).unwrap();
+        assert_eq!(matroska.tracks.len(), 1);
+        assert_eq!(matroska.tracks[0].number, 2);
+        assert_eq!(matroska.tracks[0].language, "ger");
+        assert_eq!(matroska.blocks.len(), 1);
+        assert_eq!(matroska.blocks[0].track, 2);
+        assert_eq!(matroska.blocks[0].timestamp, 1.5);
+        assert_eq!(matroska.blocks[0].data, [1, 2, 3]);
+    }
+
+    #[test]
+    fn truncated_skipped_element_is_an_error() {
+        let mut data = file(&element(VOID, &[0; 100]));
+        data.truncate(data.len() - 10);
+        assert!(matches!(
+            read_bytes(data),
+            Err(Error::Truncated { id: VOID })
+        ));
+    }
+
+    #[test]
+    fn truncated_block_is_an_error() {
+        let mut data = file(&element(SIMPLE_BLOCK, &[0x82, 0x00, 0x00, 0x80, 1, 2, 3]));
+        data.truncate(data.len() - 2);
+        assert!(matches!(
+            read_bytes(data),
+            Err(Error::Truncated { id: SIMPLE_BLOCK })
+        ));
+    }
+
+    #[test]
+    fn parses_vints() {
+        assert_eq!(parse_vint(&[0x81]).unwrap(), (1, 1));
+        assert_eq!(parse_vint(&[0x40, 0x02, 0xff]).unwrap(), (2, 2));
+        assert!(matches!(parse_vint(&[0x40]), Err(Error::InvalidVint)));
+        assert!(matches!(parse_vint(&[0x00]), Err(Error::InvalidVint)));
+        assert_eq!(unknown_size_to_none(0x7f, 1), None);
+        assert_eq!(unknown_size_to_none(0x7e, 1), Some(0x7e));
+    }
+
+    #[test]
+    fn truncated_child_is_an_error() {
+        let mut data = element(TRACK_NUMBER, &[1, 2, 3]);
+        data.pop();
+        let children: Vec<_> = children(&data).collect();
+        assert_eq!(children.len(), 1);
+        assert!(matches!(
+            children[0],
+            Err(Error::Truncated { id: TRACK_NUMBER })
+        ));
+    }
+}
@@ -1,143 +1,396 @@
-use std::{io::Cursor, str::Utf8Error};
-
-use crate::{opt::Opt, preprocessor::PreprocessedVobSubtitle};
-use image::{
-    codecs::pnm::{PnmSubtype, SampleEncoding},
-    DynamicImage, GrayImage,
-};
-use leptess::{
-    leptonica::PixError,
-    tesseract::{TessInitError, TessSetVariableError},
-    LepTess, Variable,
+use crate::{
+    bitmap::BitmapSubtitle,
+    engine::{self, Recognition, Recognizer, RecognizerFactory},
+    opt::Opt,
+    output::{Subtitle, TextLine},
+    preprocessor::{preprocess_again, LineImage, PreprocessedVobSubtitle, StreamStyle},
+    upscale::Upscaler,
 };
+use clap::ValueEnum;
+use log::{debug, info};
 use rayon::prelude::*;
 use scoped_tls_hkt::scoped_thread_local;
 use snafu::{ResultExt, Snafu};
-use subparse::timetypes::TimeSpan;
 
-scoped_thread_local!(static mut TESSERACT: Option<TesseractWrapper>);
+scoped_thread_local!(static mut RECOGNIZER: Option<Box<dyn Recognizer>>);
+
+/// Thresholds to retry subtitles with, and the x-height to scale them up to.
+const RETRY_THRESHOLDS: [f32; 3] = [0.3, 0.5, 0.7];
+const RETRY_X_HEIGHT: u32 = 40;
+
+/// Share of the text of the first try which a retry must keep, so that a
+/// retry which loses faint letters or words does not win by confidence.
+const RETRY_MIN_TEXT: f32 = 0.8;
 
 #[derive(Debug, Snafu)]
 pub enum Error {
-    #[snafu(display("Could not build tesseract thread pool: {}", source))]
+    #[snafu(display("Could not build OCR thread pool: {}", source))]
     BuildThreadPool { source: rayon::ThreadPoolBuildError },
 
-    #[snafu(display("Could not initialize tesseract {}", source))]
-    Initialize { source: TessInitError },
-
-    #[snafu(display("Could not set tesseract variable: {}", source))]
-    SetVariable { source: TessSetVariableError },
-
-    #[snafu(display("Could not write image to memory: {}", source))]
-    WriteImage { source: image::ImageError },
-
-    #[snafu(display("Could not set tesseract image: {}", source))]
-    SetImage { source: PixError },
-
-    #[snafu(display("Could not get tesseract text: {}", source))]
-    GetText { source: Utf8Error },
-
-    #[snafu(display("Tesseract not initialized"))]
-    TesseractNotInitialized,
+    #[snafu(display("{}", source))]
+    Engine { source: engine::Error },
 }
 
 pub type Result<T, E = Error> = std::result::Result<T, E>;
 
+/// What to do about subtitles recognized with low confidence.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum LowConfidence {
+    /// Log a warning.
+    Log,
+    /// Log a warning and start each uncertain line with a mark.
+    Mark,
+    /// Log a warning and exit with an error once done.
+    Fail,
+}
+
 pub fn process(
     vobsubs: Vec<PreprocessedVobSubtitle>,
+    bitmaps: &[BitmapSubtitle],
+    style: &StreamStyle,
+    engine: &dyn RecognizerFactory,
     opt: &Opt,
-) -> Result<Vec<Result<(TimeSpan, String)>>> {
-    std::env::set_var("OMP_THREAD_LIMIT", "1");
-    rayon::ThreadPoolBuilder::new()
+) -> Result<Vec<Result<Subtitle>>> {
+    let subtitles = rayon::ThreadPoolBuilder::new()
         .build_scoped(
             |thread| {
-                let mut tesseract = None;
-                TESSERACT.set(&mut tesseract, || thread.run())
+                let mut recognizer = None;
+                RECOGNIZER.set(&mut recognizer, || thread.run())
             },
             |pool| {
                 pool.install(|| {
                     vobsubs
                         .into_par_iter()
                         .map(|vobsub| {
-                            let text = vobsub
-                                .images
-                                .into_iter()
-                                .map(|image| {
-                                    TESSERACT.with(|maybe_tesseract| {
-                                        let tesseract = match maybe_tesseract {
-                                            Some(tesseract) => tesseract,
-                                            None => {
-                                                let tesseract = TesseractWrapper::new(
-                                                    opt.tessdata_dir.as_deref(),
-                                                    &opt.lang,
-                                                    &opt.config,
-                                                )?;
-                                                maybe_tesseract.insert(tesseract)
-                                            }
-                                        };
-                                        tesseract.set_image(image, opt.dpi)?;
-                                        tesseract.get_text()
-                                    })
-                                })
-                                .collect::<Result<String>>()?;
-                            Ok((vobsub.time_span, text))
+                            let mut lines = recognize_lines(vobsub.lines, engine, true, opt)?;
+                            if let Some(retry_below) = opt.retry_below {
+                                if score(&lines) < retry_below {
+                                    lines = retry(
+                                        &bitmaps[vobsub.source],
+                                        lines,
+                                        style,
+                                        engine,
+                                        retry_below,
+                                        opt,
+                                    )?;
+                                }
+                            }
+                            Ok(Subtitle {
+                                time_span: vobsub.time_span,
+                                force: vobsub.force,
+                                area: vobsub.area,
+                                lines: lines
+                                    .into_iter()
+                                    .filter(|line| !line.text.is_empty())
+                                    .collect(),
+                            })
                         })
-                        .collect::<Vec<Result<(TimeSpan, String)>>>()
+                        .collect::<Vec<Result<Subtitle>>>()
                 })
             },
         )
-        .context(BuildThreadPoolSnafu {})
+        .context(BuildThreadPoolSnafu {})?;
+    engine.finish().context(EngineSnafu {})?;
+    Ok(subtitles)
+}
+
+/// Recognize the text of line images with this thread's recognizer, setting
+/// it up first if needed. The engine only learns from them if `learn` is set.
+fn recognize_lines(
+    lines: Vec<LineImage>,
+    engine: &dyn RecognizerFactory,
+    learn: bool,
+    opt: &Opt,
+) -> Result<Vec<TextLine>> {
+    lines
+        .into_iter()
+        .map(|line| {
+            let LineImage {
+                image,
+                block_start,
+                italic,
+                color,
+            } = line;
+            let Recognition {
+                text,
+                confidence,
+                words,
+            } = RECOGNIZER
+                .with(|maybe_recognizer| {
+                    let recognizer = match maybe_recognizer {
+                        Some(recognizer) => recognizer,
+                        None => maybe_recognizer.insert(engine.recognizer()?),
+                    };
+                    recognizer.recognize(image, opt.dpi, learn)
+                })
+                .context(EngineSnafu {})?;
+            for word in words.iter().flatten() {
+                debug!(
+                    "Recognized {:?} at {}x{}+{}+{} with confidence {:.0}",
+                    word.text, word.width, word.height, word.x, word.y, word.confidence
+                );
+            }
+            let text = text.trim().to_owned();
+            Ok(TextLine {
+                text: if opt.dialogue_dashes && block_start {
+                    add_dialogue_dash(text)
+                } else {
+                    text
+                },
+                italic,
+                color,
+                confidence,
+                word_confidences: words.iter().flatten().map(|word| word.confidence).collect(),
+            })
+        })
+        .collect()
+}
+
+/// How well a subtitle was recognized: the confidence of its least certain
+/// line, where a line which came back empty counts as not recognized at all.
+fn score(lines: &[TextLine]) -> f32 {
+    lines
+        .iter()
+        .map(|line| {
+            if line.text.is_empty() {
+                0.0
+            } else {
+                line.confidence
+            }
+        })
+        .fold(None, |min: Option<f32>, confidence| {
+            Some(min.map_or(confidence, |min| min.min(confidence)))
+        })
+        .unwrap_or(0.0)
+}
+
+/// How much text was recognized: the number of lines with text, and the
+/// number of characters other than whitespace.
+fn text_amount(lines: &[TextLine]) -> (usize, usize) {
+    let non_empty = lines.iter().filter(|line| !line.text.is_empty()).count();
+    let characters = lines
+        .iter()
+        .flat_map(|line| line.text.chars())
+        .filter(|c| !c.is_whitespace())
+        .count();
+    (non_empty, characters)
+}
+
+/// Preprocessing options a subtitle can be tried again with.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Parameters {
+    threshold: Option<f32>,
+    border: u32,
+    upscale: Upscaler,
+    x_height: u32,
+    dpi: i32,
+}
+
+impl Parameters {
+    fn of(opt: &Opt) -> Self {
+        Parameters {
+            threshold: opt.threshold,
+            border: opt.border,
+            upscale: opt.upscale,
+            x_height: opt.x_height,
+            dpi: opt.dpi,
+        }
+    }
+
+    fn apply(self, opt: &Opt) -> Opt {
+        Opt {
+            threshold: self.threshold,
+            border: self.border,
+            upscale: self.upscale,
+            x_height: self.x_height,
+            dpi: self.dpi,
+            ..opt.clone()
+        }
+    }
 }
 
-struct TesseractWrapper {
-    leptess: LepTess,
+/// The preprocessing options to try a subtitle again with, in order: each of
+/// a few thresholds, with the given border, scale and DPI, a wider border, a
+/// larger scale, and a higher DPI.
+fn retry_parameters(opt: &Opt) -> Vec<Parameters> {
+    let given = Parameters::of(opt);
+    let mut thresholds = vec![opt.threshold];
+    thresholds.extend(
+        RETRY_THRESHOLDS
+            .iter()
+            .map(|&threshold| Some(threshold))
+            .filter(|&threshold| threshold != opt.threshold),
+    );
+    let layouts = [
+        given,
+        Parameters {
+            border: given.border * 2,
+            ..given
+        },
+        Parameters {
+            upscale: Upscaler::Bilinear,
+            x_height: given.x_height.max(RETRY_X_HEIGHT),
+            ..given
+        },
+        Parameters {
+            dpi: given.dpi * 2,
+            ..given
+        },
+    ];
+    let mut parameters = Vec::new();
+    for threshold in thresholds {
+        for &layout in &layouts {
+            // A larger scale may be the given one already.
+            let candidate = Parameters {
+                threshold,
+                ..layout
+            };
+            if candidate != given && !parameters.contains(&candidate) {
+                parameters.push(candidate);
+            }
+        }
+    }
+    parameters
 }
 
-impl TesseractWrapper {
-    fn new(
-        datapath: Option<&str>,
-        language: impl AsRef<str>,
-        config: &[(Variable, String)],
-    ) -> Result<Self> {
-        let mut leptess = LepTess::new(datapath, language.as_ref()).context(InitializeSnafu {})?;
-        // Disable learning by default, though a user could re-enable this
-        // option with `-c`. We turn this off since we are are multithreading,
-        // so this option would result in non-deterministic output.
-        leptess
-            .set_variable(leptess::Variable::ClassifyEnableLearning, "0")
-            .context(SetVariableSnafu {})?;
-        // 7 is PSM_SINGLE_LINE. We have preprocessed the input into individual
-        // lines, and telling Tesseract this fact greatly improves accuracy.
-        leptess
-            .set_variable(leptess::Variable::TesseditPagesegMode, "7")
-            .context(SetVariableSnafu {})?;
-        // Add user options.
-        for (key, value) in config {
-            leptess
-                .set_variable(*key, value)
-                .context(SetVariableSnafu {})?;
+/// Preprocess and recognize a subtitle again with other options until it is
+/// recognized with enough confidence, and keep the best result. Retries which
+/// lose text of the first try are not taken, however confident, and the
+/// engine does not learn from any of them.
+fn retry(
+    bitmap: &BitmapSubtitle,
+    lines: Vec<TextLine>,
+    style: &StreamStyle,
+    engine: &dyn RecognizerFactory,
+    retry_below: f32,
+    opt: &Opt,
+) -> Result<Vec<TextLine>> {
+    let first_score = score(&lines);
+    let (first_non_empty, first_characters) = text_amount(&lines);
+    let mut best = (first_score, lines, None);
+    for parameters in retry_parameters(opt) {
+        let retry_opt = parameters.apply(opt);
+        let images = match preprocess_again(bitmap, style, &retry_opt) {
+            Some(images) => images,
+            None => continue,
+        };
+        let retry_lines = recognize_lines(images, engine, false, &retry_opt)?;
+        let (non_empty, characters) = text_amount(&retry_lines);
+        if non_empty < first_non_empty
+            || (characters as f32) < RETRY_MIN_TEXT * first_characters as f32
+        {
+            continue;
+        }
+        let score = score(&retry_lines);
+        if score > best.0 {
+            best = (score, retry_lines, Some(parameters));
+            if score >= retry_below {
+                break;
+            }
         }
-        Ok(Self { leptess })
     }
+    let (score, lines, parameters) = best;
+    match parameters {
+        Some(parameters) => info!(
+            "Subtitle at {:.3}s: confidence {:.0} instead of {:.0} with threshold {}, border {}, upscale {:?} to x-height {}, DPI {}",
+            bitmap.start_time,
+            score,
+            first_score,
+            parameters
+                .threshold
+                .map_or_else(|| "automatic".to_owned(), |threshold| threshold.to_string()),
+            parameters.border,
+            parameters.upscale,
+            parameters.x_height,
+            parameters.dpi
+        ),
+        None => debug!(
+            "Subtitle at {:.3}s: no better result than confidence {:.0} when retrying",
+            bitmap.start_time, first_score
+        ),
+    }
+    Ok(lines)
+}
 
-    /// Set the tesseract image to the given image's contents.
-    fn set_image(&mut self, image: GrayImage, dpi: i32) -> Result<()> {
-        let mut bytes: Cursor<Vec<u8>> = Cursor::new(Vec::new());
-        DynamicImage::ImageLuma8(image)
-            .write_to(
-                &mut bytes,
-                image::ImageOutputFormat::Pnm(PnmSubtype::Graymap(SampleEncoding::Binary)),
-            )
-            .context(WriteImageSnafu {})?;
-        self.leptess
-            .set_image_from_mem(bytes.get_ref())
-            .context(SetImageSnafu {})?;
-        self.leptess.set_source_resolution(dpi);
-        Ok(())
+/// Start a line of dialogue with a dash, unless it already has one.
+fn add_dialogue_dash(text: String) -> String {
+    if text.starts_with(['-', '\u{2013}', '\u{2014}']) {
+        text
+    } else {
+        format!("- {}", text)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+
+    fn line(text: &str, confidence: f32) -> TextLine {
+        TextLine {
+            text: text.to_owned(),
+            italic: false,
+            color: None,
+            confidence,
+            word_confidences: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn scores_the_least_certain_line() {
+        assert_eq!(score(&[line("a", 90.0), line("b", 70.0)]), 70.0);
+        assert_eq!(score(&[line("a", 90.0), line("", 95.0)]), 0.0);
+        assert_eq!(score(&[]), 0.0);
+    }
+
+    #[test]
+    fn counts_recognized_text() {
+        assert_eq!(
+            text_amount(&[line("Hello there", 90.0), line("", 0.0), line("- Hi", 50.0)]),
+            (2, 13)
+        );
+    }
+
+    #[test]
+    fn never_retries_with_the_given_parameters() {
+        for args in [
+            &["vobsubocr", "movie.idx"][..],
+            &["vobsubocr", "--threshold", "0.5", "movie.idx"],
+            &[
+                "vobsubocr",
+                "--upscale",
+                "bilinear",
+                "--x-height",
+                "40",
+                "movie.idx",
+            ],
+        ] {
+            let opt = Opt::parse_from(args);
+            let given = Parameters::of(&opt);
+            let parameters = retry_parameters(&opt);
+            assert!(!parameters.is_empty());
+            assert!(!parameters.contains(&given));
+            for (i, a) in parameters.iter().enumerate() {
+                assert!(!parameters[i + 1..].contains(a), "{:?}", a);
+            }
+        }
     }
 
-    /// Get text.
-    fn get_text(&mut self) -> Result<String> {
-        self.leptess.get_utf8_text().context(GetTextSnafu {})
+    #[test]
+    fn retries_the_given_threshold_first() {
+        let opt = Opt::parse_from(["vobsubocr", "--threshold", "0.5", "movie.idx"]);
+        let thresholds: Vec<Option<f32>> = retry_parameters(&opt)
+            .iter()
+            .map(|parameters| parameters.threshold)
+            .collect();
+        assert!(thresholds[..3].iter().all(|&t| t == Some(0.5)));
+        assert_eq!(
+            thresholds.iter().filter(|&&t| t == Some(0.5)).count(),
+            3,
+            "{:?}",
+            thresholds
+        );
+        assert!(thresholds.contains(&Some(0.3)));
+        assert!(thresholds.contains(&Some(0.7)));
     }
 }
@@ -1,8 +1,18 @@
+use crate::{
+    command::ImageFormat,
+    engine::Engine,
+    idx::{self, Palette},
+    ifo,
+    ocr::LowConfidence,
+    output::Format,
+    preprocessor::TextColors,
+    upscale::Upscaler,
+};
 use clap::{crate_description, crate_name, crate_version};
 use clap::{Parser, ValueHint};
 use leptess::Variable;
-use snafu::Snafu;
-use std::path::PathBuf;
+use snafu::{ResultExt, Snafu};
+use std::path::{Path, PathBuf};
 
 #[derive(Debug, Snafu)]
 enum Error {
@@ -11,27 +21,92 @@ enum Error {
 
     #[snafu(display("Invalid tesseract variable name: {}", value))]
     TesseractVariableName { value: String },
+
+    #[snafu(display("{}", source))]
+    PaletteColors { source: idx::Error },
+
+    #[snafu(display("Could not read palette from {}: {}", filename, source))]
+    PaletteIfo {
+        filename: String,
+        source: ifo::Error,
+    },
+
+    #[snafu(display("Invalid PID: {}", value))]
+    Pid { value: String },
+
+    #[snafu(display("Invalid text colors: {}", value))]
+    TextColors { value: String },
+
+    #[snafu(display("Invalid confidence, must be between 0 and 100: {}", value))]
+    Confidence { value: String },
 }
 
 type Result<T, E = Error> = std::result::Result<T, E>;
 
-#[derive(Parser, Debug)]
+#[derive(Parser, Debug, Clone)]
 #[clap(name = crate_name!(), about = crate_description!(), version = crate_version!())]
 pub struct Opt {
     /// Threshold for subtitle image binarization.
     ///
     /// Must be between 0.0 and 1.0. Only pixels with luminance above the
-    /// threshold will be considered text pixels for OCR.
-    #[clap(short = 't', long, default_value = "0.6")]
-    pub threshold: f32,
+    /// threshold, relative to the brightest color of the subtitle, will be
+    /// considered text pixels for OCR. If not present, a threshold is chosen
+    /// for each subtitle from the luminance of its colors, and the text is
+    /// whichever side of it is surrounded by the other, so that dark text on
+    /// a bright outline works too.
+    #[clap(short = 't', long)]
+    pub threshold: Option<f32>,
+
+    /// Which colors of DVD subtitles are text, instead of choosing them by
+    /// luminance.
+    ///
+    /// Either a comma-separated list of sub-palette roles, where 0 is the
+    /// background, 1 the pattern, and 2 and 3 the emphasis colors, e.g. `1` or
+    /// `1,3`; or `auto` to detect the text color from the shapes of the
+    /// colors across all subtitles, which helps with dark text on a light
+    /// outline. Takes precedence over `--threshold`.
+    #[clap(long, value_parser = parse_text_colors)]
+    pub text_colors: Option<TextColors>,
+
+    /// Size below which specks are removed before OCR, as a fraction of the
+    /// square of the text height.
+    ///
+    /// Small specks are kept where punctuation would be, next to a letter on
+    /// the baseline or at the top of the line. Use 0 to keep all specks.
+    #[clap(long, default_value = "0.01")]
+    pub speck_size: f32,
 
     /// DPI of subtitle images.
     ///
-    /// This setting doesn't strictly make sense for DVD subtitles, but it can
+    /// This setting doesn't strictly make sense for disc subtitles, but it can
     /// influence Tesseract's output.
     #[clap(short = 'd', long, default_value = "150")]
     pub dpi: i32,
 
+    /// How to scale up line images whose text is smaller than `--x-height`.
+    #[clap(long, value_enum, default_value = "none")]
+    pub upscale: Upscaler,
+
+    /// Height in pixels of lowercase letters to scale line images up to.
+    ///
+    /// Tesseract is most reliable with letters somewhat larger than those of
+    /// most DVD subtitles. Only used with `--upscale`.
+    #[clap(long, default_value = "30")]
+    pub x_height: u32,
+
+    /// Rotate lines of text which are not level.
+    ///
+    /// The rotation is estimated for each line from its projection profile,
+    /// for rotated subtitles such as those of some karaoke and fan-made discs.
+    #[clap(long)]
+    pub deskew: bool,
+
+    /// Shear slanted lines of text upright.
+    ///
+    /// Italic lines are still marked as italic in the output.
+    #[clap(long)]
+    pub deslant: bool,
+
     /// Border in pixels to surround the each subtitle image for OCR.
     ///
     /// This can have subtle effects on the quality of the OCR.
@@ -42,13 +117,86 @@ pub struct Opt {
     #[clap(short = 'o', long, value_parser, value_hint = ValueHint::FilePath)]
     pub output: Option<PathBuf>,
 
+    /// Start each block of text which stood side by side with another with a
+    /// dash.
+    ///
+    /// Blocks of text side by side on the same row, usually the lines of two
+    /// speakers, are always recognized separately and written as separate
+    /// lines, one block after the other. With this option, the first line of
+    /// each block is marked as dialogue like `- Hello`.
+    #[clap(long)]
+    pub dialogue_dashes: bool,
+
+    /// Mark lines whose text color differs from the usual one.
+    ///
+    /// Useful for discs which color-code speakers or songs. The color is
+    /// written as `<font color>` tags in SRT, color overrides in ASS and TTML,
+    /// and the nearest standard color class in WebVTT.
+    #[clap(long)]
+    pub font_colors: bool,
+
+    /// Flag subtitles with a line recognized with less than this mean
+    /// confidence, from 0 to 100, or with no text recognized at all.
+    #[clap(long, value_parser = parse_confidence)]
+    pub min_confidence: Option<f32>,
+
+    /// Preprocess and recognize subtitles again with other thresholds,
+    /// borders, scales and DPIs when a line comes back empty or with less
+    /// than this mean confidence, from 0 to 100, and keep the best result.
+    #[clap(long, value_parser = parse_confidence)]
+    pub retry_below: Option<f32>,
+
+    /// What to do about subtitles below `--min-confidence`.
+    #[clap(long, value_enum, default_value = "log")]
+    pub low_confidence: LowConfidence,
+
+    /// Mark to start uncertain lines with, with `--low-confidence mark`.
+    #[clap(long, default_value = "[?] ")]
+    pub low_confidence_mark: String,
+
+    /// OCR engine to recognize text with.
+    #[clap(long, value_enum, default_value = "tesseract")]
+    pub engine: Engine,
+
+    /// Database of known glyphs for the glyphs engine.
+    ///
+    /// Glyph matching works best for stylized fonts, as each disc uses a
+    /// single font. The file is created if it does not exist. Unknown glyphs
+    /// are asked for when run in a terminal; otherwise they are added
+    /// unnamed, and can be named by editing the file.
+    #[clap(long, value_parser, value_hint = ValueHint::FilePath)]
+    pub glyph_database: Option<PathBuf>,
+
+    /// Image format to give line images to the OCR command in.
+    #[clap(long, value_enum, default_value = "png")]
+    pub ocr_image_format: ImageFormat,
+
+    /// Output subtitle format.
+    ///
+    /// If not present, the format is chosen from the extension of the output
+    /// file, or else SRT is used.
+    #[clap(short = 'f', long, value_enum)]
+    pub format: Option<Format>,
+
     /// Path to Tesseract's tessdata directory.
     #[clap(short = 'D', long, value_hint = ValueHint::DirPath)]
     pub tessdata_dir: Option<String>,
 
     /// The Tesseract language(s) to use for OCR.
+    ///
+    /// If not present, the language is chosen from the language code of the
+    /// subtitle stream; see `--lang-map`. Only the Tesseract engine uses a
+    /// language.
     #[clap(short = 'l', long)]
-    pub lang: String,
+    pub lang: Option<String>,
+
+    /// Map a stream language code to a Tesseract language, e.g. `zh=chi_tra`.
+    ///
+    /// Used when `--lang` is not present. This takes precedence over the
+    /// built-in mapping, which covers most two-letter codes and passes
+    /// three-letter codes through unchanged.
+    #[clap(short = 'm', long, value_parser = parse_lang_mapping, number_of_values = 1)]
+    pub lang_map: Vec<(String, String)>,
 
     /// Set values for config variables.
     ///
@@ -59,12 +207,90 @@ pub struct Opt {
     #[clap(short = 'c', long, value_parser = parse_key_val, number_of_values = 1)]
     pub config: Vec<(Variable, String)>,
 
+    /// Subtitle palette as 16 comma-separated hex colors, or the path of an
+    /// IFO file to read the palette from.
+    ///
+    /// Replaces the palette of DVD subtitles, which is otherwise read from the
+    /// `*.idx` file, the Matroska track, or for `*.vob` files the title set's
+    /// IFO file next to it. Useful when that palette is missing or wrong.
+    #[clap(long, value_parser = parse_palette)]
+    pub palette: Option<Palette>,
+
+    /// Index of the subtitle stream to convert.
+    ///
+    /// This is the `index:` of a stream in an `*.idx` file, the subpicture
+    /// stream number of a `*.vob` file, the track number of a Matroska file,
+    /// or the position of a stream in a `*.ts` file, as printed by
+    /// `--list-streams`. By default, the stream marked as
+    /// default by the input is converted, or else the first one.
+    #[clap(short = 's', long, conflicts_with_all = ["track_language", "all_streams"])]
+    pub stream: Option<u64>,
+
+    /// Convert the first subtitle stream with the given language code.
+    ///
+    /// The code is compared as written in the input, e.g. `en` for `*.idx`
+    /// files and `eng` for Matroska files.
+    #[clap(long, conflicts_with = "all_streams")]
+    pub track_language: Option<String>,
+
+    /// Convert the DVB subtitle stream carried on the given PID.
+    ///
+    /// Only applies to transport streams. The PID may be given in decimal or
+    /// as `0x`-prefixed hexadecimal.
+    #[clap(
+        long,
+        value_parser = parse_pid,
+        conflicts_with_all = ["stream", "track_language", "all_streams"]
+    )]
+    pub pid: Option<u16>,
+
+    /// Convert every subtitle stream into a separate file.
+    ///
+    /// Each file is named after the output file (or else the input file),
+    /// with the stream's language code inserted before the extension, e.g.
+    /// `movie.en.srt`.
+    #[clap(long)]
+    pub all_streams: bool,
+
+    /// Only convert forced subtitles, which are meant to be shown even when
+    /// subtitles are off, e.g. to translate foreign dialogue.
+    #[clap(long, conflicts_with = "forced_track")]
+    pub forced_only: bool,
+
+    /// Also write the forced subtitles into a separate file.
+    ///
+    /// The file is named after the output file (or else the input file), with
+    /// `forced` inserted before the extension, e.g. `movie.forced.srt`.
+    #[clap(long)]
+    pub forced_track: bool,
+
+    /// List the subtitle streams of the input file and exit.
+    #[clap(long)]
+    pub list_streams: bool,
+
+    /// Input `*.idx` file, `*.vob` file, Blu-ray `*.sup` file, `*.ts` file
+    /// with DVB subtitles, or a Matroska file with a VobSub or PGS track.
+    ///
+    /// For the first VOB file of a title set, like `VTS_01_1.VOB`, the
+    /// following ones are read as well.
     #[clap(name = "FILE", value_parser, value_hint = ValueHint::FilePath)]
     pub input: PathBuf,
 
     /// Dump processed subtitle images into the working directory as PNGs.
     #[clap(long)]
     pub dump: bool,
+
+    /// OCR program to run for each line image with the command engine,
+    /// followed by its arguments, after `--`.
+    ///
+    /// Each argument is passed as given, so paths with spaces only need the
+    /// shell's quoting. The image is written to the program's standard
+    /// input, or, if an argument is `{}`, to a temporary file whose path
+    /// takes its place. The program writes the text to its standard output,
+    /// either as plain text or as a JSON object like
+    /// `{"text": "Hello", "confidence": 93.5}`.
+    #[clap(last = true, value_name = "OCR_COMMAND")]
+    pub ocr_command: Vec<String>,
 }
 
 // https://github.com/clap-rs/clap_derive/blob/master/examples/keyvalue.rs
@@ -78,6 +304,54 @@ fn parse_key_val(s: &str) -> Result<(Variable, String), Error> {
     ))
 }
 
+fn parse_lang_mapping(s: &str) -> Result<(String, String)> {
+    let (code, lang) = s.split_once('=').ok_or_else(|| Error::ParseKeyValuePair {
+        value: s.to_owned(),
+    })?;
+    Ok((code.to_owned(), lang.to_owned()))
+}
+
+fn parse_palette(s: &str) -> Result<Palette> {
+    if Path::new(s).is_file() {
+        let title_set = ifo::read_title_set(s).context(PaletteIfoSnafu { filename: s })?;
+        return Ok(title_set.palette);
+    }
+    idx::parse_palette(s).context(PaletteColorsSnafu {})
+}
+
+fn parse_pid(s: &str) -> Result<u16> {
+    match s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+        Some(hex) => u16::from_str_radix(hex, 16).ok(),
+        None => s.parse().ok(),
+    }
+    .filter(|&pid| pid <= 0x1fff)
+    .ok_or_else(|| Error::Pid {
+        value: s.to_owned(),
+    })
+}
+
+fn parse_confidence(s: &str) -> Result<f32> {
+    s.parse()
+        .ok()
+        .filter(|confidence| (0.0..=100.0).contains(confidence))
+        .ok_or_else(|| Error::Confidence {
+            value: s.to_owned(),
+        })
+}
+
+fn parse_text_colors(s: &str) -> Result<TextColors> {
+    if s == "auto" {
+        return Ok(TextColors::Detect);
+    }
+    s.split(',')
+        .map(|role| role.trim().parse().ok().filter(|&role: &u8| role < 4))
+        .collect::<Option<Vec<u8>>>()
+        .map(TextColors::Roles)
+        .ok_or_else(|| Error::TextColors {
+            value: s.to_owned(),
+        })
+}
+
 fn parse_tesseract_variable(s: impl AsRef<str>) -> Result<Variable> {
     Ok(match s.as_ref() {
         "classify_num_cp_levels" => Variable::ClassifyNumCpLevels,
@@ -735,3 +1009,62 @@ fn parse_tesseract_variable(s: impl AsRef<str>) -> Result<Variable> {
         }
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_language_mappings() {
+        assert_eq!(
+            parse_lang_mapping("zh=chi_tra").unwrap(),
+            ("zh".to_owned(), "chi_tra".to_owned())
+        );
+        assert!(parse_lang_mapping("chi_tra").is_err());
+
+        let opt = Opt::parse_from(["vobsubocr", "-m", "zh=chi_tra", "-m", "en=eng", "movie.idx"]);
+        assert_eq!(
+            opt.lang_map,
+            [
+                ("zh".to_owned(), "chi_tra".to_owned()),
+                ("en".to_owned(), "eng".to_owned())
+            ]
+        );
+    }
+
+    #[test]
+    fn parses_text_colors() {
+        assert!(matches!(parse_text_colors("auto"), Ok(TextColors::Detect)));
+        assert!(matches!(
+            parse_text_colors("1,3"),
+            Ok(TextColors::Roles(roles)) if roles == [1, 3]
+        ));
+        assert!(matches!(
+            parse_text_colors(" 2 "),
+            Ok(TextColors::Roles(roles)) if roles == [2]
+        ));
+        assert!(parse_text_colors("").is_err());
+        assert!(parse_text_colors("1,4").is_err());
+        assert!(parse_text_colors("1,").is_err());
+    }
+
+    #[test]
+    fn parses_palettes() {
+        let colors: Vec<String> = (0..16).map(|i| format!("{:02x}0000", i * 16)).collect();
+        let palette = parse_palette(&colors.join(",")).unwrap();
+        assert_eq!(palette[0], [0x00, 0, 0]);
+        assert_eq!(palette[15], [0xf0, 0, 0]);
+
+        let prefixed: Vec<String> = colors.iter().map(|color| format!("#{}", color)).collect();
+        assert_eq!(parse_palette(&prefixed.join(", ")).unwrap(), palette);
+
+        assert!(matches!(
+            parse_palette(&colors[..15].join(",")),
+            Err(Error::PaletteColors { .. })
+        ));
+        assert!(matches!(
+            parse_palette(&[&colors[..], &colors[..1]].concat().join(",")),
+            Err(Error::PaletteColors { .. })
+        ));
+    }
+}
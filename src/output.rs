@@ -0,0 +1,717 @@
+//! Writing recognized subtitles out in the supported subtitle formats.
+
+use crate::bitmap::Rect;
+use clap::ValueEnum;
+use snafu::Snafu;
+use std::{collections::BTreeMap, fmt::Write, path::Path};
+use subparse::{
+    timetypes::{TimePoint, TimeSpan},
+    SrtFile, SubtitleFile,
+};
+
+#[derive(Debug, Snafu)]
+pub enum Error {
+    #[snafu(display("Could not generate SRT file: {}", message))]
+    GenerateSrt { message: String },
+}
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+/// A subtitle file format we can write.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Format {
+    /// SubRip.
+    Srt,
+    /// Advanced SubStation Alpha, which keeps each subtitle's position.
+    Ass,
+    /// WebVTT, for HTML5 video, which keeps each subtitle's position.
+    #[value(name = "webvtt", alias = "vtt")]
+    WebVtt,
+    /// TTML, following the EBU-TT-D profile, with a region for each position
+    /// and forced subtitles marked.
+    #[value(alias = "ebu-tt-d")]
+    Ttml,
+}
+
+impl Format {
+    /// Find the format matching a file's extension.
+    pub fn from_path(path: &Path) -> Option<Self> {
+        let extension = path.extension()?.to_str()?.to_ascii_lowercase();
+        match extension.as_str() {
+            "srt" => Some(Format::Srt),
+            "ass" | "ssa" => Some(Format::Ass),
+            "vtt" => Some(Format::WebVtt),
+            "ttml" | "dfxp" => Some(Format::Ttml),
+            _ => None,
+        }
+    }
+
+    /// The usual file extension of the format.
+    pub fn extension(self) -> &'static str {
+        match self {
+            Format::Srt => "srt",
+            Format::Ass => "ass",
+            Format::WebVtt => "vtt",
+            Format::Ttml => "ttml",
+        }
+    }
+}
+
+/// A recognized subtitle, ready to be written out.
+#[derive(Debug)]
+pub struct Subtitle {
+    pub time_span: TimeSpan,
+    /// Whether the subtitle should be shown even when subtitles are off.
+    pub force: bool,
+    /// Where the subtitle was shown on screen.
+    pub area: Rect,
+    pub lines: Vec<TextLine>,
+}
+
+/// A line of recognized text, with its style.
+#[derive(Debug)]
+pub struct TextLine {
+    pub text: String,
+    pub italic: bool,
+    /// The sRGB color of the text, if it should be marked.
+    pub color: Option<[u8; 3]>,
+    /// Mean OCR confidence of the line, from 0 to 100.
+    pub confidence: f32,
+    /// OCR confidence of each word of the line.
+    pub word_confidences: Vec<f32>,
+}
+
+/// Generate a subtitle file. The frame size of the video is needed to place
+/// subtitles on screen; if it is not known, it is guessed from the subtitles.
+/// The language code is recorded in formats which have a place for it.
+pub fn generate(
+    format: Format,
+    subtitles: &[Subtitle],
+    frame_size: Option<(u32, u32)>,
+    language: Option<&str>,
+) -> Result<Vec<u8>> {
+    let frame_size = frame_size.unwrap_or_else(|| guess_frame_size(subtitles));
+    match format {
+        Format::Srt => generate_srt(subtitles),
+        Format::Ass => Ok(generate_ass(subtitles, frame_size).into_bytes()),
+        Format::WebVtt => Ok(generate_webvtt(subtitles, frame_size).into_bytes()),
+        Format::Ttml => Ok(generate_ttml(subtitles, frame_size, language).into_bytes()),
+    }
+}
+
+fn generate_srt(subtitles: &[Subtitle]) -> Result<Vec<u8>> {
+    let subtitles = subtitles
+        .iter()
+        .map(|subtitle| {
+            let lines: Vec<String> = subtitle
+                .lines
+                .iter()
+                .map(|line| {
+                    let mut text = line.text.clone();
+                    if line.italic {
+                        text = format!("<i>{}</i>", text);
+                    }
+                    if let Some([r, g, b]) = line.color {
+                        text = format!(
+                            r##"<font color="#{:02x}{:02x}{:02x}">{}</font>"##,
+                            r, g, b, text
+                        );
+                    }
+                    text
+                })
+                .collect();
+            (subtitle.time_span, lines.join("\n"))
+        })
+        .collect();
+    let file = SubtitleFile::SubRipFile(SrtFile::create(subtitles).map_err(|e| {
+        GenerateSrtSnafu {
+            message: e.to_string(),
+        }
+        .build()
+    })?);
+    file.to_data().map_err(|e| {
+        GenerateSrtSnafu {
+            message: e.to_string(),
+        }
+        .build()
+    })
+}
+
+fn generate_ass(subtitles: &[Subtitle], (width, height): (u32, u32)) -> String {
+    // Scale the font with the frame, so it looks about the same at any
+    // resolution.
+    let font_size = (height as f32 * 0.055).round();
+    let mut ass = String::new();
+    writeln!(ass, "[Script Info]").unwrap();
+    writeln!(ass, "; Generated by {}", env!("CARGO_PKG_NAME")).unwrap();
+    writeln!(ass, "ScriptType: v4.00+").unwrap();
+    writeln!(ass, "PlayResX: {}", width).unwrap();
+    writeln!(ass, "PlayResY: {}", height).unwrap();
+    writeln!(ass, "ScaledBorderAndShadow: yes").unwrap();
+    writeln!(ass).unwrap();
+    writeln!(ass, "[V4+ Styles]").unwrap();
+    writeln!(ass, "Format: Name, Fontname, Fontsize, PrimaryColour, SecondaryColour, OutlineColour, BackColour, Bold, Italic, Underline, StrikeOut, ScaleX, ScaleY, Spacing, Angle, BorderStyle, Outline, Shadow, Alignment, MarginL, MarginR, MarginV, Encoding").unwrap();
+    writeln!(ass, "Style: Default,Arial,{},&H00FFFFFF,&H000000FF,&H00000000,&H00000000,0,0,0,0,100,100,0,0,1,2,0,2,10,10,10,1", font_size).unwrap();
+    writeln!(ass).unwrap();
+    writeln!(ass, "[Events]").unwrap();
+    writeln!(
+        ass,
+        "Format: Layer, Start, End, Style, Name, MarginL, MarginR, MarginV, Effect, Text"
+    )
+    .unwrap();
+    for subtitle in subtitles {
+        let lines: Vec<String> = subtitle
+            .lines
+            .iter()
+            .map(|line| {
+                let mut text = ass_escape(&line.text);
+                if line.italic {
+                    text = format!("{{\\i1}}{}{{\\i0}}", text);
+                }
+                if let Some([r, g, b]) = line.color {
+                    // Colors are written as BGR, and `\c` on its own goes
+                    // back to the style's color.
+                    text = format!("{{\\c&H{:02X}{:02X}{:02X}&}}{}{{\\c}}", b, g, r, text);
+                }
+                text
+            })
+            .collect();
+        writeln!(
+            ass,
+            "Dialogue: 0,{},{},Default,,0,0,0,,{}{}",
+            ass_time(subtitle.time_span.start),
+            ass_time(subtitle.time_span.end),
+            ass_position(subtitle.area, height),
+            lines.join("\\N")
+        )
+        .unwrap();
+    }
+    ass
+}
+
+/// An override block placing a subtitle where it was on screen. Subtitles in
+/// the top half of the screen are anchored by their top edge, and all others by
+/// their bottom edge, so that differences in font size grow them away from
+/// the edge of the screen.
+fn ass_position(area: Rect, height: u32) -> String {
+    let x = area.x + area.width / 2;
+    if area.y + area.height / 2 < height / 2 {
+        format!("{{\\an8\\pos({},{})}}", x, area.y)
+    } else {
+        format!("{{\\an2\\pos({},{})}}", x, area.y + area.height)
+    }
+}
+
+/// Keep text from being read as override codes. ASS has no escape character,
+/// so a word joiner goes after each backslash to break up sequences like
+/// `\N`, and braces are replaced by their fullwidth forms, which renderers do
+/// not take for override blocks.
+fn ass_escape(text: &str) -> String {
+    text.replace('\\', "\\\u{2060}")
+        .replace('{', "\u{ff5b}")
+        .replace('}', "\u{ff5d}")
+}
+
+/// Format a time as `H:MM:SS.cc`.
+fn ass_time(time: TimePoint) -> String {
+    let centiseconds = time.msecs().max(0) / 10;
+    format!(
+        "{}:{:02}:{:02}.{:02}",
+        centiseconds / 360_000,
+        centiseconds / 6000 % 60,
+        centiseconds / 100 % 60,
+        centiseconds % 100
+    )
+}
+
+fn generate_webvtt(subtitles: &[Subtitle], frame_size: (u32, u32)) -> String {
+    let mut vtt = String::from("WEBVTT\n");
+    for subtitle in subtitles {
+        writeln!(vtt).unwrap();
+        writeln!(
+            vtt,
+            "{} --> {} {}",
+            clock_time(subtitle.time_span.start),
+            clock_time(subtitle.time_span.end),
+            webvtt_settings(subtitle.area, frame_size)
+        )
+        .unwrap();
+        for line in &subtitle.lines {
+            let mut text = webvtt_escape(&line.text);
+            if line.italic {
+                text = format!("<i>{}</i>", text);
+            }
+            if let Some(color) = line.color {
+                text = format!("<c.{}>{}</c>", webvtt_color_class(color), text);
+            }
+            writeln!(vtt, "{}", text).unwrap();
+        }
+    }
+    vtt
+}
+
+/// Cue settings placing a subtitle where it was on screen, anchored the same
+/// way as in [`ass_position`].
+fn webvtt_settings(area: Rect, (width, height): (u32, u32)) -> String {
+    let percent = |value: u32, total: u32| value as f32 * 100.0 / total.max(1) as f32;
+    let position = percent(area.x + area.width / 2, width);
+    if area.y + area.height / 2 < height / 2 {
+        format!(
+            "line:{:.1}%,start position:{:.1}%",
+            percent(area.y, height),
+            position
+        )
+    } else {
+        format!(
+            "line:{:.1}%,end position:{:.1}%",
+            percent(area.y + area.height, height),
+            position
+        )
+    }
+}
+
+/// The WebVTT default color class nearest to a color, since cue text has no
+/// way to give arbitrary colors.
+fn webvtt_color_class(color: [u8; 3]) -> &'static str {
+    const CLASSES: [(&str, [u8; 3]); 8] = [
+        ("white", [255, 255, 255]),
+        ("lime", [0, 255, 0]),
+        ("cyan", [0, 255, 255]),
+        ("red", [255, 0, 0]),
+        ("yellow", [255, 255, 0]),
+        ("magenta", [255, 0, 255]),
+        ("blue", [0, 0, 255]),
+        ("black", [0, 0, 0]),
+    ];
+    let distance = |other: [u8; 3]| -> u32 {
+        color
+            .iter()
+            .zip(&other)
+            .map(|(&a, &b)| (a.abs_diff(b) as u32).pow(2))
+            .sum()
+    };
+    CLASSES
+        .iter()
+        .min_by_key(|(_, other)| distance(*other))
+        .map(|(class, _)| *class)
+        .unwrap_or("white")
+}
+
+/// Format a time as `HH:MM:SS.mmm`, as used by WebVTT and TTML.
+fn clock_time(time: TimePoint) -> String {
+    let milliseconds = time.msecs().max(0);
+    format!(
+        "{:02}:{:02}:{:02}.{:03}",
+        milliseconds / 3_600_000,
+        milliseconds / 60_000 % 60,
+        milliseconds / 1000 % 60,
+        milliseconds % 1000
+    )
+}
+
+/// Escape the characters which have a meaning in cue text.
+fn webvtt_escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+fn generate_ttml(
+    subtitles: &[Subtitle],
+    (width, height): (u32, u32),
+    language: Option<&str>,
+) -> String {
+    // Give each distinct position its own region, with coordinates rounded
+    // outwards to whole percentages so that nearby positions share one.
+    let mut regions: BTreeMap<(u32, u32, u32, u32), String> = BTreeMap::new();
+    let subtitle_regions: Vec<(u32, u32, u32, u32)> = subtitles
+        .iter()
+        .map(|subtitle| {
+            let area = subtitle.area;
+            // Keep regions on screen, even for areas beyond a frame size
+            // which was guessed wrong.
+            let left = (area.x * 100 / width.max(1)).min(99);
+            let top = (area.y * 100 / height.max(1)).min(99);
+            let right = ((area.x + area.width) * 100)
+                .div_ceil(width.max(1))
+                .clamp(left + 1, 100);
+            let bottom = ((area.y + area.height) * 100)
+                .div_ceil(height.max(1))
+                .clamp(top + 1, 100);
+            let region = (left, top, right, bottom);
+            let id = format!("r{}", regions.len());
+            regions.entry(region).or_insert(id);
+            region
+        })
+        .collect();
+
+    let mut ttml = String::new();
+    writeln!(ttml, r#"<?xml version="1.0" encoding="UTF-8"?>"#).unwrap();
+    writeln!(
+        ttml,
+        r#"<tt xmlns="http://www.w3.org/ns/ttml" xmlns:ttp="http://www.w3.org/ns/ttml#parameter" xmlns:tts="http://www.w3.org/ns/ttml#styling" xmlns:ebuttm="urn:ebu:tt:metadata" xmlns:itts="http://www.w3.org/ns/ttml/profile/imsc1#styling" ttp:timeBase="media" ttp:cellResolution="32 15" xml:lang="{}">"#,
+        // `und` is the code for an undetermined language.
+        xml_escape(language.unwrap_or("und"))
+    )
+    .unwrap();
+    writeln!(ttml, "  <head>").unwrap();
+    writeln!(ttml, "    <metadata>").unwrap();
+    writeln!(ttml, "      <ebuttm:documentMetadata>").unwrap();
+    writeln!(
+        ttml,
+        "        <ebuttm:conformsToStandard>urn:ebu:tt:distribution:2018-04</ebuttm:conformsToStandard>"
+    )
+    .unwrap();
+    writeln!(ttml, "      </ebuttm:documentMetadata>").unwrap();
+    writeln!(ttml, "    </metadata>").unwrap();
+    writeln!(ttml, "    <styling>").unwrap();
+    writeln!(
+        ttml,
+        r##"      <style xml:id="s0" tts:fontFamily="proportionalSansSerif" tts:fontSize="100%" tts:lineHeight="normal" tts:textAlign="center" tts:color="#FFFFFF" tts:backgroundColor="#000000C2"/>"##
+    )
+    .unwrap();
+    writeln!(ttml, "    </styling>").unwrap();
+    writeln!(ttml, "    <layout>").unwrap();
+    for (&(left, top, right, bottom), id) in &regions {
+        // Anchor text to the edge of the region nearest the edge of the
+        // screen, like the other formats do.
+        let display_align = if top + bottom < 100 {
+            "before"
+        } else {
+            "after"
+        };
+        writeln!(
+            ttml,
+            r#"      <region xml:id="{}" tts:origin="{}% {}%" tts:extent="{}% {}%" tts:displayAlign="{}"/>"#,
+            id,
+            left,
+            top,
+            right - left,
+            bottom - top,
+            display_align
+        )
+        .unwrap();
+    }
+    writeln!(ttml, "    </layout>").unwrap();
+    writeln!(ttml, "  </head>").unwrap();
+    writeln!(ttml, r#"  <body style="s0">"#).unwrap();
+    writeln!(ttml, "    <div>").unwrap();
+    for (i, (subtitle, region)) in subtitles.iter().zip(&subtitle_regions).enumerate() {
+        let lines: Vec<String> = subtitle
+            .lines
+            .iter()
+            .map(|line| {
+                let mut text = xml_escape(&line.text);
+                if line.italic {
+                    text = format!(r#"<span tts:fontStyle="italic">{}</span>"#, text);
+                }
+                if let Some([r, g, b]) = line.color {
+                    text = format!(
+                        r##"<span tts:color="#{:02X}{:02X}{:02X}">{}</span>"##,
+                        r, g, b, text
+                    );
+                }
+                text
+            })
+            .collect();
+        writeln!(
+            ttml,
+            r#"      <p xml:id="sub{}" region="{}" begin="{}" end="{}"{}>{}</p>"#,
+            i + 1,
+            regions[region],
+            clock_time(subtitle.time_span.start),
+            clock_time(subtitle.time_span.end),
+            if subtitle.force {
+                r#" itts:forcedDisplay="true""#
+            } else {
+                ""
+            },
+            lines.join("<br/>")
+        )
+        .unwrap();
+    }
+    writeln!(ttml, "    </div>").unwrap();
+    writeln!(ttml, "  </body>").unwrap();
+    writeln!(ttml, "</tt>").unwrap();
+    ttml
+}
+
+/// Escape the characters which have a meaning in XML text and attributes.
+fn xml_escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// Guess the frame size from the extent of the subtitles, assuming one of the
+/// usual DVD, HD or UHD sizes.
+fn guess_frame_size(subtitles: &[Subtitle]) -> (u32, u32) {
+    let right = subtitles
+        .iter()
+        .map(|subtitle| subtitle.area.x + subtitle.area.width)
+        .max()
+        .unwrap_or(0);
+    let bottom = subtitles
+        .iter()
+        .map(|subtitle| subtitle.area.y + subtitle.area.height)
+        .max()
+        .unwrap_or(0);
+    [
+        (720, 480),
+        (720, 576),
+        (1280, 720),
+        (1920, 1080),
+        (3840, 2160),
+    ]
+    .iter()
+    .copied()
+    .find(|&(width, height)| right <= width && bottom <= height)
+    .unwrap_or((right, bottom))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn formats_ass_times() {
+        assert_eq!(ass_time(TimePoint::from_msecs(0)), "0:00:00.00");
+        assert_eq!(ass_time(TimePoint::from_msecs(3_723_456)), "1:02:03.45");
+        assert_eq!(ass_time(TimePoint::from_msecs(-500)), "0:00:00.00");
+    }
+
+    #[test]
+    fn anchors_ass_positions_to_the_nearest_edge() {
+        let top = Rect {
+            x: 100,
+            y: 20,
+            width: 200,
+            height: 40,
+        };
+        assert_eq!(ass_position(top, 480), "{\\an8\\pos(200,20)}");
+        let bottom = Rect {
+            x: 100,
+            y: 400,
+            width: 200,
+            height: 40,
+        };
+        assert_eq!(ass_position(bottom, 480), "{\\an2\\pos(200,440)}");
+    }
+
+    #[test]
+    fn escapes_ass_override_codes() {
+        assert_eq!(ass_escape("plain text"), "plain text");
+        assert_eq!(ass_escape("a\\Nb"), "a\\\u{2060}Nb");
+        assert_eq!(ass_escape("{\\i1}x"), "\u{ff5b}\\\u{2060}i1\u{ff5d}x");
+    }
+
+    fn subtitle(start: i64, end: i64, area: Rect, text: &str) -> Subtitle {
+        Subtitle {
+            time_span: TimeSpan::new(TimePoint::from_msecs(start), TimePoint::from_msecs(end)),
+            force: false,
+            area,
+            lines: text
+                .lines()
+                .map(|line| TextLine {
+                    text: line.to_owned(),
+                    italic: false,
+                    color: None,
+                    confidence: 100.0,
+                    word_confidences: Vec::new(),
+                })
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn generates_webvtt_cues() {
+        let subtitles = [
+            subtitle(
+                1500,
+                3250,
+                Rect {
+                    x: 160,
+                    y: 40,
+                    width: 400,
+                    height: 60,
+                },
+                "Hello & <you>\nthere",
+            ),
+            subtitle(
+                61_000,
+                62_500,
+                Rect {
+                    x: 60,
+                    y: 390,
+                    width: 300,
+                    height: 42,
+                },
+                "Bye",
+            ),
+        ];
+        let vtt = generate(Format::WebVtt, &subtitles, Some((720, 480)), None).unwrap();
+        assert_eq!(
+            String::from_utf8(vtt).unwrap(),
+            "WEBVTT\n\
+             \n\
+             00:00:01.500 --> 00:00:03.250 line:8.3%,start position:50.0%\n\
+             Hello &amp; &lt;you&gt;\n\
+             there\n\
+             \n\
+             00:01:01.000 --> 00:01:02.500 line:90.0%,end position:29.2%\n\
+             Bye\n"
+        );
+    }
+
+    #[test]
+    fn finds_formats_by_extension() {
+        assert_eq!(
+            Format::from_path(Path::new("movie.vtt")),
+            Some(Format::WebVtt)
+        );
+        assert_eq!(
+            Format::from_path(Path::new("MOVIE.VTT")),
+            Some(Format::WebVtt)
+        );
+        assert_eq!(Format::from_path(Path::new("movie.ass")), Some(Format::Ass));
+        assert_eq!(Format::from_path(Path::new("movie.txt")), None);
+        assert_eq!(Format::from_path(Path::new("movie")), None);
+    }
+
+    #[test]
+    fn generates_ttml_regions() {
+        let top = Rect {
+            x: 100,
+            y: 30,
+            width: 500,
+            height: 50,
+        };
+        let bottom = Rect {
+            x: 110,
+            y: 500,
+            width: 480,
+            height: 40,
+        };
+        let mut subtitles = [
+            subtitle(1000, 2000, top, "Tom & Jerry\nsay \"hi\""),
+            subtitle(3000, 4000, bottom, "Forced"),
+            subtitle(5000, 6000, top, "Again"),
+        ];
+        subtitles[1].force = true;
+        let ttml = generate(Format::Ttml, &subtitles, Some((720, 576)), None).unwrap();
+        assert_eq!(
+            String::from_utf8(ttml).unwrap(),
+            r##"<?xml version="1.0" encoding="UTF-8"?>
+<tt xmlns="http://www.w3.org/ns/ttml" xmlns:ttp="http://www.w3.org/ns/ttml#parameter" xmlns:tts="http://www.w3.org/ns/ttml#styling" xmlns:ebuttm="urn:ebu:tt:metadata" xmlns:itts="http://www.w3.org/ns/ttml/profile/imsc1#styling" ttp:timeBase="media" ttp:cellResolution="32 15" xml:lang="und">
+  <head>
+    <metadata>
+      <ebuttm:documentMetadata>
+        <ebuttm:conformsToStandard>urn:ebu:tt:distribution:2018-04</ebuttm:conformsToStandard>
+      </ebuttm:documentMetadata>
+    </metadata>
+    <styling>
+      <style xml:id="s0" tts:fontFamily="proportionalSansSerif" tts:fontSize="100%" tts:lineHeight="normal" tts:textAlign="center" tts:color="#FFFFFF" tts:backgroundColor="#000000C2"/>
+    </styling>
+    <layout>
+      <region xml:id="r0" tts:origin="13% 5%" tts:extent="71% 9%" tts:displayAlign="before"/>
+      <region xml:id="r1" tts:origin="15% 86%" tts:extent="67% 8%" tts:displayAlign="after"/>
+    </layout>
+  </head>
+  <body style="s0">
+    <div>
+      <p xml:id="sub1" region="r0" begin="00:00:01.000" end="00:00:02.000">Tom &amp; Jerry<br/>say &quot;hi&quot;</p>
+      <p xml:id="sub2" region="r1" begin="00:00:03.000" end="00:00:04.000" itts:forcedDisplay="true">Forced</p>
+      <p xml:id="sub3" region="r0" begin="00:00:05.000" end="00:00:06.000">Again</p>
+    </div>
+  </body>
+</tt>
+"##
+        );
+    }
+
+    #[test]
+    fn keeps_ttml_regions_on_screen() {
+        // Areas beyond a frame size which was guessed wrong.
+        let subtitles = [
+            subtitle(
+                1000,
+                2000,
+                Rect {
+                    x: 700,
+                    y: 560,
+                    width: 100,
+                    height: 40,
+                },
+                "Edge",
+            ),
+            subtitle(
+                3000,
+                4000,
+                Rect {
+                    x: 800,
+                    y: 600,
+                    width: 20,
+                    height: 20,
+                },
+                "Outside",
+            ),
+        ];
+        let ttml = generate_ttml(&subtitles, (720, 576), Some("de"));
+        assert!(ttml.contains(r#"xml:lang="de""#));
+        assert!(ttml.contains(r#"<region xml:id="r0" tts:origin="97% 97%" tts:extent="3% 3%""#));
+        assert!(ttml.contains(r#"<region xml:id="r1" tts:origin="99% 99%" tts:extent="1% 1%""#));
+    }
+
+    #[test]
+    fn marks_italic_lines_in_every_format() {
+        let mut subtitle = subtitle(
+            1000,
+            2000,
+            Rect {
+                x: 100,
+                y: 400,
+                width: 200,
+                height: 40,
+            },
+            "Slanted\nUpright",
+        );
+        subtitle.lines[0].italic = true;
+        let output = |format| {
+            let data = generate(format, std::slice::from_ref(&subtitle), None, None).unwrap();
+            String::from_utf8(data).unwrap()
+        };
+        assert!(output(Format::Srt).contains("<i>Slanted</i>\nUpright\n"));
+        assert!(output(Format::Ass).contains("{\\i1}Slanted{\\i0}\\NUpright\n"));
+        assert!(output(Format::WebVtt).contains("<i>Slanted</i>\nUpright\n"));
+        assert!(output(Format::Ttml)
+            .contains(r#"<span tts:fontStyle="italic">Slanted</span><br/>Upright</p>"#));
+    }
+
+    #[test]
+    fn wraps_italics_in_colors() {
+        let mut subtitle = subtitle(
+            1000,
+            2000,
+            Rect {
+                x: 100,
+                y: 400,
+                width: 200,
+                height: 40,
+            },
+            "Yellow",
+        );
+        subtitle.lines[0].italic = true;
+        subtitle.lines[0].color = Some([255, 255, 0]);
+        let output = |format| {
+            let data = generate(format, std::slice::from_ref(&subtitle), None, None).unwrap();
+            String::from_utf8(data).unwrap()
+        };
+        assert!(output(Format::Srt).contains(r##"<font color="#ffff00"><i>Yellow</i></font>"##));
+        assert!(output(Format::Ass).contains("{\\c&H00FFFF&}{\\i1}Yellow{\\i0}{\\c}"));
+        assert!(output(Format::WebVtt).contains("<c.yellow><i>Yellow</i></c>"));
+        assert!(output(Format::Ttml).contains(
+            r##"<span tts:color="#FFFF00"><span tts:fontStyle="italic">Yellow</span></span>"##
+        ));
+    }
+}
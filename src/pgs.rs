@@ -0,0 +1,634 @@
+//! Decoding Blu-ray presentation graphics (PGS) subtitles, as found in `*.sup`
+//! files and in `S_HDMV/PGS` Matroska tracks.
+
+use crate::bitmap::{ycrcb_to_rgb_bt709, BitmapSubtitle, Rect};
+use log::warn;
+use snafu::{ensure, OptionExt, ResultExt, Snafu};
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{self, BufReader, ErrorKind, Read},
+    path::Path,
+};
+
+const MAGIC: &[u8; 2] = b"PG";
+/// Size of the header in front of each segment of a `*.sup` file.
+const SUP_HEADER_SIZE: usize = 13;
+const CLOCK_RATE: f64 = 90_000.0;
+/// How long to show the last subtitle if nothing clears it, in seconds.
+const DEFAULT_DURATION: f64 = 5.0;
+
+const PALETTE_DEFINITION: u8 = 0x14;
+const OBJECT_DEFINITION: u8 = 0x15;
+const PRESENTATION_COMPOSITION: u8 = 0x16;
+const END_OF_DISPLAY_SET: u8 = 0x80;
+
+/// Composition state which starts a new epoch, discarding all palettes and
+/// objects.
+const EPOCH_START: u8 = 0x80;
+/// Flag for a composition which only changes the palette of what is shown.
+const PALETTE_UPDATE: u8 = 0x80;
+const OBJECT_CROPPED: u8 = 0x80;
+const OBJECT_FORCED: u8 = 0x40;
+const FIRST_IN_SEQUENCE: u8 = 0x80;
+
+#[derive(Debug, Snafu)]
+pub enum Error {
+    #[snafu(display("Could not read PGS subtitles: {}", source))]
+    Io { source: io::Error },
+
+    #[snafu(display("Missing PGS segment header at offset {}", offset))]
+    NotPgs { offset: u64 },
+
+    #[snafu(display("Truncated PGS segment of type {:#04x}", kind))]
+    Truncated { kind: u8 },
+
+    #[snafu(display("Composition refers to undefined object {}", id))]
+    MissingObject { id: u16 },
+
+    #[snafu(display("Composition refers to undefined palette {}", id))]
+    MissingPalette { id: u8 },
+
+    #[snafu(display("Invalid run-length encoding in object {}", id))]
+    InvalidRle { id: u16 },
+}
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+/// A PGS segment, along with the time it applies at.
+#[derive(Debug)]
+pub struct Segment {
+    seconds: f64,
+    kind: u8,
+    data: Vec<u8>,
+}
+
+/// Check for a segment header at the start of the file.
+pub fn is_sup<P: AsRef<Path>>(path: P) -> io::Result<bool> {
+    let mut magic = [0; 2];
+    match File::open(path)?.read_exact(&mut magic) {
+        Ok(()) => Ok(&magic == MAGIC),
+        Err(e) if e.kind() == ErrorKind::UnexpectedEof => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+/// Read all segments of a `*.sup` file, where each segment carries its own
+/// header with a presentation timestamp.
+pub fn read_sup<P: AsRef<Path>>(path: P) -> Result<Vec<Segment>> {
+    let mut reader = BufReader::new(File::open(path).context(IoSnafu {})?);
+    let mut segments = Vec::new();
+    let mut offset = 0;
+    loop {
+        let mut header = [0; SUP_HEADER_SIZE];
+        match reader.read_exact(&mut header) {
+            Ok(()) => {}
+            // A partial header at the end is most likely a cut-off file.
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => break,
+            Err(e) => return Err(e).context(IoSnafu {}),
+        }
+        ensure!(&header[..2] == MAGIC, NotPgsSnafu { offset });
+        // The decoding timestamp in bytes 6..10 is not needed.
+        let pts = u32::from_be_bytes([header[2], header[3], header[4], header[5]]);
+        let kind = header[10];
+        let size = u16::from_be_bytes([header[11], header[12]]) as usize;
+        let mut data = vec![0; size];
+        reader.read_exact(&mut data).map_err(|e| match e.kind() {
+            ErrorKind::UnexpectedEof => TruncatedSnafu { kind }.build(),
+            _ => Error::Io { source: e },
+        })?;
+        segments.push(Segment {
+            seconds: pts as f64 / CLOCK_RATE,
+            kind,
+            data,
+        });
+        offset += (SUP_HEADER_SIZE + size) as u64;
+    }
+    Ok(segments)
+}
+
+/// Split a Matroska block, which holds the segments of one display set
+/// without their `*.sup` headers, into segments.
+pub fn parse_segments(seconds: f64, mut data: &[u8]) -> Result<Vec<Segment>> {
+    let mut segments = Vec::new();
+    while let [kind, size_hi, size_lo, rest @ ..] = data {
+        let size = u16::from_be_bytes([*size_hi, *size_lo]) as usize;
+        let segment = rest.get(..size).context(TruncatedSnafu { kind: *kind })?;
+        segments.push(Segment {
+            seconds,
+            kind: *kind,
+            data: segment.to_vec(),
+        });
+        data = &rest[size..];
+    }
+    Ok(segments)
+}
+
+/// The frame size of the video, from the first composition.
+pub fn frame_size(segments: &[Segment]) -> Option<(u32, u32)> {
+    let composition = segments
+        .iter()
+        .find(|segment| segment.kind == PRESENTATION_COMPOSITION)?;
+    let size = composition.data.get(..4)?;
+    Some((read_u16(size, 0) as u32, read_u16(size, 2) as u32))
+}
+
+/// The number of subtitles in a list of segments, i.e. the number of
+/// compositions which show something new.
+pub fn count_subtitles(segments: &[Segment]) -> usize {
+    segments
+        .iter()
+        .filter(|segment| {
+            segment.kind == PRESENTATION_COMPOSITION
+                && segment
+                    .data
+                    .get(8)
+                    .is_some_and(|&flag| flag & PALETTE_UPDATE == 0)
+                && segment.data.get(10).is_some_and(|&n| n > 0)
+        })
+        .count()
+}
+
+/// Decode the subtitles in a list of segments. A bad display set only loses
+/// its own subtitle, so errors are returned per subtitle.
+pub fn subtitles(segments: &[Segment]) -> Vec<Result<BitmapSubtitle>> {
+    let mut decoder = Decoder::default();
+    for segment in segments {
+        if let Err(e) = decoder.process(segment) {
+            decoder.subtitles.push(Err(e));
+        }
+    }
+    if let Some(mut subtitle) = decoder.showing.take() {
+        subtitle.end_time = subtitle.start_time + DEFAULT_DURATION;
+        decoder.subtitles.push(Ok(subtitle));
+    }
+    decoder.subtitles
+}
+
+/// A graphics object, with its pixels still run-length encoded.
+#[derive(Debug)]
+struct Object {
+    width: u16,
+    height: u16,
+    rle: Vec<u8>,
+}
+
+/// The placement of an object on screen.
+#[derive(Debug)]
+struct CompositionObject {
+    id: u16,
+    x: u16,
+    y: u16,
+    forced: bool,
+    /// The part of the object to show as `(x, y, width, height)`, if not all
+    /// of it.
+    crop: Option<(u16, u16, u16, u16)>,
+}
+
+/// A presentation composition segment, which describes what the display set
+/// shows.
+#[derive(Debug)]
+struct Composition {
+    seconds: f64,
+    /// Whether the composition shows the same objects as before, only with
+    /// a different palette, e.g. to fade them in or out.
+    palette_update: bool,
+    palette_id: u8,
+    objects: Vec<CompositionObject>,
+}
+
+/// The state carried from one display set to the next.
+#[derive(Default)]
+struct Decoder {
+    palettes: HashMap<u8, Vec<[u8; 4]>>,
+    objects: HashMap<u16, Object>,
+    composition: Option<Composition>,
+    /// The subtitle on screen, which ends with the next display set.
+    showing: Option<BitmapSubtitle>,
+    subtitles: Vec<Result<BitmapSubtitle>>,
+}
+
+impl Decoder {
+    fn process(&mut self, segment: &Segment) -> Result<()> {
+        let data = &segment.data;
+        let truncated = || TruncatedSnafu { kind: segment.kind };
+        match segment.kind {
+            PRESENTATION_COMPOSITION => {
+                let header = data.get(..11).context(truncated())?;
+                if header[7] & EPOCH_START != 0 {
+                    self.palettes.clear();
+                    self.objects.clear();
+                }
+                let mut objects = Vec::new();
+                let mut rest = &data[11..];
+                for _ in 0..header[10] {
+                    let entry = rest.get(..8).context(truncated())?;
+                    let crop = if entry[3] & OBJECT_CROPPED != 0 {
+                        let crop = rest.get(8..16).context(truncated())?;
+                        rest = &rest[16..];
+                        Some((
+                            read_u16(crop, 0),
+                            read_u16(crop, 2),
+                            read_u16(crop, 4),
+                            read_u16(crop, 6),
+                        ))
+                    } else {
+                        rest = &rest[8..];
+                        None
+                    };
+                    objects.push(CompositionObject {
+                        id: read_u16(entry, 0),
+                        x: read_u16(entry, 4),
+                        y: read_u16(entry, 6),
+                        forced: entry[3] & OBJECT_FORCED != 0,
+                        crop,
+                    });
+                }
+                self.composition = Some(Composition {
+                    seconds: segment.seconds,
+                    palette_update: header[8] & PALETTE_UPDATE != 0,
+                    palette_id: header[9],
+                    objects,
+                });
+            }
+            PALETTE_DEFINITION => {
+                let id = *data.first().context(truncated())?;
+                let palette = self.palettes.entry(id).or_insert_with(|| vec![[0; 4]; 256]);
+                // Skip the palette version, then read (index, Y, Cr, Cb, alpha)
+                // entries.
+                for entry in data.get(2..).unwrap_or_default().chunks_exact(5) {
+                    let [r, g, b] = ycrcb_to_rgb_bt709(entry[1], entry[2], entry[3]);
+                    palette[entry[0] as usize] = [r, g, b, entry[4]];
+                }
+            }
+            OBJECT_DEFINITION => {
+                let header = data.get(..4).context(truncated())?;
+                let id = read_u16(header, 0);
+                if header[3] & FIRST_IN_SEQUENCE != 0 {
+                    // Skip the 3-byte data length, which we can do without.
+                    let size = data.get(7..11).context(truncated())?;
+                    self.objects.insert(
+                        id,
+                        Object {
+                            width: read_u16(size, 0),
+                            height: read_u16(size, 2),
+                            rle: data[11..].to_vec(),
+                        },
+                    );
+                } else if let Some(object) = self.objects.get_mut(&id) {
+                    object.rle.extend_from_slice(&data[4..]);
+                }
+            }
+            END_OF_DISPLAY_SET => {
+                let composition = match self.composition.take() {
+                    Some(composition) => composition,
+                    None => return Ok(()),
+                };
+                // A new palette for the same objects does not make a new
+                // subtitle; the one on screen just stays up.
+                if composition.palette_update && self.showing.is_some() {
+                    return Ok(());
+                }
+                // Each display set replaces whatever was on screen before.
+                if let Some(mut subtitle) = self.showing.take() {
+                    subtitle.end_time = composition.seconds;
+                    self.subtitles.push(Ok(subtitle));
+                }
+                if !composition.objects.is_empty() {
+                    self.showing = Some(self.render(&composition)?);
+                }
+            }
+            // Window definitions are not needed, since each object carries
+            // its own position.
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// Draw all objects of a composition into a single bitmap covering them.
+    fn render(&self, composition: &Composition) -> Result<BitmapSubtitle> {
+        let palette = self
+            .palettes
+            .get(&composition.palette_id)
+            .context(MissingPaletteSnafu {
+                id: composition.palette_id,
+            })?;
+        // Fill the space between objects with a transparent color.
+        let transparent = palette
+            .iter()
+            .position(|color| color[3] == 0)
+            .unwrap_or(0xff) as u8;
+
+        let mut placed = Vec::new();
+        for placement in &composition.objects {
+            let object = self
+                .objects
+                .get(&placement.id)
+                .context(MissingObjectSnafu { id: placement.id })?;
+            let pixels = decode_rle(object).context(InvalidRleSnafu { id: placement.id })?;
+            let (crop_x, crop_y, width, height) = match placement.crop {
+                Some((x, y, width, height)) => (
+                    x.min(object.width),
+                    y.min(object.height),
+                    width.min(object.width.saturating_sub(x)),
+                    height.min(object.height.saturating_sub(y)),
+                ),
+                None => (0, 0, object.width, object.height),
+            };
+            let area = Rect {
+                x: placement.x as u32,
+                y: placement.y as u32,
+                width: width as u32,
+                height: height as u32,
+            };
+            placed.push((area, object.width as usize, crop_x, crop_y, pixels));
+        }
+
+        let left = placed.iter().map(|(area, ..)| area.x).min().unwrap_or(0);
+        let top = placed.iter().map(|(area, ..)| area.y).min().unwrap_or(0);
+        let right = placed
+            .iter()
+            .map(|(area, ..)| area.x + area.width)
+            .max()
+            .unwrap_or(0);
+        let bottom = placed
+            .iter()
+            .map(|(area, ..)| area.y + area.height)
+            .max()
+            .unwrap_or(0);
+        let width = (right - left) as usize;
+        let height = (bottom - top) as usize;
+
+        let mut canvas = vec![transparent; width * height];
+        for (area, stride, crop_x, crop_y, pixels) in &placed {
+            for y in 0..area.height as usize {
+                let src = (*crop_y as usize + y) * stride + *crop_x as usize;
+                let dst = (area.y - top) as usize + y;
+                let dst = dst * width + (area.x - left) as usize;
+                canvas[dst..dst + area.width as usize]
+                    .copy_from_slice(&pixels[src..src + area.width as usize]);
+            }
+        }
+
+        Ok(BitmapSubtitle {
+            start_time: composition.seconds,
+            end_time: composition.seconds + DEFAULT_DURATION,
+            force: composition.objects.iter().any(|object| object.forced),
+            area: Rect {
+                x: left,
+                y: top,
+                width: width as u32,
+                height: height as u32,
+            },
+            palette: palette.clone(),
+            dvd_roles: false,
+            pixels: canvas,
+        })
+    }
+}
+
+/// Decode the run-length encoded pixels of an object, returning `None` if
+/// they do not fit the object's size.
+fn decode_rle(object: &Object) -> Option<Vec<u8>> {
+    let width = object.width as usize;
+    let size = width * object.height as usize;
+    let mut pixels = Vec::with_capacity(size);
+    let mut data = object.rle.iter().copied();
+    while let Some(byte) = data.next() {
+        let (length, color) = if byte != 0 {
+            (1, byte)
+        } else {
+            let flags = data.next()?;
+            let mut length = (flags & 0x3f) as usize;
+            if flags & 0x40 != 0 {
+                length = length << 8 | data.next()? as usize;
+            }
+            let color = if flags & 0x80 != 0 { data.next()? } else { 0 };
+            if flags == 0 {
+                // End of line; pad short lines out to the full width. An
+                // overlong line has already spilled into the next one, which
+                // is padded instead, and surplus ends of lines do nothing.
+                if width > 0 {
+                    pixels.resize(pixels.len().div_ceil(width) * width, 0);
+                }
+                continue;
+            }
+            (length, color)
+        };
+        if pixels.len() + length > size {
+            return None;
+        }
+        pixels.resize(pixels.len() + length, color);
+    }
+    if pixels.len() < size {
+        warn!("PGS object is shorter than its declared size");
+        pixels.resize(size, 0);
+    }
+    Some(pixels)
+}
+
+fn read_u16(data: &[u8], offset: usize) -> u16 {
+    u16::from_be_bytes([data[offset], data[offset + 1]])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn segment(kind: u8, data: &[u8]) -> Vec<u8> {
+        let mut segment = vec![kind];
+        segment.extend_from_slice(&(data.len() as u16).to_be_bytes());
+        segment.extend_from_slice(data);
+        segment
+    }
+
+    /// A display set showing a 4x2 object at (100, 900) on a 1080p frame.
+    fn display_set() -> Vec<u8> {
+        let composition = [
+            &[
+                0x07,
+                0x80,
+                0x04,
+                0x38,
+                0x10,
+                0x00,
+                0x01,
+                EPOCH_START,
+                0x00,
+                0x00,
+                0x01,
+            ][..],
+            &[0x00, 0x00, 0x00, 0x00, 0x00, 0x64, 0x03, 0x84],
+        ]
+        .concat();
+        // Index 0 is transparent, index 1 opaque white.
+        let palette = [
+            0x00, 0x00, 0x00, 0x10, 0x80, 0x80, 0x00, 0x01, 0xeb, 0x80, 0x80, 0xff,
+        ];
+        let rle = [
+            // Two pixels of color 1 and two of color 0, then end of line.
+            &[0x01, 0x01, 0x00, 0x02, 0x00, 0x00][..],
+            // Four pixels of color 1, then end of line.
+            &[0x00, 0x84, 0x01, 0x00, 0x00],
+        ]
+        .concat();
+        let object = [
+            &[
+                0x00, 0x00, 0x00, 0xc0, 0x00, 0x00, 0x00, 0x00, 0x04, 0x00, 0x02,
+            ][..],
+            &rle,
+        ]
+        .concat();
+        [
+            segment(PRESENTATION_COMPOSITION, &composition),
+            segment(PALETTE_DEFINITION, &palette),
+            segment(OBJECT_DEFINITION, &object),
+            segment(END_OF_DISPLAY_SET, &[]),
+        ]
+        .concat()
+    }
+
+    /// A display set which only changes the palette of what is shown.
+    fn palette_update() -> Vec<u8> {
+        let composition = [
+            &[
+                0x07,
+                0x80,
+                0x04,
+                0x38,
+                0x10,
+                0x00,
+                0x02,
+                0x00,
+                PALETTE_UPDATE,
+                0x00,
+                0x01,
+            ][..],
+            &[0x00, 0x00, 0x00, 0x00, 0x00, 0x64, 0x03, 0x84],
+        ]
+        .concat();
+        // Color 1 half transparent.
+        let palette = [0x00, 0x01, 0x01, 0xeb, 0x80, 0x80, 0x80];
+        [
+            segment(PRESENTATION_COMPOSITION, &composition),
+            segment(PALETTE_DEFINITION, &palette),
+            segment(END_OF_DISPLAY_SET, &[]),
+        ]
+        .concat()
+    }
+
+    /// A display set which clears the screen.
+    fn clear() -> Vec<u8> {
+        let composition = [
+            0x07, 0x80, 0x04, 0x38, 0x10, 0x00, 0x03, 0x00, 0x00, 0x00, 0x00,
+        ];
+        [
+            segment(PRESENTATION_COMPOSITION, &composition),
+            segment(END_OF_DISPLAY_SET, &[]),
+        ]
+        .concat()
+    }
+
+    #[test]
+    fn decodes_display_sets() {
+        let mut segments = parse_segments(1.0, &display_set()).unwrap();
+        segments.extend(parse_segments(2.5, &clear()).unwrap());
+        assert_eq!(frame_size(&segments), Some((1920, 1080)));
+        assert_eq!(count_subtitles(&segments), 1);
+
+        let subtitles = subtitles(&segments);
+        assert_eq!(subtitles.len(), 1);
+        let subtitle = subtitles[0].as_ref().unwrap();
+        assert_eq!(subtitle.start_time, 1.0);
+        assert_eq!(subtitle.end_time, 2.5);
+        assert!(!subtitle.force);
+        assert_eq!(
+            (
+                subtitle.area.x,
+                subtitle.area.y,
+                subtitle.area.width,
+                subtitle.area.height
+            ),
+            (100, 900, 4, 2)
+        );
+        assert_eq!(subtitle.pixels, [1, 1, 0, 0, 1, 1, 1, 1]);
+        assert_eq!(subtitle.palette[0][3], 0);
+        assert_eq!(subtitle.palette[1], [255, 255, 255, 255]);
+    }
+
+    #[test]
+    fn palette_updates_keep_the_subtitle_up() {
+        let mut segments = parse_segments(1.0, &display_set()).unwrap();
+        segments.extend(parse_segments(2.0, &palette_update()).unwrap());
+        segments.extend(parse_segments(2.5, &clear()).unwrap());
+        assert_eq!(count_subtitles(&segments), 1);
+
+        let subtitles = subtitles(&segments);
+        assert_eq!(subtitles.len(), 1);
+        let subtitle = subtitles[0].as_ref().unwrap();
+        assert_eq!(subtitle.start_time, 1.0);
+        assert_eq!(subtitle.end_time, 2.5);
+    }
+
+    #[test]
+    fn rejects_truncated_segments() {
+        let mut data = display_set();
+        data.truncate(data.len() - 5);
+        assert!(matches!(
+            parse_segments(1.0, &data),
+            Err(Error::Truncated {
+                kind: OBJECT_DEFINITION
+            })
+        ));
+
+        // A composition cut short inside its object list.
+        let composition = [
+            0x07, 0x80, 0x04, 0x38, 0x10, 0x00, 0x01, 0x80, 0x00, 0x00, 0x01, 0x00,
+        ];
+        let segments =
+            parse_segments(1.0, &segment(PRESENTATION_COMPOSITION, &composition)).unwrap();
+        let subtitles = subtitles(&segments);
+        assert_eq!(subtitles.len(), 1);
+        assert!(matches!(
+            subtitles[0],
+            Err(Error::Truncated {
+                kind: PRESENTATION_COMPOSITION
+            })
+        ));
+    }
+
+    #[test]
+    fn decodes_run_lengths() {
+        let object = |rle: &[u8]| Object {
+            width: 4,
+            height: 2,
+            rle: rle.to_vec(),
+        };
+        // A long run of color 2 covering both lines.
+        assert_eq!(
+            decode_rle(&object(&[0x00, 0xc0, 0x08, 0x02])),
+            Some(vec![2; 8])
+        );
+        // Lines cut short are padded with color 0.
+        assert_eq!(
+            decode_rle(&object(&[0x03, 0x00, 0x00, 0x03])),
+            Some(vec![3, 0, 0, 0, 3, 0, 0, 0])
+        );
+        // An overlong first line spills into the second, which is padded.
+        assert_eq!(
+            decode_rle(&object(&[0x00, 0x85, 0x01, 0x00, 0x00])),
+            Some(vec![1, 1, 1, 1, 1, 0, 0, 0])
+        );
+        // Surplus ends of lines are ignored.
+        assert_eq!(
+            decode_rle(&object(&[
+                0x00, 0x00, 0x03, 0x00, 0x00, 0x00, 0x00, 0x03, 0x00, 0x00
+            ])),
+            Some(vec![3, 0, 0, 0, 3, 0, 0, 0])
+        );
+        // More pixels than fit the object.
+        assert_eq!(decode_rle(&object(&[0x00, 0x89, 0x01])), None);
+        // A run cut off in the middle.
+        assert_eq!(decode_rle(&object(&[0x00, 0xc0])), None);
+    }
+}
@@ -1,315 +1,534 @@
-use std::{
-    cmp::{max, min},
-    ops::Range,
+use crate::{
+    bitmap::{BitmapSubtitle, Rect},
+    deskew::{estimate_skew, estimate_slant, rotate, shear},
+    opt::Opt,
+    segment::{segment_lines, Line, Mask},
+    upscale::upscale,
 };
-
-use crate::opt::Opt;
-use image::{GrayImage, ImageBuffer, Luma};
-use iter_fixed::IntoIteratorFixed;
-use log::warn;
+use image::{GrayImage, Luma};
+use log::{debug, info};
 use rayon::prelude::*;
+use std::collections::HashMap;
 use subparse::timetypes::{TimePoint, TimeSpan};
 
+/// Lines slanted at least this much are italic. Upright fonts stay well
+/// below it, while italic fonts slant by 0.2 or so.
+const ITALIC_SLANT: f32 = 0.1;
+
+/// Colors whose channels differ by no more than this count as the same text
+/// color.
+const SAME_COLOR_DISTANCE: u8 = 32;
+
+/// How to choose the text colors of DVD subtitles.
+#[derive(Debug, Clone)]
+pub enum TextColors {
+    /// Detect the text role from pixel statistics of the whole stream.
+    Detect,
+    /// These sub-palette roles are text.
+    Roles(Vec<u8>),
+}
+
 pub struct PreprocessedVobSubtitle {
+    /// Index of the subtitle it was made from.
+    pub source: usize,
     pub time_span: TimeSpan,
     pub force: bool,
-    pub images: Vec<GrayImage>,
+    /// Where the subtitle is shown on screen.
+    pub area: Rect,
+    pub lines: Vec<LineImage>,
 }
 
-pub type Result<T, E = vobsub::Error> = std::result::Result<T, E>;
+/// An image of a single line of text, ready for OCR.
+pub struct LineImage {
+    pub image: GrayImage,
+    /// Whether this line starts one of several blocks of text side by side,
+    /// such as the lines of one of two speakers.
+    pub block_start: bool,
+    /// Whether the text is slanted like italics.
+    pub italic: bool,
+    /// The sRGB color of most of the text, if colors are detected and it is
+    /// not the usual color of the stream.
+    pub color: Option<[u8; 3]>,
+}
+
+/// What was learned about a whole stream while preprocessing it, which is
+/// needed to preprocess its subtitles again.
+pub struct StreamStyle {
+    /// Which DVD sub-palette roles are text, if chosen by role.
+    text_roles: Option<Vec<bool>>,
+    /// The color of most lines of text, if colors are detected.
+    usual_color: Option<[u8; 3]>,
+}
 
 /// Return a vector of binarized subtitles.
-pub fn preprocess_subtitles(opt: &Opt) -> Result<Vec<PreprocessedVobSubtitle>> {
-    let idx = vobsub::Index::open(&opt.input)?;
-    let subtitles: Vec<vobsub::Subtitle> = idx
-        .subtitles()
-        .filter_map(|sub| match sub {
-            Ok(sub) => Some(sub),
-            Err(e) => {
-                warn!(
-                    "warning: unable to read subtitle: {}. (This can usually be safely ignored.)",
-                    e
-                );
-                None
-            }
-        })
-        .collect();
-    let palette = rgb_palette_to_luminance(idx.palette());
-    let result = subtitles
+pub fn preprocess_subtitles(
+    subtitles: &[BitmapSubtitle],
+    opt: &Opt,
+) -> (Vec<PreprocessedVobSubtitle>, StreamStyle) {
+    let text_roles: Option<Vec<bool>> = match &opt.text_colors {
+        Some(TextColors::Roles(roles)) => Some((0..4).map(|role| roles.contains(&role)).collect()),
+        Some(TextColors::Detect) => detect_text_roles(subtitles),
+        None => None,
+    };
+    let mut subtitles: Vec<PreprocessedVobSubtitle> = subtitles
         .par_iter()
-        .filter_map(|sub| {
-            subtitle_to_images(sub, &palette, opt.threshold, opt.border).map(|images| {
+        .enumerate()
+        .filter_map(|(source, sub)| {
+            subtitle_to_images(sub, text_roles.as_deref(), opt).map(|lines| {
                 PreprocessedVobSubtitle {
+                    source,
                     time_span: TimeSpan::new(
-                        seconds_to_time_point(sub.start_time()),
-                        seconds_to_time_point(sub.end_time()),
+                        seconds_to_time_point(sub.start_time),
+                        seconds_to_time_point(sub.end_time),
                     ),
-                    force: sub.force(),
-                    images,
+                    force: sub.force,
+                    area: sub.area,
+                    lines,
                 }
             })
         })
         .collect();
-    Ok(result)
+    let usual_color = if opt.font_colors {
+        usual_color(&subtitles)
+    } else {
+        None
+    };
+    if let Some(usual_color) = usual_color {
+        for subtitle in &mut subtitles {
+            forget_color(&mut subtitle.lines, usual_color);
+        }
+    }
+    (
+        subtitles,
+        StreamStyle {
+            text_roles,
+            usual_color,
+        },
+    )
 }
 
-/// Represents the left and right boundaries on a scanline.
-#[derive(Debug)]
-struct ScanlineExtent {
-    left: usize,
-    right: usize,
+/// Preprocess a single subtitle of a stream again, with other options.
+pub fn preprocess_again(
+    subtitle: &BitmapSubtitle,
+    style: &StreamStyle,
+    opt: &Opt,
+) -> Option<Vec<LineImage>> {
+    let mut lines = subtitle_to_images(subtitle, style.text_roles.as_deref(), opt)?;
+    if let Some(usual_color) = style.usual_color {
+        forget_color(&mut lines, usual_color);
+    }
+    Some(lines)
 }
 
-/// Represents a square subregion of an image, x by y.
-#[derive(Debug)]
-struct ImageRegion {
-    x: Range<usize>,
-    y: Range<usize>,
+/// Find the color most lines of text have.
+fn usual_color(subtitles: &[PreprocessedVobSubtitle]) -> Option<[u8; 3]> {
+    let mut counts: HashMap<[u8; 3], usize> = HashMap::new();
+    for line in subtitles.iter().flat_map(|subtitle| &subtitle.lines) {
+        if let Some(color) = line.color {
+            *counts.entry(color).or_default() += 1;
+        }
+    }
+    let (usual, _) = counts.into_iter().max_by_key(|&(_, count)| count)?;
+    info!(
+        "Usual text color is #{:02x}{:02x}{:02x}",
+        usual[0], usual[1], usual[2]
+    );
+    Some(usual)
+}
+
+/// Forget the color of lines close to the usual color, so that only lines
+/// which stand out are marked.
+fn forget_color(lines: &mut [LineImage], usual_color: [u8; 3]) {
+    for line in lines {
+        if let Some(color) = line.color {
+            if color
+                .iter()
+                .zip(&usual_color)
+                .all(|(&a, &b)| a.abs_diff(b) <= SAME_COLOR_DISTANCE)
+            {
+                line.color = None;
+            }
+        }
+    }
+}
+
+/// Find the sRGB color of most of the pixels of a line of text.
+fn dominant_color(subtitle: &BitmapSubtitle, line: &Line) -> Option<[u8; 3]> {
+    let width = subtitle.area.width as usize;
+    let mut counts = vec![0usize; subtitle.palette.len()];
+    for component in &line.components {
+        for &(x, y) in &component.pixels {
+            counts[subtitle.pixels[y * width + x] as usize] += 1;
+        }
+    }
+    let (palette_ix, _) = counts
+        .iter()
+        .enumerate()
+        .filter(|&(_, &count)| count > 0)
+        .max_by_key(|&(_, &count)| count)?;
+    let [r, g, b, _] = subtitle.palette[palette_ix];
+    Some([r, g, b])
 }
 
 fn seconds_to_time_point(seconds: f64) -> TimePoint {
     TimePoint::from_msecs((seconds * 1000.0) as i64)
 }
 
-/// Convert an sRGB palette to a luminance palette.
-fn rgb_palette_to_luminance(palette: &vobsub::Palette) -> [f32; 16] {
-    palette.map(|x| {
-        let r = srgb_to_linear(x[0]);
-        let g = srgb_to_linear(x[1]);
-        let b = srgb_to_linear(x[2]);
-        0.2126 * r + 0.7152 * g + 0.0722 * b
-    })
+/// Convert the palette of a subtitle to a luminance palette. PGS and DVB
+/// subtitles anti-alias through alpha, so their colors are taken as seen over a black
+/// background. DVD subtitles use alpha for whole outlines and backgrounds, so
+/// their luminance ignores it, and only fully transparent colors are left
+/// out, by `generate_visibility_palette`.
+fn palette_to_luminance(subtitle: &BitmapSubtitle) -> Vec<f32> {
+    subtitle
+        .palette
+        .iter()
+        .map(|x| {
+            let r = srgb_to_linear(x[0]);
+            let g = srgb_to_linear(x[1]);
+            let b = srgb_to_linear(x[2]);
+            let luminance = 0.2126 * r + 0.7152 * g + 0.0722 * b;
+            if subtitle.dvd_roles {
+                luminance
+            } else {
+                luminance * x[3] as f32 / 255.0
+            }
+        })
+        .collect()
 }
 
 /// Given a subtitle, binarize, invert, and split the image into multiple lines
-/// with borders for direct feeding into Tesseract.
+/// with borders for direct feeding into Tesseract. Each line image only holds
+/// the glyphs of its own line, even where lines overlap.
 fn subtitle_to_images(
-    subtitle: &vobsub::Subtitle,
-    palette: &[f32; 16],
-    threshold: f32,
-    border: u32,
-) -> Option<Vec<GrayImage>> {
-    let sub_palette_visibility = generate_visibility_palette(subtitle);
-
-    let binarized_palette = binarize_palette(
-        palette,
-        subtitle.palette(),
-        &sub_palette_visibility,
-        threshold,
-    );
+    subtitle: &BitmapSubtitle,
+    text_roles: Option<&[bool]>,
+    opt: &Opt,
+) -> Option<Vec<LineImage>> {
+    let palette = palette_to_luminance(subtitle);
+    let palette_usage = count_palette_usage(subtitle);
+    let palette_visibility = generate_visibility_palette(subtitle, &palette_usage);
+
+    let binarized_palette = match text_roles {
+        Some(text_roles) if subtitle.dvd_roles => text_roles
+            .iter()
+            .zip(&palette_visibility)
+            .map(|(&text, &visible)| text && visible)
+            .collect(),
+        _ => binarize_subtitle_palette(
+            subtitle,
+            &palette,
+            &palette_visibility,
+            &palette_usage,
+            opt.threshold,
+        ),
+    };
 
-    let scanlines = inventory_scanlines(subtitle, &binarized_palette);
-    let scanline_groups = find_contiguous_scanline_groups(&scanlines);
-    if scanline_groups.is_empty() {
+    let mask = Mask {
+        width: subtitle.area.width as usize,
+        height: subtitle.area.height as usize,
+        pixels: subtitle
+            .pixels
+            .iter()
+            .map(|&palette_ix| binarized_palette[palette_ix as usize])
+            .collect(),
+    };
+    let lines = segment_lines(&mask, opt.speck_size);
+    if lines.is_empty() {
         // No images found.
         return None;
     }
 
-    let image_regions = scanline_groups_to_image_regions(&scanlines, &scanline_groups);
-
-    let raw_image_width = subtitle.coordinates().width() as u32;
-
     Some(
-        image_regions
+        lines
             .into_par_iter()
-            .map(|region| {
-                let x0 = region.x.start as u32;
-                let y0 = region.y.start as u32;
-                let width = region.x.len() as u32;
-                let height = region.y.len() as u32;
-                ImageBuffer::from_fn(width + border * 2, height + border * 2, |x, y| {
-                    if x < border || x >= width + border || y < border || y >= height + border {
-                        Luma([255])
-                    } else {
-                        let offset = (y0 + (y - border)) * raw_image_width + x0 + (x - border);
-                        let sub_palette_ix = subtitle.raw_image()[offset as usize] as usize;
-                        if binarized_palette[sub_palette_ix] {
-                            Luma([0])
-                        } else {
-                            Luma([255])
-                        }
+            .map(|line| {
+                let (x, y) = line.bounds();
+                let mut image = GrayImage::from_pixel(x.len() as u32, y.len() as u32, Luma([255]));
+                for component in &line.components {
+                    for &(px, py) in &component.pixels {
+                        image.put_pixel((px - x.start) as u32, (py - y.start) as u32, Luma([0]));
                     }
-                })
+                }
+                if opt.deskew {
+                    let skew = estimate_skew(&image);
+                    debug!("Rotating line by {:.1}°", skew.to_degrees());
+                    image = rotate(&image, skew);
+                }
+                let slant = estimate_slant(&image);
+                if opt.deslant {
+                    image = shear(&image, slant);
+                }
+                let color = if opt.font_colors {
+                    dominant_color(subtitle, &line)
+                } else {
+                    None
+                };
+                let image = upscale(image, opt.upscale, line.x_height() as u32, opt.x_height);
+                LineImage {
+                    image: add_border(&image, opt.border),
+                    block_start: line.block_start,
+                    italic: slant >= ITALIC_SLANT,
+                    color,
+                }
             })
             .collect(),
     )
 }
 
-/// Find all the palette indices used in this image, and filter out the
-/// transparent ones. Checking each and every single pixel in the image like
-/// this is probably not strictly necessary, but it could theoretically catch an
-/// edge case.
-fn generate_visibility_palette(subtitle: &vobsub::Subtitle) -> [bool; 4] {
-    let mut sub_palette_visibility = subtitle
-        .raw_image()
+/// Surround an image with a white border.
+fn add_border(image: &GrayImage, border: u32) -> GrayImage {
+    let mut bordered = GrayImage::from_pixel(
+        image.width() + border * 2,
+        image.height() + border * 2,
+        Luma([255]),
+    );
+    image::imageops::replace(&mut bordered, image, border as i64, border as i64);
+    bordered
+}
+
+/// Binarize the palette of a subtitle by luminance. With a given threshold,
+/// the colors above it are text. Otherwise a threshold is chosen for this
+/// subtitle, and the text is whichever side of it touches transparent
+/// pixels less, as the outline surrounds the text.
+fn binarize_subtitle_palette(
+    subtitle: &BitmapSubtitle,
+    palette: &[f32],
+    palette_visibility: &[bool],
+    palette_usage: &[usize],
+    threshold: Option<f32>,
+) -> Vec<bool> {
+    let (threshold, bright_text) = match threshold {
+        Some(threshold) => (threshold, true),
+        None => {
+            let threshold = automatic_threshold(palette, palette_visibility, palette_usage);
+            let bright_text = bright_text(subtitle, palette, palette_visibility, threshold);
+            debug!(
+                "Subtitle at {:.3}s: chose threshold {:.3} with {} text for colors (relative luminance, pixels) {:?}",
+                subtitle.start_time,
+                threshold,
+                if bright_text { "bright" } else { "dark" },
+                relative_luminances(palette, palette_visibility)
+                    .zip(palette_usage)
+                    .filter_map(|(luminance, &count)| luminance.map(|luminance| (luminance, count)))
+                    .collect::<Vec<_>>()
+            );
+            (threshold, bright_text)
+        }
+    };
+    binarize_palette(palette, palette_visibility, threshold, bright_text)
+}
+
+/// Count the pixels of each palette index in this image.
+fn count_palette_usage(subtitle: &BitmapSubtitle) -> Vec<usize> {
+    let palette_len = subtitle.palette.len();
+    subtitle
+        .pixels
         .par_iter()
         .fold(
-            || [false; 4],
-            |mut visible: [bool; 4], &sub_palette_ix| {
-                visible[sub_palette_ix as usize] = true;
-                visible
+            || vec![0; palette_len],
+            |mut counts: Vec<usize>, &palette_ix| {
+                counts[palette_ix as usize] += 1;
+                counts
             },
         )
         .reduce(
-            || [false; 4],
-            |mut a: [bool; 4], b: [bool; 4]| {
-                a[0] = a[0] || b[0];
-                a[1] = a[1] || b[1];
-                a[2] = a[2] || b[2];
-                a[3] = a[3] || b[3];
+            || vec![0; palette_len],
+            |mut a: Vec<usize>, b: Vec<usize>| {
+                for (a, b) in a.iter_mut().zip(b) {
+                    *a += b;
+                }
                 a
             },
-        );
-    // The alpha palette is reversed.
-    for (i, &alpha) in subtitle.alpha().iter().rev().enumerate() {
-        if alpha == 0 {
-            sub_palette_visibility[i] = false;
-        }
-    }
-    sub_palette_visibility
+        )
 }
 
-/// Generate a binarized palette where `true` represents a filled text pixel.
-fn binarize_palette(
-    palette: &[f32; 16],
-    sub_palette: &[u8; 4],
-    sub_palette_visibility: &[bool; 4],
-    threshold: f32,
-) -> [bool; 4] {
-    // Find the max luminance, so we can scale each luminance value by it.
-    // Reminder that the sub palette is reversed.
-    let mut max_luminance = 0.0;
-    for (&palette_ix, &visible) in sub_palette.iter().rev().zip(sub_palette_visibility) {
-        if visible {
-            let luminance = palette[palette_ix as usize];
-            if luminance > max_luminance {
-                max_luminance = luminance;
-            }
-        }
-    }
-
-    // Empty image?
-    if max_luminance == 0.0 {
-        return [false; 4];
-    }
-
-    sub_palette
-        .into_iter_fixed()
-        .rev()
-        .zip(sub_palette_visibility)
-        .map(|(&palette_ix, &visible)| {
-            if visible {
-                let luminance = palette[palette_ix as usize] / max_luminance;
-                luminance > threshold
-            } else {
-                false
-            }
-        })
+/// Find all the palette indices used in this image, and filter out the
+/// transparent ones. Checking each and every single pixel in the image like
+/// this is probably not strictly necessary, but it could theoretically catch an
+/// edge case.
+fn generate_visibility_palette(subtitle: &BitmapSubtitle, palette_usage: &[usize]) -> Vec<bool> {
+    palette_usage
+        .iter()
+        .zip(&subtitle.palette)
+        .map(|(&count, color)| count > 0 && color[3] != 0)
         .collect()
 }
 
-/// Inventory each scanline of the image, recording if a given scanline has
-/// text pixels, and if it does, the left and right extents of the pixels on
-/// the scanline.
-fn inventory_scanlines(
-    subtitle: &vobsub::Subtitle,
-    palette: &[bool; 4],
-) -> Vec<Option<ScanlineExtent>> {
-    let width = subtitle.coordinates().width() as usize;
-    let height = subtitle.coordinates().height() as usize;
-    (0..height)
-        .into_par_iter()
-        .map(|y| {
-            (0..width)
-                .into_par_iter()
-                .fold(
-                    || None,
-                    |scanline: Option<ScanlineExtent>, x| {
-                        let offset = y * width + x;
-                        let palette_ix = subtitle.raw_image()[offset] as usize;
-                        if palette[palette_ix] {
-                            match scanline {
-                                Some(extent) => Some(ScanlineExtent {
-                                    left: min(x, extent.left),
-                                    right: max(x, extent.right),
-                                }),
-                                None => Some(ScanlineExtent { left: x, right: x }),
-                            }
-                        } else {
-                            scanline
-                        }
-                    },
-                )
-                .reduce(
-                    || None,
-                    |a: Option<ScanlineExtent>, b: Option<ScanlineExtent>| match a {
-                        Some(extent_a) => match b {
-                            Some(extent_b) => Some(ScanlineExtent {
-                                left: min(extent_a.left, extent_b.left),
-                                right: max(extent_a.right, extent_b.right),
-                            }),
-                            None => Some(extent_a),
-                        },
-                        None => b,
-                    },
-                )
+/// Scale the luminance of each visible palette entry by the maximum, so that
+/// the brightest color has a luminance of 1. Invisible entries, and all
+/// entries of an empty image, are `None`.
+fn relative_luminances<'a>(
+    palette: &'a [f32],
+    palette_visibility: &'a [bool],
+) -> impl Iterator<Item = Option<f32>> + 'a {
+    let max_luminance = palette
+        .iter()
+        .zip(palette_visibility)
+        .filter(|(_, &visible)| visible)
+        .map(|(&luminance, _)| luminance)
+        .fold(0.0, f32::max);
+    palette
+        .iter()
+        .zip(palette_visibility)
+        .map(move |(&luminance, &visible)| {
+            (visible && max_luminance > 0.0).then(|| luminance / max_luminance)
         })
-        .collect()
 }
 
-/// Find ranges of contiguous, filled scanlines.
-fn find_contiguous_scanline_groups(scanlines: &[Option<ScanlineExtent>]) -> Vec<Range<usize>> {
-    let mut scanline_groups: Vec<Range<usize>> = Vec::new();
-    let mut scanline_ix = 0;
-    while scanline_ix < scanlines.len() {
-        // Find the start of the next range of contiguous scanlines.
-        match scanlines.iter().skip(scanline_ix).position(|x| x.is_some()) {
-            Some(start_ix_offset) => {
-                // Find the end of this range.
-                let end_ix = match scanlines
-                    .iter()
-                    .skip(scanline_ix + start_ix_offset)
-                    .position(|x| x.is_none())
-                {
-                    Some(end_ix_offset) => scanline_ix + start_ix_offset + end_ix_offset,
-                    None => scanlines.len(),
-                };
-                scanline_groups.push((scanline_ix + start_ix_offset)..end_ix);
-                scanline_ix = end_ix;
-            }
-            None => break,
+/// Choose a threshold which splits the visible colors of a subtitle into a
+/// dark and a bright group, using Otsu's method: the split between
+/// luminances that maximizes the variance between the two groups, weighted
+/// by their pixel counts. This only separates text from outline; which of
+/// the two is the text is up to `bright_text`.
+fn automatic_threshold(
+    palette: &[f32],
+    palette_visibility: &[bool],
+    palette_usage: &[usize],
+) -> f32 {
+    let mut colors: Vec<(f32, f32)> = relative_luminances(palette, palette_visibility)
+        .zip(palette_usage)
+        .filter_map(|(luminance, &count)| luminance.map(|luminance| (luminance, count as f32)))
+        .collect();
+    colors.sort_by(|a, b| a.0.total_cmp(&b.0));
+
+    let total: f32 = colors.iter().map(|&(_, count)| count).sum();
+    let total_luminance: f32 = colors
+        .iter()
+        .map(|&(luminance, count)| luminance * count)
+        .sum();
+    let mut best = (0.0, 0.0);
+    let mut dark = 0.0;
+    let mut dark_luminance = 0.0;
+    for pair in colors.windows(2) {
+        let (luminance, count) = pair[0];
+        dark += count;
+        dark_luminance += luminance * count;
+        if pair[1].0 == luminance {
+            continue;
+        }
+        let bright = total - dark;
+        let mean_difference = dark_luminance / dark - (total_luminance - dark_luminance) / bright;
+        let variance = dark * bright * mean_difference * mean_difference;
+        if variance > best.0 {
+            best = (variance, (luminance + pair[1].0) / 2.0);
         }
     }
-    scanline_groups
+    // With a single color, every visible pixel is text.
+    best.1
 }
 
-/// Given the list of scanlines and a list of contiguous groups, calculate image regions that
-/// encompass the extents.
-fn scanline_groups_to_image_regions(
-    scanlines: &[Option<ScanlineExtent>],
-    scanline_groups: &[Range<usize>],
-) -> Vec<ImageRegion> {
-    scanline_groups
-        .iter()
-        .map(|y_range| {
-            let mut left = usize::MAX;
-            let mut right = usize::MIN;
-            for y in y_range.clone() {
-                // Unwrap here, since we should have filtered out all None
-                // scanlines before calling this.
-                let x = scanlines[y].as_ref().unwrap();
-                if x.left < left {
-                    left = x.left;
-                }
-                if x.right > right {
-                    right = x.right;
+/// Whether the text of a subtitle is the group of colors above the threshold
+/// rather than the one below it. Text is drawn inside its outline, so it is
+/// the group whose pixels less often touch transparent pixels. If either
+/// group has no such pixels to tell by, the brighter one is text.
+fn bright_text(
+    subtitle: &BitmapSubtitle,
+    palette: &[f32],
+    palette_visibility: &[bool],
+    threshold: f32,
+) -> bool {
+    let (pixels, edges) = edge_statistics(subtitle);
+    let luminances: Vec<Option<f32>> = relative_luminances(palette, palette_visibility).collect();
+    let edge_ratio = |bright: bool| {
+        let (pixels, edges) = luminances
+            .iter()
+            .zip(pixels.iter().zip(&edges))
+            .filter(|(luminance, _)| luminance.is_some_and(|l| (l > threshold) == bright))
+            .fold((0, 0), |(a, b), (_, (&pixels, &edges))| {
+                (a + pixels, b + edges)
+            });
+        (pixels > 0).then(|| edges as f32 / pixels as f32)
+    };
+    match (edge_ratio(true), edge_ratio(false)) {
+        (Some(bright), Some(dark)) => bright <= dark,
+        _ => true,
+    }
+}
+
+/// Detect which DVD sub-palette role is the text over all subtitles of a
+/// stream. Text is drawn inside its outline, so of the roles other than the
+/// background, it is the one whose pixels least often touch the background.
+fn detect_text_roles(subtitles: &[BitmapSubtitle]) -> Option<Vec<bool>> {
+    let (pixels, edges) = subtitles
+        .par_iter()
+        .filter(|subtitle| subtitle.dvd_roles)
+        .map(edge_statistics)
+        .reduce(
+            || (vec![0; 4], vec![0; 4]),
+            |mut a, b| {
+                for role in 0..4 {
+                    a.0[role] += b.0[role];
+                    a.1[role] += b.1[role];
                 }
+                a
+            },
+        );
+    let edge_ratios: Vec<Option<f32>> = pixels
+        .iter()
+        .zip(&edges)
+        .map(|(&pixels, &edges)| (pixels > 0).then(|| edges as f32 / pixels as f32))
+        .collect();
+    let text_role = (1..4)
+        .filter_map(|role| edge_ratios[role].map(|ratio| (role, ratio)))
+        .min_by(|a, b| a.1.total_cmp(&b.1))
+        .map(|(role, _)| role);
+    match text_role {
+        Some(text_role) => info!(
+            "Detected sub-palette role {} as text (pixels per role {:?}, ratio touching the background {:?})",
+            text_role, pixels, edge_ratios
+        ),
+        None => info!("Could not detect a text sub-palette role; choosing text colors by luminance"),
+    }
+    text_role.map(|text_role| (0..4).map(|role| role == text_role).collect())
+}
+
+/// Count the visible pixels of each palette entry in a subtitle, and how
+/// many of them are next to a transparent pixel, the background role of a
+/// DVD subtitle, or the edge of the image.
+fn edge_statistics(subtitle: &BitmapSubtitle) -> (Vec<usize>, Vec<usize>) {
+    let width = subtitle.area.width as usize;
+    let height = subtitle.area.height as usize;
+    let background = |x: usize, y: usize| {
+        let palette_ix = subtitle.pixels[y * width + x] as usize;
+        (subtitle.dvd_roles && palette_ix == 0) || subtitle.palette[palette_ix][3] == 0
+    };
+    let mut pixels = vec![0; subtitle.palette.len()];
+    let mut edges = vec![0; subtitle.palette.len()];
+    for y in 0..height {
+        for x in 0..width {
+            if background(x, y) {
+                continue;
             }
-            ImageRegion {
-                x: left..right + 1,
-                y: y_range.clone(),
+            let palette_ix = subtitle.pixels[y * width + x] as usize;
+            pixels[palette_ix] += 1;
+            if x == 0
+                || y == 0
+                || x + 1 == width
+                || y + 1 == height
+                || background(x - 1, y)
+                || background(x + 1, y)
+                || background(x, y - 1)
+                || background(x, y + 1)
+            {
+                edges[palette_ix] += 1;
             }
-        })
+        }
+    }
+    (pixels, edges)
+}
+
+/// Generate a binarized palette where `true` represents a filled text pixel:
+/// the visible colors above the threshold if the text is bright, or else
+/// those below it.
+fn binarize_palette(
+    palette: &[f32],
+    palette_visibility: &[bool],
+    threshold: f32,
+    bright_text: bool,
+) -> Vec<bool> {
+    relative_luminances(palette, palette_visibility)
+        .map(|luminance| luminance.is_some_and(|luminance| (luminance > threshold) == bright_text))
         .collect()
 }
 
@@ -322,3 +541,174 @@ fn srgb_to_linear(channel: u8) -> f32 {
         ((value + 0.055) / 1.055).powf(2.4)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TRANSPARENT: [u8; 4] = [0, 0, 0, 0];
+    const BLACK: [u8; 4] = [0, 0, 0, 255];
+    const WHITE: [u8; 4] = [255, 255, 255, 255];
+
+    /// A subtitle drawn with one character per pixel, each a digit for its
+    /// palette index.
+    fn subtitle(palette: &[[u8; 4]], rows: &[&str]) -> BitmapSubtitle {
+        BitmapSubtitle {
+            start_time: 0.0,
+            end_time: 1.0,
+            force: false,
+            area: Rect {
+                x: 0,
+                y: 0,
+                width: rows[0].len() as u32,
+                height: rows.len() as u32,
+            },
+            palette: palette.to_vec(),
+            dvd_roles: false,
+            pixels: rows
+                .iter()
+                .flat_map(|row| row.bytes().map(|b| b - b'0'))
+                .collect(),
+        }
+    }
+
+    /// A 3x2 block of color 2 with a 1-pixel outline of color 1.
+    const OUTLINED: &[&str] = &[
+        "0000000", //
+        "0111110", //
+        "0122210", //
+        "0122210", //
+        "0111110", //
+        "0000000", //
+    ];
+
+    /// Binarize a subtitle with an automatic threshold, the way
+    /// `subtitle_to_images` does.
+    fn binarize(subtitle: &BitmapSubtitle) -> Vec<bool> {
+        let palette = palette_to_luminance(subtitle);
+        let palette_usage = count_palette_usage(subtitle);
+        let palette_visibility = generate_visibility_palette(subtitle, &palette_usage);
+        let threshold = automatic_threshold(&palette, &palette_visibility, &palette_usage);
+        let bright_text = bright_text(subtitle, &palette, &palette_visibility, threshold);
+        binarize_palette(&palette, &palette_visibility, threshold, bright_text)
+    }
+
+    #[test]
+    fn counts_edge_pixels() {
+        let subtitle = subtitle(&[TRANSPARENT, BLACK, WHITE], OUTLINED);
+        assert_eq!(edge_statistics(&subtitle), (vec![0, 14, 6], vec![0, 14, 0]));
+    }
+
+    #[test]
+    fn finds_white_text_on_black_outline() {
+        let subtitle = subtitle(&[TRANSPARENT, BLACK, WHITE], OUTLINED);
+        let palette = palette_to_luminance(&subtitle);
+        let palette_usage = count_palette_usage(&subtitle);
+        let palette_visibility = generate_visibility_palette(&subtitle, &palette_usage);
+        assert_eq!(palette_visibility, [false, true, true]);
+        let threshold = automatic_threshold(&palette, &palette_visibility, &palette_usage);
+        assert_eq!(threshold, 0.5);
+        assert!(bright_text(
+            &subtitle,
+            &palette,
+            &palette_visibility,
+            threshold
+        ));
+        assert_eq!(binarize(&subtitle), [false, false, true]);
+    }
+
+    #[test]
+    fn finds_dark_text_on_light_outline() {
+        let subtitle = subtitle(&[TRANSPARENT, WHITE, BLACK], OUTLINED);
+        assert_eq!(binarize(&subtitle), [false, false, true]);
+    }
+
+    #[test]
+    fn takes_a_single_color_as_text() {
+        let subtitle = subtitle(&[TRANSPARENT, WHITE], &["0110", "0110"]);
+        let palette = palette_to_luminance(&subtitle);
+        let palette_usage = count_palette_usage(&subtitle);
+        let palette_visibility = generate_visibility_palette(&subtitle, &palette_usage);
+        assert_eq!(
+            automatic_threshold(&palette, &palette_visibility, &palette_usage),
+            0.0
+        );
+        assert_eq!(binarize(&subtitle), [false, true]);
+    }
+
+    #[test]
+    fn detects_dvd_text_roles() {
+        // Emphasis 2 is the text, inside an emphasis 1 outline, with a
+        // pattern-colored shadow.
+        let mut subtitle = subtitle(
+            &[TRANSPARENT, BLACK, BLACK, WHITE],
+            &[
+                "0000000", //
+                "0222220", //
+                "0233320", //
+                "0233320", //
+                "0222221", //
+                "0011111", //
+            ],
+        );
+        assert_eq!(detect_text_roles(std::slice::from_ref(&subtitle)), None);
+        subtitle.dvd_roles = true;
+        assert_eq!(
+            detect_text_roles(&[subtitle]),
+            Some(vec![false, false, false, true])
+        );
+    }
+
+    /// An image of three 2x20 strokes, each leaning right by `slant` pixels
+    /// per pixel of height.
+    fn strokes(slant: f32) -> GrayImage {
+        let mut image = GrayImage::from_pixel(40, 20, Luma([255]));
+        for y in 0..20 {
+            let shift = (slant * (20 - y) as f32).round() as u32;
+            for stroke in 0..3 {
+                let x = 4 + stroke * 10 + shift;
+                image.put_pixel(x, y, Luma([0]));
+                image.put_pixel(x + 1, y, Luma([0]));
+            }
+        }
+        image
+    }
+
+    #[test]
+    fn tells_italics_from_upright_text() {
+        assert!(estimate_slant(&strokes(0.0)) < ITALIC_SLANT);
+        assert!(estimate_slant(&strokes(0.25)) >= ITALIC_SLANT);
+    }
+    #[test]
+    fn forgets_the_usual_color() {
+        let line = |color| LineImage {
+            image: GrayImage::new(1, 1),
+            block_start: false,
+            italic: false,
+            color: Some(color),
+        };
+        let mut subtitles = vec![PreprocessedVobSubtitle {
+            source: 0,
+            time_span: TimeSpan::new(TimePoint::from_msecs(0), TimePoint::from_msecs(1000)),
+            force: false,
+            area: Rect {
+                x: 0,
+                y: 0,
+                width: 1,
+                height: 1,
+            },
+            lines: vec![
+                line([255, 255, 255]),
+                line([250, 250, 240]),
+                line([255, 255, 0]),
+                line([255, 255, 255]),
+            ],
+        }];
+        let usual = usual_color(&subtitles);
+        assert_eq!(usual, Some([255, 255, 255]));
+        forget_color(&mut subtitles[0].lines, usual.unwrap());
+        let colors: Vec<Option<[u8; 3]>> =
+            subtitles[0].lines.iter().map(|line| line.color).collect();
+        assert_eq!(colors, [None, None, Some([255, 255, 0]), None]);
+    }
+}
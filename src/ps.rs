@@ -0,0 +1,395 @@
+//! Just enough MPEG-2 Program Stream support to get DVD subpictures in and
+//! out of the `vobsub` crate, which only knows how to decode subtitles from a
+//! `*.sub` program stream containing nothing but a single subtitle stream.
+
+use log::{trace, warn};
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{self, BufRead, ErrorKind, Read},
+    ops::RangeInclusive,
+    path::Path,
+};
+
+const PACK_START: u8 = 0xba;
+const PROGRAM_END: u8 = 0xb9;
+/// Private stream 1, which carries DVD subpictures.
+const PRIVATE_STREAM_1: u8 = 0xbd;
+/// Stream IDs of MPEG video streams.
+const VIDEO_STREAMS: RangeInclusive<u8> = 0xe0..=0xef;
+/// Private stream 1 substream IDs of subpicture streams.
+const SUBPICTURE_STREAMS: RangeInclusive<u8> = 0x20..=0x3f;
+
+/// The maximum payload of a single PES packet, after the PES header, the PTS
+/// and the substream ID.
+const MAX_PES_PAYLOAD: usize = 0xffff - 3 - 5 - 1;
+
+/// The MPEG system clock runs at 90kHz.
+const CLOCK_RATE: f64 = 90_000.0;
+
+/// Check for a pack start code at the start of the file.
+pub fn is_program_stream<P: AsRef<Path>>(path: P) -> io::Result<bool> {
+    let mut magic = [0; 4];
+    match File::open(path)?.read_exact(&mut magic) {
+        Ok(()) => Ok(magic == [0x00, 0x00, 0x01, PACK_START]),
+        Err(e) if e.kind() == ErrorKind::UnexpectedEof => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+/// A complete subpicture unit demultiplexed from a program stream.
+#[derive(Debug)]
+pub struct Subpicture {
+    pub substream_id: u8,
+    /// Presentation timestamp in seconds, relative to the start of the video.
+    pub seconds: f64,
+    pub data: Vec<u8>,
+}
+
+/// A subpicture unit which is still being reassembled from PES packets.
+struct PartialSubpicture {
+    /// Presentation timestamp on the continuous timeline of the stream.
+    pts: i64,
+    data: Vec<u8>,
+}
+
+/// Keeps the timestamps of a program stream on one continuous timeline. The
+/// system clock may start over at the cells and VOB files of a title set, so
+/// when it jumps back, the timestamps after the jump are moved to carry on
+/// from before it. Jumps ahead are left alone, since packs can be far apart
+/// where nothing else is multiplexed between them.
+#[derive(Default)]
+struct Timeline {
+    last_scr: Option<u64>,
+    /// What to add to timestamps to move them onto the timeline.
+    offset: i64,
+}
+
+impl Timeline {
+    /// Follow the system clock reference of a pack.
+    fn pack(&mut self, scr: u64) {
+        if let Some(last_scr) = self.last_scr {
+            if scr < last_scr {
+                trace!("System clock discontinuity from {} to {}", last_scr, scr);
+                self.offset += last_scr as i64 - scr as i64;
+            }
+        }
+        self.last_scr = Some(scr);
+    }
+
+    /// Move a timestamp of the current pack onto the timeline.
+    fn timestamp(&self, pts: u64) -> i64 {
+        pts as i64 + self.offset
+    }
+}
+
+/// Demultiplex all subpicture units from a program stream such as a `*.vob`
+/// file, or the VOB files of a title set read one after another.
+///
+/// With `follow_clock`, timestamps are made relative to the first video
+/// frame, so that they line up with the video once it is remuxed, and carry
+/// on where the system clock starts over. Otherwise they are the raw
+/// presentation timestamps, which is what the subtitles of a `*.sub` file
+/// are timed by.
+pub fn demux_subpictures<R: BufRead>(
+    mut reader: R,
+    follow_clock: bool,
+) -> io::Result<Vec<Subpicture>> {
+    let mut timeline = Timeline::default();
+    let mut video_start: Option<i64> = None;
+    let mut partials: HashMap<u8, PartialSubpicture> = HashMap::new();
+    let mut complete: Vec<(u8, PartialSubpicture)> = Vec::new();
+    while let Some(stream_id) = next_start_code(&mut reader)? {
+        match stream_id {
+            PACK_START => {
+                let scr = read_pack_header(&mut reader)?;
+                if follow_clock {
+                    timeline.pack(scr);
+                }
+            }
+            PROGRAM_END => {}
+            // Everything else from the system header on is a packet with a
+            // length.
+            0xbb..=0xff => {
+                let mut len = [0; 2];
+                reader.read_exact(&mut len)?;
+                let len = u16::from_be_bytes(len) as usize;
+                let wanted = stream_id == PRIVATE_STREAM_1
+                    || (follow_clock
+                        && video_start.is_none()
+                        && VIDEO_STREAMS.contains(&stream_id));
+                if !wanted {
+                    io::copy(&mut (&mut reader).take(len as u64), &mut io::sink())?;
+                    continue;
+                }
+                let mut packet = vec![0; len];
+                reader.read_exact(&mut packet)?;
+                let (pts, payload) = match parse_pes_packet(&packet) {
+                    Some(parsed) => parsed,
+                    None => {
+                        trace!("Skipping unparseable packet of stream 0x{:x}", stream_id);
+                        continue;
+                    }
+                };
+                let pts = pts.map(|pts| timeline.timestamp(pts));
+                if stream_id != PRIVATE_STREAM_1 {
+                    video_start = video_start.or(pts);
+                    continue;
+                }
+                let (&substream_id, payload) = match payload.split_first() {
+                    Some(split) if SUBPICTURE_STREAMS.contains(split.0) => split,
+                    _ => continue,
+                };
+                match pts {
+                    Some(pts) => {
+                        if partials.contains_key(&substream_id) {
+                            warn!(
+                                "Discarding incomplete subpicture in stream 0x{:x}",
+                                substream_id
+                            );
+                        }
+                        partials.insert(
+                            substream_id,
+                            PartialSubpicture {
+                                pts,
+                                data: payload.to_vec(),
+                            },
+                        );
+                    }
+                    None => match partials.get_mut(&substream_id) {
+                        Some(partial) => partial.data.extend_from_slice(payload),
+                        None => continue,
+                    },
+                }
+                // Subpicture units start with their total size.
+                let partial = &partials[&substream_id];
+                if partial.data.len() >= 2
+                    && partial.data.len()
+                        >= u16::from_be_bytes([partial.data[0], partial.data[1]]) as usize
+                {
+                    let partial = partials.remove(&substream_id).unwrap();
+                    complete.push((substream_id, partial));
+                }
+            }
+            _ => {}
+        }
+    }
+
+    let start = video_start.unwrap_or(0);
+    Ok(complete
+        .into_iter()
+        .map(|(substream_id, partial)| Subpicture {
+            substream_id,
+            seconds: (partial.pts - start) as f64 / CLOCK_RATE,
+            data: partial.data,
+        })
+        .collect())
+}
+
+/// Find the next start code, returning its stream ID, or `None` at the end of
+/// the stream.
+fn next_start_code<R: BufRead>(reader: &mut R) -> io::Result<Option<u8>> {
+    // The number of zero bytes we have just seen.
+    let mut zeros = 0;
+    loop {
+        let mut byte = [0];
+        match reader.read_exact(&mut byte) {
+            Ok(()) => {}
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(e),
+        }
+        match byte[0] {
+            0x00 => zeros += 1,
+            0x01 if zeros >= 2 => {
+                reader.read_exact(&mut byte)?;
+                return Ok(Some(byte[0]));
+            }
+            _ => zeros = 0,
+        }
+    }
+}
+
+/// Read the rest of a pack header, after its start code, returning the base
+/// of its system clock reference.
+fn read_pack_header<R: Read>(reader: &mut R) -> io::Result<u64> {
+    let mut header = [0; 10];
+    reader.read_exact(&mut header[..8])?;
+    if header[0] & 0xc0 == 0x40 {
+        // MPEG-2 pack headers are longer and may be followed by stuffing.
+        reader.read_exact(&mut header[8..])?;
+        let stuffing = (header[9] & 0x07) as u64;
+        io::copy(&mut reader.take(stuffing), &mut io::sink())?;
+        Ok((header[0] as u64 >> 3 & 0x07) << 30
+            | (header[0] as u64 & 0x03) << 28
+            | (header[1] as u64) << 20
+            | (header[2] as u64 >> 3) << 15
+            | (header[2] as u64 & 0x03) << 13
+            | (header[3] as u64) << 5
+            | header[4] as u64 >> 3)
+    } else {
+        // MPEG-1 packs store it like a PTS.
+        Ok(read_timestamp(&header[..5]))
+    }
+}
+
+/// Parse an MPEG-2 PES packet after its length, returning the PTS, if any,
+/// and the payload.
+pub fn parse_pes_packet(packet: &[u8]) -> Option<(Option<u64>, &[u8])> {
+    if packet.len() < 3 || packet[0] & 0xc0 != 0x80 {
+        return None;
+    }
+    let header_data = packet.get(3..3 + packet[2] as usize)?;
+    let payload = &packet[3 + header_data.len()..];
+    if packet[1] & 0x80 == 0 {
+        return Some((None, payload));
+    }
+    Some((Some(read_timestamp(header_data.get(..5)?)), payload))
+}
+
+/// Read a 33-bit timestamp stored in five bytes with marker bits, as PTSs
+/// are.
+fn read_timestamp(bytes: &[u8]) -> u64 {
+    (bytes[0] as u64 >> 1 & 0x07) << 30
+        | (bytes[1] as u64) << 22
+        | (bytes[2] as u64 >> 1) << 15
+        | (bytes[3] as u64) << 7
+        | bytes[4] as u64 >> 1
+}
+
+/// Append a complete subpicture unit to a program stream, splitting it into
+/// several PES packets if it does not fit in one. Only the first packet
+/// carries the presentation timestamp, just like on a DVD.
+pub fn write_subpicture(out: &mut Vec<u8>, substream_id: u8, seconds: f64, spu: &[u8]) {
+    let pts = (seconds.max(0.0) * CLOCK_RATE) as u64 & 0x1_ffff_ffff;
+    for (i, chunk) in spu.chunks(MAX_PES_PAYLOAD).enumerate() {
+        write_pack_header(out, pts);
+        let pts = if i == 0 { Some(pts) } else { None };
+        write_pes_packet(out, substream_id, pts, chunk);
+    }
+}
+
+/// Write a pack header with the given system clock reference and no
+/// stuffing.
+fn write_pack_header(out: &mut Vec<u8>, scr: u64) {
+    out.extend_from_slice(&[0x00, 0x00, 0x01, 0xba]);
+    out.extend_from_slice(&[
+        0x44 | ((scr >> 27) & 0x38) as u8 | ((scr >> 28) & 0x03) as u8,
+        (scr >> 20) as u8,
+        0x04 | ((scr >> 12) & 0xf8) as u8 | ((scr >> 13) & 0x03) as u8,
+        (scr >> 5) as u8,
+        0x04 | ((scr << 3) & 0xf8) as u8,
+        // SCR extension is zero, followed by a marker bit.
+        0x01,
+        // Program mux rate of 25200 * 50 bytes/s, as on a DVD, plus markers.
+        0x01,
+        0x89,
+        0xc3,
+        // Reserved bits and a stuffing length of zero.
+        0xf8,
+    ]);
+}
+
+/// Write a private stream 1 PES packet.
+fn write_pes_packet(out: &mut Vec<u8>, substream_id: u8, pts: Option<u64>, data: &[u8]) {
+    let header_data_len = if pts.is_some() { 5 } else { 0 };
+    let len = 3 + header_data_len + 1 + data.len();
+    out.extend_from_slice(&[0x00, 0x00, 0x01, PRIVATE_STREAM_1]);
+    out.extend_from_slice(&(len as u16).to_be_bytes());
+    match pts {
+        Some(pts) => {
+            out.extend_from_slice(&[0x81, 0x80, header_data_len as u8]);
+            out.extend_from_slice(&[
+                0x21 | ((pts >> 29) & 0x0e) as u8,
+                (pts >> 22) as u8,
+                0x01 | ((pts >> 14) & 0xfe) as u8,
+                (pts >> 7) as u8,
+                0x01 | ((pts << 1) & 0xfe) as u8,
+            ]);
+        }
+        None => out.extend_from_slice(&[0x81, 0x00, 0x00]),
+    }
+    out.push(substream_id);
+    out.extend_from_slice(data);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A subpicture unit of the given size, which it starts with.
+    fn spu(len: usize) -> Vec<u8> {
+        let mut spu: Vec<u8> = (0..len).map(|i| i as u8).collect();
+        spu[..2].copy_from_slice(&(len as u16).to_be_bytes());
+        spu
+    }
+
+    #[test]
+    fn reads_back_written_subpictures() {
+        let mut out = Vec::new();
+        write_subpicture(&mut out, 0x20, 1.5, &spu(100));
+        // Too large for one PES packet.
+        write_subpicture(&mut out, 0x21, 3.0, &spu(0xffff));
+        let subpictures = demux_subpictures(&out[..], true).unwrap();
+        assert_eq!(subpictures.len(), 2);
+        assert_eq!(subpictures[0].substream_id, 0x20);
+        assert_eq!(subpictures[0].seconds, 1.5);
+        assert_eq!(subpictures[0].data, spu(100));
+        assert_eq!(subpictures[1].substream_id, 0x21);
+        assert_eq!(subpictures[1].seconds, 3.0);
+        assert_eq!(subpictures[1].data, spu(0xffff));
+    }
+
+    #[test]
+    fn truncated_stream_is_an_error() {
+        let mut out = Vec::new();
+        write_subpicture(&mut out, 0x20, 1.5, &spu(100));
+        out.truncate(out.len() - 10);
+        let error = demux_subpictures(&out[..], true).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn reads_system_clock_references() {
+        let scr = 0x1_2345_6789;
+        let mut out = Vec::new();
+        write_pack_header(&mut out, scr);
+        let mut reader = &out[..];
+        assert_eq!(next_start_code(&mut reader).unwrap(), Some(PACK_START));
+        assert_eq!(read_pack_header(&mut reader).unwrap(), scr);
+        assert!(reader.is_empty());
+    }
+
+    #[test]
+    fn keeps_timestamps_far_apart() {
+        let mut out = Vec::new();
+        for &seconds in &[10.0, 200.0, 1000.0] {
+            write_subpicture(&mut out, 0x20, seconds, &spu(100));
+        }
+        let subpictures = demux_subpictures(&out[..], true).unwrap();
+        let seconds: Vec<f64> = subpictures.iter().map(|s| s.seconds).collect();
+        assert_eq!(seconds, [10.0, 200.0, 1000.0]);
+    }
+
+    #[test]
+    fn carries_timestamps_across_clock_resets() {
+        let mut out = Vec::new();
+        write_subpicture(&mut out, 0x20, 10.0, &spu(100));
+        // The next VOB file starts the clock over.
+        write_subpicture(&mut out, 0x20, 2.0, &spu(100));
+        let subpictures = demux_subpictures(&out[..], true).unwrap();
+        assert_eq!(subpictures.len(), 2);
+        assert_eq!(subpictures[0].seconds, 10.0);
+        assert_eq!(subpictures[1].seconds, 10.0);
+    }
+
+    #[test]
+    fn rejects_truncated_pes_headers() {
+        assert_eq!(parse_pes_packet(&[0x81, 0x80]), None);
+        // Header data claims five bytes for the PTS, but only has two.
+        assert_eq!(parse_pes_packet(&[0x81, 0x80, 0x05, 0x21, 0x00]), None);
+        assert_eq!(
+            parse_pes_packet(&[0x81, 0x00, 0x00, 0x20, 0x01]),
+            Some((None, &[0x20, 0x01][..]))
+        );
+    }
+}
@@ -0,0 +1,601 @@
+//! Splitting binarized subtitle images into lines of text. Glyphs are found
+//! as connected components and grouped into lines by their vertical position,
+//! so that descenders reaching into the next line or stray specks between
+//! lines do not upset the split. When glyphs of different lines touch, the
+//! lines are cut apart at the valleys of the projection profile instead.
+//! Specks which are too small and out of place to be punctuation are removed,
+//! and blocks of text side by side on the same row, as used for two speakers,
+//! are then split into separate lines.
+
+use log::debug;
+use std::ops::Range;
+
+/// Horizontal gap, relative to the text height, which separates blocks of
+/// text on the same row. Spaces between words are much narrower.
+const BLOCK_GAP: usize = 2;
+
+/// A binarized image, where `true` represents a text pixel.
+pub struct Mask {
+    pub width: usize,
+    pub height: usize,
+    pub pixels: Vec<bool>,
+}
+
+/// A connected group of text pixels.
+#[derive(Debug, Clone)]
+pub struct Component {
+    pub x: Range<usize>,
+    pub y: Range<usize>,
+    /// Coordinates of the pixels, within the whole image.
+    pub pixels: Vec<(usize, usize)>,
+}
+
+impl Component {
+    fn from_pixels(pixels: Vec<(usize, usize)>) -> Self {
+        let (x, y) = bounding_box(pixels.iter().map(|&(x, y)| (x..x + 1, y..y + 1)));
+        Component { x, y, pixels }
+    }
+
+    pub fn height(&self) -> usize {
+        self.y.len()
+    }
+
+    fn area(&self) -> usize {
+        self.pixels.len()
+    }
+
+    fn center_y(&self) -> f32 {
+        (self.y.start + self.y.end) as f32 / 2.0
+    }
+}
+
+/// A line of text, as the components which make it up, from left to right.
+#[derive(Debug)]
+pub struct Line {
+    pub components: Vec<Component>,
+    /// Whether this line starts one of several blocks of text side by side,
+    /// such as the lines of one of two speakers.
+    pub block_start: bool,
+}
+
+impl Line {
+    fn new(mut components: Vec<Component>) -> Self {
+        components.sort_by_key(|component| component.x.start);
+        Line {
+            components,
+            block_start: false,
+        }
+    }
+
+    /// Split the line into blocks wherever there is a horizontal gap of
+    /// `BLOCK_GAP` times the text height.
+    fn split_blocks(self, text_height: usize) -> Vec<Line> {
+        let mut blocks: Vec<Vec<Component>> = Vec::new();
+        let mut right = 0;
+        for component in self.components {
+            let end = component.x.end;
+            match blocks.last_mut() {
+                Some(block) if component.x.start < right + text_height * BLOCK_GAP => {
+                    block.push(component)
+                }
+                _ => blocks.push(vec![component]),
+            }
+            right = right.max(end);
+        }
+        let block_start = blocks.len() > 1;
+        blocks
+            .into_iter()
+            .map(|components| Line {
+                components,
+                block_start,
+            })
+            .collect()
+    }
+
+    /// Estimate the x-height of the line, as the median height of its
+    /// letters. Most lowercase letters have neither ascenders nor descenders.
+    pub fn x_height(&self) -> usize {
+        let max_height = self
+            .components
+            .iter()
+            .map(Component::height)
+            .max()
+            .unwrap_or(0);
+        let mut heights: Vec<usize> = self
+            .components
+            .iter()
+            .map(Component::height)
+            .filter(|&height| height * 2 >= max_height)
+            .collect();
+        heights.sort_unstable();
+        heights.get(heights.len() / 2).copied().unwrap_or(0)
+    }
+
+    /// The bounding box of the line, as horizontal and vertical ranges.
+    pub fn bounds(&self) -> (Range<usize>, Range<usize>) {
+        bounding_box(
+            self.components
+                .iter()
+                .map(|component| (component.x.clone(), component.y.clone())),
+        )
+    }
+}
+
+/// Find the smallest box containing all the given boxes.
+fn bounding_box(
+    boxes: impl Iterator<Item = (Range<usize>, Range<usize>)>,
+) -> (Range<usize>, Range<usize>) {
+    let (mut left, mut top, mut right, mut bottom) = (usize::MAX, usize::MAX, 0, 0);
+    for (x, y) in boxes {
+        left = left.min(x.start);
+        top = top.min(y.start);
+        right = right.max(x.end);
+        bottom = bottom.max(y.end);
+    }
+    (left..right, top..bottom)
+}
+
+/// Split a mask into lines of text, from top to bottom, and blocks of text
+/// on the same row from left to right. Components smaller than `speck_size`
+/// times the square of the text height are removed unless they are where
+/// punctuation would be.
+pub fn segment_lines(mask: &Mask, speck_size: f32) -> Vec<Line> {
+    let components = connected_components(mask);
+    if components.is_empty() {
+        return Vec::new();
+    }
+    let text_height = text_height(&components);
+    let lines = match lines_by_components(&components, text_height) {
+        Some(lines) => lines,
+        None => {
+            debug!("Lines of text touch; splitting them at projection profile valleys");
+            lines_by_projection(mask, components, text_height)
+        }
+    };
+    order_blocks(
+        lines
+            .into_iter()
+            .filter_map(|line| remove_specks(line, text_height, speck_size))
+            .map(|line| line.split_blocks(text_height))
+            .collect(),
+    )
+}
+
+/// Order the blocks of each row of text, so that blocks side by side over
+/// several rows are read column by column, keeping the lines of each speaker
+/// together. Consecutive rows split into the same number of blocks, each
+/// overlapping the block above it, are taken as one set of columns.
+fn order_blocks(rows: Vec<Vec<Line>>) -> Vec<Line> {
+    let mut lines = Vec::new();
+    let mut rows = rows.into_iter().peekable();
+    while let Some(first) = rows.next() {
+        let columns = first.len();
+        let mut group = vec![first];
+        while let Some(next) = rows.next_if(|next| {
+            let above = &group[group.len() - 1];
+            columns > 1
+                && next.len() == columns
+                && above.iter().zip(next).all(|(above, below)| {
+                    let (above, _) = above.bounds();
+                    let (below, _) = below.bounds();
+                    above.start < below.end && below.start < above.end
+                })
+        }) {
+            // Only the first line of each column starts a block.
+            group.push(
+                next.into_iter()
+                    .map(|line| Line {
+                        block_start: false,
+                        ..line
+                    })
+                    .collect(),
+            );
+        }
+        let mut group: Vec<_> = group.into_iter().map(Vec::into_iter).collect();
+        for _ in 0..columns {
+            lines.extend(group.iter_mut().filter_map(Iterator::next));
+        }
+    }
+    lines
+}
+
+/// Find the 8-connected components of a mask.
+pub fn connected_components(mask: &Mask) -> Vec<Component> {
+    let mut visited = vec![false; mask.pixels.len()];
+    let mut components = Vec::new();
+    let mut stack = Vec::new();
+    for start in 0..mask.pixels.len() {
+        if !mask.pixels[start] || visited[start] {
+            continue;
+        }
+        visited[start] = true;
+        stack.push(start);
+        let mut pixels = Vec::new();
+        while let Some(offset) = stack.pop() {
+            let (x, y) = (offset % mask.width, offset / mask.width);
+            pixels.push((x, y));
+            for ny in y.saturating_sub(1)..(y + 2).min(mask.height) {
+                for nx in x.saturating_sub(1)..(x + 2).min(mask.width) {
+                    let neighbour = ny * mask.width + nx;
+                    if mask.pixels[neighbour] && !visited[neighbour] {
+                        visited[neighbour] = true;
+                        stack.push(neighbour);
+                    }
+                }
+            }
+        }
+        components.push(Component::from_pixels(pixels));
+    }
+    components
+}
+
+/// Estimate the height of the letters. Most components are letters, so the
+/// upper quartile of component heights is a letter with ascenders, without
+/// being thrown off by punctuation or the odd oversized component. Specks
+/// far smaller than the tallest component are left out, in case there are a
+/// lot of them.
+pub fn text_height(components: &[Component]) -> usize {
+    let max_height = components.iter().map(Component::height).max().unwrap_or(0);
+    let mut heights: Vec<usize> = components
+        .iter()
+        .map(Component::height)
+        .filter(|&height| height * 4 >= max_height)
+        .collect();
+    heights.sort_unstable();
+    heights[heights.len() * 3 / 4]
+}
+
+/// Remove small components from a line, unless they are next to a letter
+/// and sit on the baseline, like a period or comma, at the top of the line,
+/// like an apostrophe or the dot of an i, or above another dot, like a colon.
+/// Returns `None` if nothing but specks are left.
+fn remove_specks(line: Line, text_height: usize, speck_size: f32) -> Option<Line> {
+    let min_area = speck_size * (text_height * text_height) as f32;
+    let letters: Vec<&Component> = line
+        .components
+        .iter()
+        .filter(|component| component.height() * 2 >= text_height)
+        .collect();
+    if letters.is_empty() {
+        debug!("Removing a line of {} specks", line.components.len());
+        return None;
+    }
+    let mut tops: Vec<usize> = letters.iter().map(|letter| letter.y.start).collect();
+    let mut baselines: Vec<usize> = letters.iter().map(|letter| letter.y.end).collect();
+    tops.sort_unstable();
+    baselines.sort_unstable();
+    let top = tops[tops.len() / 2];
+    let baseline = baselines[baselines.len() / 2];
+    let middle = (top + baseline) / 2;
+    let tolerance = (text_height / 8).max(1);
+    let stroke = stroke_width(&letters);
+
+    let overlaps = |a: &Range<usize>, b: &Range<usize>| a.start < b.end && b.start < a.end;
+    let on_baseline = |component: &Component| {
+        component.y.start >= middle
+            && (component.y.end.abs_diff(baseline) <= tolerance || component.y.end > baseline)
+            && component.y.start < baseline
+    };
+    let is_punctuation = |component: &Component| {
+        let near_letter = letters.iter().any(|letter| {
+            letter.x.start < component.x.end + text_height
+                && component.x.start < letter.x.end + text_height
+        });
+        let inside_letter = letters.iter().any(|letter| {
+            letter.x.start <= component.x.start
+                && component.x.end <= letter.x.end
+                && letter.y.start <= component.y.start
+                && component.y.end <= letter.y.end
+        });
+        let at_top = component.y.start <= top + tolerance
+            && component.y.end <= middle
+            && component.y.end + text_height / 2 >= top;
+        let above_dot = line.components.iter().any(|other| {
+            other.y.start >= component.y.end
+                && overlaps(&other.x, &component.x)
+                && other.height() * 2 < text_height
+                && on_baseline(other)
+        });
+        // Punctuation is drawn with the same pen as the letters.
+        let stroke_sized = component.x.len() * 2 >= stroke && component.y.len() * 2 >= stroke;
+        near_letter
+            && !inside_letter
+            && stroke_sized
+            && (on_baseline(component) || at_top || above_dot)
+    };
+    let count = line.components.len();
+    let components: Vec<Component> = line
+        .components
+        .iter()
+        .filter(|component| component.area() as f32 >= min_area || is_punctuation(component))
+        .cloned()
+        .collect();
+    if components.len() < count {
+        debug!("Removed {} specks", count - components.len());
+    }
+    Some(Line { components, ..line })
+}
+
+/// Estimate the width of the strokes of some letters, as the median length
+/// of their horizontal runs of pixels.
+fn stroke_width(letters: &[&Component]) -> usize {
+    let mut runs = Vec::new();
+    for letter in letters {
+        let mut pixels = letter.pixels.clone();
+        pixels.sort_unstable_by_key(|&(x, y)| (y, x));
+        let mut run = 0;
+        for (i, &(x, y)) in pixels.iter().enumerate() {
+            run += 1;
+            if pixels.get(i + 1) != Some(&(x + 1, y)) {
+                runs.push(run);
+                run = 0;
+            }
+        }
+    }
+    runs.sort_unstable();
+    runs.get(runs.len() / 2).copied().unwrap_or(1)
+}
+
+/// Group components into lines by their vertical centers, starting with
+/// letter-sized components and then giving punctuation and other small
+/// components to the nearest line. Returns `None` if some component is too
+/// tall to be a single letter, which happens when lines touch.
+fn lines_by_components(components: &[Component], text_height: usize) -> Option<Vec<Line>> {
+    if components
+        .iter()
+        .any(|component| component.height() * 2 > text_height * 3)
+    {
+        return None;
+    }
+    let (mut letters, small): (Vec<&Component>, Vec<&Component>) = components
+        .iter()
+        .partition(|component| component.height() * 2 >= text_height);
+    letters.sort_by(|a, b| a.center_y().total_cmp(&b.center_y()));
+
+    let mut lines: Vec<(Range<usize>, Vec<Component>)> = Vec::new();
+    for letter in letters {
+        match lines.last_mut() {
+            Some((y, line)) if letter.center_y() < y.end as f32 => {
+                *y = y.start.min(letter.y.start)..y.end.max(letter.y.end);
+                line.push(letter.clone());
+            }
+            _ => lines.push((letter.y.clone(), vec![letter.clone()])),
+        }
+    }
+    for component in small {
+        let center = component.center_y();
+        let distance = |y: &Range<usize>| {
+            (y.start as f32 - center)
+                .max(center - y.end as f32)
+                .max(0.0)
+        };
+        let (_, line) = lines
+            .iter_mut()
+            .min_by(|a, b| distance(&a.0).total_cmp(&distance(&b.0)))?;
+        line.push(component.clone());
+    }
+    Some(
+        lines
+            .into_iter()
+            .map(|(_, components)| Line::new(components))
+            .collect(),
+    )
+}
+
+/// Split the image into bands of rows at the valleys of its projection
+/// profile, and cut the components along them.
+fn lines_by_projection(mask: &Mask, components: Vec<Component>, text_height: usize) -> Vec<Line> {
+    let profile: Vec<usize> = (0..mask.height)
+        .map(|y| {
+            mask.pixels[y * mask.width..(y + 1) * mask.width]
+                .iter()
+                .filter(|&&pixel| pixel)
+                .count()
+        })
+        .collect();
+    let mut bands = Vec::new();
+    for group in find_contiguous_row_groups(&profile) {
+        split_at_valleys(&profile, group, text_height, &mut bands);
+    }
+    let bands = merge_small_bands(bands, text_height);
+
+    let mut lines: Vec<Vec<Component>> = bands.iter().map(|_| Vec::new()).collect();
+    for component in components {
+        for (band, line) in bands.iter().zip(&mut lines) {
+            let pixels: Vec<(usize, usize)> = component
+                .pixels
+                .iter()
+                .copied()
+                .filter(|(_, y)| band.contains(y))
+                .collect();
+            if !pixels.is_empty() {
+                line.push(Component::from_pixels(pixels));
+            }
+        }
+    }
+    lines.into_iter().map(Line::new).collect()
+}
+
+/// Find ranges of contiguous rows with text pixels.
+fn find_contiguous_row_groups(profile: &[usize]) -> Vec<Range<usize>> {
+    let mut groups: Vec<Range<usize>> = Vec::new();
+    let mut y = 0;
+    while y < profile.len() {
+        // Find the start of the next range of contiguous rows.
+        match profile[y..].iter().position(|&count| count > 0) {
+            Some(start_offset) => {
+                let start = y + start_offset;
+                // Find the end of this range.
+                let end = profile[start..]
+                    .iter()
+                    .position(|&count| count == 0)
+                    .map_or(profile.len(), |end_offset| start + end_offset);
+                groups.push(start..end);
+                y = end;
+            }
+            None => break,
+        }
+    }
+    groups
+}
+
+/// Split a group of rows that is too tall for one line at the emptiest row,
+/// keeping at least most of a letter's height on either side.
+fn split_at_valleys(
+    profile: &[usize],
+    group: Range<usize>,
+    text_height: usize,
+    bands: &mut Vec<Range<usize>>,
+) {
+    let margin = text_height * 3 / 5;
+    if group.len() * 5 <= text_height * 8 || group.len() <= margin * 2 {
+        bands.push(group);
+        return;
+    }
+    let candidates = group.start + margin..group.end - margin;
+    let valley = candidates
+        .clone()
+        .min_by_key(|&y| profile[y])
+        .unwrap_or(candidates.start);
+    split_at_valleys(profile, group.start..valley, text_height, bands);
+    split_at_valleys(profile, valley..group.end, text_height, bands);
+}
+
+/// Merge bands too short to be a line, such as a speck between two lines,
+/// into the nearest neighbouring band.
+fn merge_small_bands(bands: Vec<Range<usize>>, text_height: usize) -> Vec<Range<usize>> {
+    let is_small = |band: &Range<usize>| band.len() * 3 < text_height;
+    let mut merged: Vec<Range<usize>> = bands
+        .iter()
+        .filter(|band| !is_small(band))
+        .cloned()
+        .collect();
+    if merged.is_empty() {
+        return bands;
+    }
+    for band in bands.into_iter().filter(is_small) {
+        let distance = |other: &Range<usize>| {
+            other
+                .start
+                .saturating_sub(band.end)
+                .max(band.start.saturating_sub(other.end))
+        };
+        if let Some(nearest) = merged.iter_mut().min_by_key(|other| distance(other)) {
+            *nearest = nearest.start.min(band.start)..nearest.end.max(band.end);
+        }
+    }
+    merged
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mask(width: usize, height: usize, boxes: &[(usize, usize, usize, usize)]) -> Mask {
+        let mut pixels = vec![false; width * height];
+        for &(x, y, w, h) in boxes {
+            for row in y..y + h {
+                pixels[row * width + x..row * width + x + w].fill(true);
+            }
+        }
+        Mask {
+            width,
+            height,
+            pixels,
+        }
+    }
+
+    /// Three 4x10 letters starting at the given position.
+    fn word(x: usize, y: usize) -> Vec<(usize, usize, usize, usize)> {
+        (0..3).map(|i| (x + i * 6, y, 4, 10)).collect()
+    }
+
+    fn line_bounds(lines: &[Line]) -> Vec<(Range<usize>, Range<usize>)> {
+        lines.iter().map(Line::bounds).collect()
+    }
+
+    #[test]
+    fn finds_8_connected_components() {
+        let mask = mask(10, 10, &[(0, 0, 2, 2), (2, 2, 2, 2), (7, 0, 2, 5)]);
+        let components = connected_components(&mask);
+        assert_eq!(components.len(), 2);
+        assert_eq!(
+            (components[0].x.clone(), components[0].y.clone()),
+            (0..4, 0..4)
+        );
+        assert_eq!(components[0].pixels.len(), 8);
+        assert_eq!(
+            (components[1].x.clone(), components[1].y.clone()),
+            (7..9, 0..5)
+        );
+    }
+
+    #[test]
+    fn splits_lines_and_keeps_punctuation() {
+        let mut boxes = [word(2, 2), word(2, 20)].concat();
+        // A period after the first line, and a speck between the lines.
+        boxes.push((20, 10, 2, 2));
+        boxes.push((30, 16, 1, 1));
+        let lines = segment_lines(&mask(40, 32, &boxes), 0.1);
+        assert_eq!(line_bounds(&lines), [(2..22, 2..12), (2..18, 20..30)]);
+        assert_eq!(lines[0].components.len(), 4);
+    }
+
+    #[test]
+    fn splits_blocks_side_by_side() {
+        let boxes = [word(2, 2), word(50, 2)].concat();
+        let lines = segment_lines(&mask(70, 14, &boxes), 0.1);
+        assert_eq!(line_bounds(&lines), [(2..18, 2..12), (50..66, 2..12)]);
+        assert!(lines.iter().all(|line| line.block_start));
+    }
+
+    #[test]
+    fn reads_blocks_side_by_side_column_by_column() {
+        // Two speakers with two lines each, the second line of each shorter.
+        let boxes = [word(2, 2), word(2, 16), word(50, 2), word(56, 16)].concat();
+        let lines = segment_lines(&mask(80, 28, &boxes), 0.1);
+        assert_eq!(
+            line_bounds(&lines),
+            [
+                (2..18, 2..12),
+                (2..18, 16..26),
+                (50..66, 2..12),
+                (56..72, 16..26)
+            ]
+        );
+        let block_starts: Vec<bool> = lines.iter().map(|line| line.block_start).collect();
+        assert_eq!(block_starts, [true, false, true, false]);
+    }
+
+    #[test]
+    fn keeps_rows_without_matching_columns_apart() {
+        // A single line under two blocks side by side.
+        let boxes = [word(2, 2), word(50, 2), word(20, 16)].concat();
+        let lines = segment_lines(&mask(80, 28, &boxes), 0.1);
+        assert_eq!(
+            line_bounds(&lines),
+            [(2..18, 2..12), (50..66, 2..12), (20..36, 16..26)]
+        );
+        let block_starts: Vec<bool> = lines.iter().map(|line| line.block_start).collect();
+        assert_eq!(block_starts, [true, true, false]);
+    }
+
+    #[test]
+    fn splits_touching_lines_at_valleys() {
+        let mut boxes = [word(2, 2), word(2, 14)].concat();
+        // Join the first letters of both lines, like a descender would.
+        boxes.push((2, 12, 2, 2));
+        let lines = segment_lines(&mask(20, 26, &boxes), 0.1);
+        assert_eq!(line_bounds(&lines), [(2..18, 2..12), (2..18, 12..24)]);
+        assert!(lines.iter().all(|line| line.components.len() == 3));
+    }
+
+    #[test]
+    fn handles_empty_and_speck_only_masks() {
+        assert!(segment_lines(&mask(10, 10, &[]), 0.1).is_empty());
+        assert!(segment_lines(&mask(0, 0, &[]), 0.1).is_empty());
+        // A lone dot is a line of its own letter height, and stays.
+        let lines = segment_lines(&mask(10, 10, &[(4, 4, 1, 1)]), 0.1);
+        assert_eq!(line_bounds(&lines), [(4..5, 4..5)]);
+    }
+}
@@ -0,0 +1,162 @@
+//! The Tesseract OCR engine, through `leptess`.
+
+use crate::engine::{self, Recognition, Recognizer, RecognizerFactory, Word};
+use image::{
+    codecs::pnm::{PnmSubtype, SampleEncoding},
+    DynamicImage, GrayImage,
+};
+use leptess::{
+    leptonica::PixError,
+    tesseract::{TessInitError, TessSetVariableError},
+    LepTess, Variable,
+};
+use snafu::{ResultExt, Snafu};
+use std::{io::Cursor, str::Utf8Error};
+
+#[derive(Debug, Snafu)]
+pub enum Error {
+    #[snafu(display("Could not initialize tesseract {}", source))]
+    Initialize { source: TessInitError },
+
+    #[snafu(display("Could not set tesseract variable: {}", source))]
+    SetVariable { source: TessSetVariableError },
+
+    #[snafu(display("Could not write image to memory: {}", source))]
+    WriteImage { source: image::ImageError },
+
+    #[snafu(display("Could not set tesseract image: {}", source))]
+    SetImage { source: PixError },
+
+    #[snafu(display("Could not get tesseract text: {}", source))]
+    GetText { source: Utf8Error },
+}
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+/// Tesseract, with the options to set up an instance for each thread with.
+pub struct TesseractEngine {
+    datapath: Option<String>,
+    language: String,
+    config: Vec<(Variable, String)>,
+}
+
+impl TesseractEngine {
+    pub fn new(datapath: Option<&str>, language: &str, config: &[(Variable, String)]) -> Self {
+        // Each thread runs its own instance; keep them from starting threads
+        // of their own.
+        std::env::set_var("OMP_THREAD_LIMIT", "1");
+        TesseractEngine {
+            datapath: datapath.map(str::to_owned),
+            language: language.to_owned(),
+            config: config.to_vec(),
+        }
+    }
+}
+
+impl RecognizerFactory for TesseractEngine {
+    fn recognizer(&self) -> engine::Result<Box<dyn Recognizer>> {
+        let tesseract =
+            TesseractWrapper::new(self.datapath.as_deref(), &self.language, &self.config)
+                .context(engine::TesseractSnafu {})?;
+        Ok(Box::new(tesseract))
+    }
+}
+
+struct TesseractWrapper {
+    leptess: LepTess,
+}
+
+impl TesseractWrapper {
+    fn new(
+        datapath: Option<&str>,
+        language: impl AsRef<str>,
+        config: &[(Variable, String)],
+    ) -> Result<Self> {
+        let mut leptess = LepTess::new(datapath, language.as_ref()).context(InitializeSnafu {})?;
+        // Disable learning by default, though a user could re-enable this
+        // option with `-c`. We turn this off since we are are multithreading,
+        // so this option would result in non-deterministic output.
+        leptess
+            .set_variable(leptess::Variable::ClassifyEnableLearning, "0")
+            .context(SetVariableSnafu {})?;
+        // 7 is PSM_SINGLE_LINE. We have preprocessed the input into individual
+        // lines, and telling Tesseract this fact greatly improves accuracy.
+        leptess
+            .set_variable(leptess::Variable::TesseditPagesegMode, "7")
+            .context(SetVariableSnafu {})?;
+        // Add user options.
+        for (key, value) in config {
+            leptess
+                .set_variable(*key, value)
+                .context(SetVariableSnafu {})?;
+        }
+        Ok(Self { leptess })
+    }
+
+    /// Set the tesseract image to the given image's contents.
+    fn set_image(&mut self, image: GrayImage, dpi: i32) -> Result<()> {
+        let mut bytes: Cursor<Vec<u8>> = Cursor::new(Vec::new());
+        DynamicImage::ImageLuma8(image)
+            .write_to(
+                &mut bytes,
+                image::ImageOutputFormat::Pnm(PnmSubtype::Graymap(SampleEncoding::Binary)),
+            )
+            .context(WriteImageSnafu {})?;
+        self.leptess
+            .set_image_from_mem(bytes.get_ref())
+            .context(SetImageSnafu {})?;
+        self.leptess.set_source_resolution(dpi);
+        Ok(())
+    }
+
+    /// Recognize the text of the image, with its confidences.
+    fn get_text(&mut self) -> Result<Recognition> {
+        let text = self.leptess.get_utf8_text().context(GetTextSnafu {})?;
+        let tsv = self.leptess.get_tsv_text(0).context(GetTextSnafu {})?;
+        Ok(Recognition {
+            text,
+            confidence: self.leptess.mean_text_conf() as f32,
+            words: Some(parse_words(&tsv)),
+        })
+    }
+}
+
+impl Recognizer for TesseractWrapper {
+    fn recognize(
+        &mut self,
+        image: GrayImage,
+        dpi: i32,
+        _learn: bool,
+    ) -> engine::Result<Recognition> {
+        self.set_image(image, dpi)
+            .and_then(|()| self.get_text())
+            .context(engine::TesseractSnafu {})
+    }
+}
+
+/// Read the words from Tesseract's TSV output, which has a row for each page,
+/// block, paragraph, line and word. Word rows are level 5, with the box in
+/// the 7th to 10th columns, the confidence in the 11th and the text in the
+/// 12th.
+fn parse_words(tsv: &str) -> Vec<Word> {
+    tsv.lines()
+        .filter_map(|row| {
+            let columns: Vec<&str> = row.split('\t').collect();
+            match columns.as_slice() {
+                ["5", _, _, _, _, _, x, y, width, height, confidence, text]
+                    if !text.trim().is_empty() =>
+                {
+                    Some(Word {
+                        text: text.to_string(),
+                        confidence: confidence.parse().ok()?,
+                        x: x.parse().ok()?,
+                        y: y.parse().ok()?,
+                        width: width.parse().ok()?,
+                        height: height.parse().ok()?,
+                    })
+                }
+                _ => None,
+            }
+        })
+        .collect()
+}
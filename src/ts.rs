@@ -0,0 +1,506 @@
+//! Just enough MPEG-2 transport stream support to get DVB subtitle streams out
+//! of broadcast recordings such as `*.ts` files.
+
+use crate::ps;
+use log::trace;
+use std::{
+    collections::{HashMap, HashSet},
+    fs::File,
+    io::{self, ErrorKind, Read},
+    ops::RangeInclusive,
+    path::Path,
+};
+
+const SYNC_BYTE: u8 = 0x47;
+const PACKET_SIZE: usize = 188;
+/// Packet sizes we understand: plain transport streams, and Blu-ray style
+/// streams with a 4-byte timecode in front of each packet.
+const PACKET_SIZES: [usize; 2] = [188, 192];
+const PAT_PID: u16 = 0x0000;
+const PAT_TABLE_ID: u8 = 0x00;
+const PMT_TABLE_ID: u8 = 0x02;
+/// Stream type of PES packets containing private data, which is how DVB
+/// subtitles are carried.
+const PRIVATE_PES_STREAM_TYPE: u8 = 0x06;
+const SUBTITLING_DESCRIPTOR: u8 = 0x59;
+/// Subtitling types of DVB bitmap subtitles, as opposed to teletext.
+const DVB_SUBTITLE_TYPES: [RangeInclusive<u8>; 2] = [0x10..=0x15, 0x20..=0x25];
+const CLOCK_RATE: f64 = 90_000.0;
+/// Timestamps count in 33 bits, and wrap around about every 26.5 hours.
+const TIMESTAMP_MASK: u64 = (1 << 33) - 1;
+/// Most packets to hold back while waiting for the program map tables,
+/// which broadcasts repeat several times a second.
+const MAX_PENDING_PACKETS: usize = 50_000;
+
+/// A DVB subtitle service declared in a program map table.
+#[derive(Debug, PartialEq, Eq)]
+pub struct SubtitleService {
+    pub pid: u16,
+    /// ISO 639-2 language code.
+    pub language: String,
+    /// The page carrying this service's subtitles.
+    pub composition_page: u16,
+    /// The page carrying data shared between services, such as color tables.
+    pub ancillary_page: u16,
+}
+
+/// A complete PES packet of a subtitle PID.
+#[derive(Debug)]
+pub struct Pes {
+    pub pid: u16,
+    /// Presentation timestamp in seconds, relative to the start of the
+    /// recording.
+    pub seconds: f64,
+    pub data: Vec<u8>,
+}
+
+/// The subtitle services of a transport stream, along with their packets.
+#[derive(Debug, Default)]
+pub struct TransportStream {
+    pub services: Vec<SubtitleService>,
+    pub packets: Vec<Pes>,
+}
+
+/// Check for sync bytes at the start of the first few packets, returning the
+/// packet size if they are there.
+pub fn packet_size<P: AsRef<Path>>(path: P) -> io::Result<Option<usize>> {
+    let mut start = [0; 3 * 192];
+    match File::open(path)?.read_exact(&mut start) {
+        Ok(()) => {}
+        Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e),
+    }
+    Ok(PACKET_SIZES
+        .iter()
+        .copied()
+        .find(|&size| (0..3).all(|i| start[size - PACKET_SIZE + i * size] == SYNC_BYTE)))
+}
+
+/// Demultiplex the DVB subtitle services of a transport stream and all of
+/// their PES packets. Timestamps are made relative to the first timestamp of
+/// any elementary stream, which is where players start the recording.
+pub fn demux_subtitles<R: Read>(mut reader: R, packet_size: usize) -> io::Result<TransportStream> {
+    let mut demuxer = Demuxer {
+        pending: Some(Vec::new()),
+        ..Demuxer::default()
+    };
+    let mut buf = vec![0; packet_size];
+    loop {
+        match reader.read_exact(&mut buf) {
+            Ok(()) => {}
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => break,
+            Err(e) => return Err(e),
+        }
+        demuxer.packet(&buf[packet_size - PACKET_SIZE..]);
+    }
+    demuxer.replay_pending();
+    Ok(demuxer.finish())
+}
+
+/// The state of demultiplexing a transport stream.
+#[derive(Default)]
+struct Demuxer {
+    stream: TransportStream,
+    pmt_pids: HashSet<u16>,
+    /// The version of each program map table read so far.
+    pmt_versions: HashMap<u16, u8>,
+    es_pids: HashSet<u16>,
+    sections: HashMap<u16, Vec<u8>>,
+    partials: HashMap<u16, Vec<u8>>,
+    complete: Vec<(u16, Vec<u8>)>,
+    start: Option<u64>,
+    /// Packets of elementary streams seen before all program map tables were
+    /// read, which cannot be told apart yet. `None` once they are replayed.
+    pending: Option<Vec<Vec<u8>>>,
+}
+
+impl Demuxer {
+    fn packet(&mut self, packet: &[u8]) {
+        // Skip packets which are out of sync or flagged as corrupt.
+        if packet[0] != SYNC_BYTE || packet[1] & 0x80 != 0 {
+            trace!("Skipping bad transport stream packet");
+            return;
+        }
+        let unit_start = packet[1] & 0x40 != 0;
+        let pid = u16::from_be_bytes([packet[1] & 0x1f, packet[2]]);
+        let payload = match payload(packet) {
+            Some(payload) => payload,
+            None => return,
+        };
+
+        if pid == PAT_PID || self.pmt_pids.contains(&pid) {
+            let section = match read_section(&mut self.sections, pid, unit_start, payload) {
+                Some(section) => section,
+                None => return,
+            };
+            if pid == PAT_PID {
+                self.pmt_pids.extend(parse_pat(&section));
+            } else if let Some((version, services)) = parse_pmt(&section, &mut self.es_pids) {
+                // Tables repeat all the time; only read a new version.
+                if self.pmt_versions.insert(pid, version) != Some(version) {
+                    trace!(
+                        "Read version {} of the program map table on PID {}",
+                        version,
+                        pid
+                    );
+                    for service in services {
+                        if !self.stream.services.contains(&service) {
+                            self.stream.services.push(service);
+                        }
+                    }
+                }
+                if self.pmt_versions.len() == self.pmt_pids.len() {
+                    self.replay_pending();
+                }
+            }
+        } else {
+            match &mut self.pending {
+                Some(pending) if pending.len() < MAX_PENDING_PACKETS => {
+                    pending.push(packet.to_vec())
+                }
+                Some(_) => {
+                    self.replay_pending();
+                    self.elementary_packet(pid, unit_start, payload);
+                }
+                None => self.elementary_packet(pid, unit_start, payload),
+            }
+        }
+    }
+
+    /// Handle the packets held back until the program map tables were read.
+    fn replay_pending(&mut self) {
+        for packet in self.pending.take().into_iter().flatten() {
+            let unit_start = packet[1] & 0x40 != 0;
+            let pid = u16::from_be_bytes([packet[1] & 0x1f, packet[2]]);
+            if let Some(payload) = payload(&packet) {
+                self.elementary_packet(pid, unit_start, payload);
+            }
+        }
+    }
+
+    /// Handle a packet of an elementary stream, keeping it if it carries
+    /// subtitles, and noting the first timestamp of the recording.
+    fn elementary_packet(&mut self, pid: u16, unit_start: bool, payload: &[u8]) {
+        if self
+            .stream
+            .services
+            .iter()
+            .any(|service| service.pid == pid)
+        {
+            if unit_start {
+                if let Some(pes) = self.partials.insert(pid, payload.to_vec()) {
+                    self.complete.push((pid, pes));
+                }
+            } else if let Some(pes) = self.partials.get_mut(&pid) {
+                pes.extend_from_slice(payload);
+            }
+        } else if self.start.is_none() && unit_start && self.es_pids.contains(&pid) {
+            self.start = parse_pes(payload).and_then(|(pts, _)| pts);
+        }
+    }
+
+    fn finish(mut self) -> TransportStream {
+        self.complete.extend(self.partials);
+        let start = self.start.unwrap_or(0);
+        self.stream.packets = self
+            .complete
+            .into_iter()
+            .filter_map(|(pid, pes)| {
+                let (pts, payload) = parse_pes(&pes)?;
+                Some(Pes {
+                    pid,
+                    seconds: (pts?.wrapping_sub(start) & TIMESTAMP_MASK) as f64 / CLOCK_RATE,
+                    data: payload.to_vec(),
+                })
+            })
+            .collect();
+        // Flushing the partial packets at the end may have put them out of
+        // order.
+        self.stream
+            .packets
+            .sort_by(|a, b| a.seconds.total_cmp(&b.seconds));
+        self.stream
+    }
+}
+
+/// The payload of a transport stream packet, after any adaptation field.
+fn payload(packet: &[u8]) -> Option<&[u8]> {
+    match packet[3] >> 4 & 0x03 {
+        0x01 => Some(&packet[4..]),
+        0x03 => packet.get(5 + packet[4] as usize..),
+        _ => None,
+    }
+}
+
+/// Add a packet's payload to the PSI section being reassembled on its PID,
+/// returning the section once it is complete.
+fn read_section(
+    sections: &mut HashMap<u16, Vec<u8>>,
+    pid: u16,
+    unit_start: bool,
+    payload: &[u8],
+) -> Option<Vec<u8>> {
+    if unit_start {
+        // The pointer field says where the new section starts.
+        let pointer = *payload.first()? as usize;
+        sections.insert(pid, payload.get(1 + pointer..)?.to_vec());
+    } else {
+        sections.get_mut(&pid)?.extend_from_slice(payload);
+    }
+    let section = &sections[&pid];
+    let length = 3 + (u16::from_be_bytes([*section.get(1)?, *section.get(2)?]) & 0x0fff) as usize;
+    if section.len() < length {
+        return None;
+    }
+    let mut section = sections.remove(&pid)?;
+    section.truncate(length);
+    Some(section)
+}
+
+/// Find the PIDs of the program map tables in a program association table.
+fn parse_pat(section: &[u8]) -> Vec<u16> {
+    if section.first() != Some(&PAT_TABLE_ID) || section.len() < 12 {
+        return Vec::new();
+    }
+    // Skip the header, and leave off the CRC at the end.
+    section[8..section.len() - 4]
+        .chunks_exact(4)
+        .filter(|entry| entry[..2] != [0, 0])
+        .map(|entry| u16::from_be_bytes([entry[2] & 0x1f, entry[3]]))
+        .collect()
+}
+
+/// Find the version and DVB subtitle services of a program map table, and
+/// record all of its elementary streams. Returns `None` if this is not a
+/// complete table which applies now.
+fn parse_pmt(section: &[u8], es_pids: &mut HashSet<u16>) -> Option<(u8, Vec<SubtitleService>)> {
+    // Tables which are not current yet have the last bit of the version
+    // byte cleared.
+    if *section.first()? != PMT_TABLE_ID || section.len() < 16 || section[5] & 0x01 == 0 {
+        return None;
+    }
+    let version = section[5] >> 1 & 0x1f;
+    let program_info_length = (u16::from_be_bytes([section[10], section[11]]) & 0x0fff) as usize;
+    let mut streams = section.get(12 + program_info_length..section.len() - 4)?;
+    let mut services = Vec::new();
+    while let [stream_type, pid_hi, pid_lo, info_hi, info_lo, rest @ ..] = streams {
+        let pid = u16::from_be_bytes([pid_hi & 0x1f, *pid_lo]);
+        let info_length = (u16::from_be_bytes([*info_hi, *info_lo]) & 0x0fff) as usize;
+        let mut descriptors = rest.get(..info_length)?;
+        streams = &rest[info_length..];
+        es_pids.insert(pid);
+        if *stream_type != PRIVATE_PES_STREAM_TYPE {
+            continue;
+        }
+        while let [tag, length, rest @ ..] = descriptors {
+            let descriptor = rest.get(..*length as usize)?;
+            descriptors = &rest[descriptor.len()..];
+            if *tag != SUBTITLING_DESCRIPTOR {
+                continue;
+            }
+            for entry in descriptor.chunks_exact(8) {
+                if !DVB_SUBTITLE_TYPES
+                    .iter()
+                    .any(|types| types.contains(&entry[3]))
+                {
+                    continue;
+                }
+                services.push(SubtitleService {
+                    pid,
+                    language: String::from_utf8_lossy(&entry[..3]).to_ascii_lowercase(),
+                    composition_page: u16::from_be_bytes([entry[4], entry[5]]),
+                    ancillary_page: u16::from_be_bytes([entry[6], entry[7]]),
+                });
+            }
+        }
+    }
+    Some((version, services))
+}
+
+/// Parse a PES packet from its start code, returning the PTS, if any, and the
+/// payload.
+fn parse_pes(pes: &[u8]) -> Option<(Option<u64>, &[u8])> {
+    if pes.get(..3)? != [0x00, 0x00, 0x01] {
+        return None;
+    }
+    ps::parse_pes_packet(pes.get(6..)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PMT_PID: u16 = 0x100;
+    const VIDEO_PID: u16 = 0x101;
+    const SUBTITLE_PID: u16 = 0x102;
+
+    /// Split a payload into transport stream packets, padding the last one
+    /// with an adaptation field.
+    fn packets(pid: u16, payload: &[u8]) -> Vec<u8> {
+        let mut out = Vec::new();
+        for (i, chunk) in payload.chunks(PACKET_SIZE - 4).enumerate() {
+            let unit_start = if i == 0 { 0x40 } else { 0x00 };
+            out.extend_from_slice(&[SYNC_BYTE, unit_start | (pid >> 8) as u8, pid as u8]);
+            let padding = PACKET_SIZE - 4 - chunk.len();
+            if padding == 0 {
+                out.push(0x10);
+            } else {
+                out.push(0x30);
+                out.push(padding as u8 - 1);
+                if padding > 1 {
+                    out.push(0x00);
+                    out.resize(out.len() + padding - 2, 0xff);
+                }
+            }
+            out.extend_from_slice(chunk);
+        }
+        out
+    }
+
+    /// A section with a pointer field in front, and a dummy CRC.
+    fn section(table_id: u8, version: u8, body: &[u8]) -> Vec<u8> {
+        let length = 5 + body.len() + 4;
+        let mut section = vec![0x00, table_id, 0xb0 | (length >> 8) as u8, length as u8];
+        section.extend_from_slice(&[0x00, 0x01, 0xc1 | version << 1, 0x00, 0x00]);
+        section.extend_from_slice(body);
+        section.extend_from_slice(&[0; 4]);
+        section
+    }
+
+    fn pat() -> Vec<u8> {
+        let program = [0x00, 0x01, 0xe0 | (PMT_PID >> 8) as u8, PMT_PID as u8];
+        packets(PAT_PID, &section(PAT_TABLE_ID, 0, &program))
+    }
+
+    fn pmt(version: u8, with_subtitles: bool) -> Vec<u8> {
+        let mut body = vec![0xe1, 0x01, 0xf0, 0x00];
+        body.extend_from_slice(&[0x02, 0xe1, 0x01, 0xf0, 0x00]);
+        if with_subtitles {
+            body.extend_from_slice(&[0x06, 0xe1, 0x02, 0xf0, 10, SUBTITLING_DESCRIPTOR, 8]);
+            body.extend_from_slice(b"DEU");
+            body.extend_from_slice(&[0x10, 0x00, 0x01, 0x00, 0x02]);
+        }
+        packets(PMT_PID, &section(PMT_TABLE_ID, version, &body))
+    }
+
+    fn pes(pid: u16, pts: u64, data: &[u8]) -> Vec<u8> {
+        let mut pes = vec![0x00, 0x00, 0x01, 0xbd];
+        pes.extend_from_slice(&(8 + data.len() as u16).to_be_bytes());
+        pes.extend_from_slice(&[
+            0x81,
+            0x80,
+            0x05,
+            0x21 | (pts >> 29 & 0x0e) as u8,
+            (pts >> 22) as u8,
+            0x01 | (pts >> 14 & 0xfe) as u8,
+            (pts >> 7) as u8,
+            0x01 | (pts << 1 & 0xfe) as u8,
+        ]);
+        pes.extend_from_slice(data);
+        packets(pid, &pes)
+    }
+
+    fn subtitle_data() -> Vec<u8> {
+        (0..300).map(|i| i as u8).collect()
+    }
+
+    fn demux(parts: &[Vec<u8>]) -> TransportStream {
+        demux_subtitles(&parts.concat()[..], PACKET_SIZE).unwrap()
+    }
+
+    #[test]
+    fn demuxes_subtitle_services() {
+        let stream = demux(&[
+            pat(),
+            pmt(0, true),
+            pes(VIDEO_PID, 90_000, &[0; 10]),
+            pes(SUBTITLE_PID, 180_000, &subtitle_data()),
+        ]);
+        assert_eq!(
+            stream.services,
+            [SubtitleService {
+                pid: SUBTITLE_PID,
+                language: "deu".to_owned(),
+                composition_page: 1,
+                ancillary_page: 2,
+            }]
+        );
+        assert_eq!(stream.packets.len(), 1);
+        assert_eq!(stream.packets[0].pid, SUBTITLE_PID);
+        assert_eq!(stream.packets[0].seconds, 1.0);
+        assert_eq!(stream.packets[0].data, subtitle_data());
+    }
+
+    #[test]
+    fn keeps_packets_from_before_the_pmt() {
+        let stream = demux(&[
+            pes(VIDEO_PID, 90_000, &[0; 10]),
+            pes(SUBTITLE_PID, 180_000, &subtitle_data()),
+            pat(),
+            pmt(0, true),
+        ]);
+        assert_eq!(stream.services.len(), 1);
+        assert_eq!(stream.packets.len(), 1);
+        assert_eq!(stream.packets[0].seconds, 1.0);
+    }
+
+    #[test]
+    fn reads_new_pmt_versions() {
+        let stream = demux(&[
+            pat(),
+            pmt(0, false),
+            pes(VIDEO_PID, 90_000, &[0; 10]),
+            pmt(0, false),
+            pmt(1, true),
+            pes(SUBTITLE_PID, 180_000, &subtitle_data()),
+            pmt(1, true),
+        ]);
+        assert_eq!(stream.services.len(), 1);
+        assert_eq!(stream.packets.len(), 1);
+        assert_eq!(stream.packets[0].data, subtitle_data());
+    }
+
+    #[test]
+    fn carries_timestamps_across_wraparound() {
+        let start = TIMESTAMP_MASK + 1 - 90_000;
+        let stream = demux(&[
+            pat(),
+            pmt(0, true),
+            pes(VIDEO_PID, start, &[0; 10]),
+            pes(SUBTITLE_PID, start + 45_000, &[1; 10]),
+            pes(SUBTITLE_PID, 180_000, &[2; 10]),
+        ]);
+        let seconds: Vec<f64> = stream.packets.iter().map(|pes| pes.seconds).collect();
+        assert_eq!(seconds, [0.5, 3.0]);
+    }
+
+    #[test]
+    fn ignores_truncated_packets() {
+        let mut data = [
+            pat(),
+            pmt(0, true),
+            pes(VIDEO_PID, 90_000, &[0; 10]),
+            pes(SUBTITLE_PID, 180_000, &[1; 10]),
+            pes(SUBTITLE_PID, 270_000, &[2; 10]),
+        ]
+        .concat();
+        data.truncate(data.len() - PACKET_SIZE / 2);
+        let stream = demux_subtitles(&data[..], PACKET_SIZE).unwrap();
+        assert_eq!(stream.packets.len(), 1);
+        assert_eq!(stream.packets[0].data, [1; 10]);
+    }
+
+    #[test]
+    fn rejects_truncated_pmts() {
+        let mut es_pids = HashSet::new();
+        // The descriptors of the second stream run past the end.
+        let truncated = &section(
+            PMT_TABLE_ID,
+            0,
+            &[0xe1, 0x01, 0xf0, 0x00, 0x06, 0xe1, 0x02, 0xf0, 10],
+        )[1..];
+        assert_eq!(parse_pmt(truncated, &mut es_pids), None);
+        let empty = &section(PMT_TABLE_ID, 3, &[0xe1, 0x01, 0xf0, 0x00])[1..];
+        assert_eq!(parse_pmt(empty, &mut es_pids), Some((3, Vec::new())));
+    }
+}
@@ -0,0 +1,140 @@
+//! Upscaling line images, since Tesseract does poorly on the small fonts of
+//! many DVD subtitles.
+
+use clap::ValueEnum;
+use image::{
+    imageops::{self, FilterType},
+    GrayImage, Luma,
+};
+
+/// Largest factor a line is scaled up by.
+const MAX_FACTOR: f32 = 8.0;
+
+/// How to scale up line images.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Upscaler {
+    /// Leave images at their original size.
+    None,
+    /// Repeat pixels by a whole factor.
+    Nearest,
+    /// Interpolate bilinearly, then binarize again.
+    Bilinear,
+    /// Scale by powers of two with the Scale2x pixel-art scaler, which
+    /// smooths diagonal edges without blurring.
+    Scale2x,
+}
+
+/// Scale up a binarized image so that its x-height is at least the target.
+pub fn upscale(image: GrayImage, upscaler: Upscaler, x_height: u32, target: u32) -> GrayImage {
+    let ratio = (target as f32 / x_height.max(1) as f32).min(MAX_FACTOR);
+    if ratio <= 1.0 {
+        return image;
+    }
+    match upscaler {
+        Upscaler::None => image,
+        Upscaler::Nearest => {
+            let factor = ratio.ceil() as u32;
+            imageops::resize(
+                &image,
+                image.width() * factor,
+                image.height() * factor,
+                FilterType::Nearest,
+            )
+        }
+        Upscaler::Bilinear => {
+            let mut scaled = imageops::resize(
+                &image,
+                (image.width() as f32 * ratio).round() as u32,
+                (image.height() as f32 * ratio).round() as u32,
+                FilterType::Triangle,
+            );
+            for pixel in scaled.pixels_mut() {
+                *pixel = Luma([if pixel[0] < 128 { 0 } else { 255 }]);
+            }
+            scaled
+        }
+        Upscaler::Scale2x => {
+            let mut scaled = image;
+            let mut factor = 1.0;
+            while factor < ratio {
+                scaled = scale2x(&scaled);
+                factor *= 2.0;
+            }
+            scaled
+        }
+    }
+}
+
+/// Double the size of an image with the Scale2x algorithm: each pixel
+/// becomes four, and each of those takes the color of the two neighbours it
+/// touches if they agree and the other two neighbours don't.
+fn scale2x(image: &GrayImage) -> GrayImage {
+    let (width, height) = image.dimensions();
+    let pixel = |x: i64, y: i64| {
+        let x = x.clamp(0, width as i64 - 1) as u32;
+        let y = y.clamp(0, height as i64 - 1) as u32;
+        *image.get_pixel(x, y)
+    };
+    let mut scaled = GrayImage::new(width * 2, height * 2);
+    for y in 0..height {
+        for x in 0..width {
+            let (x, y) = (x as i64, y as i64);
+            let center = pixel(x, y);
+            let above = pixel(x, y - 1);
+            let right = pixel(x + 1, y);
+            let left = pixel(x - 1, y);
+            let below = pixel(x, y + 1);
+            let corner = |a: Luma<u8>, b: Luma<u8>, c: Luma<u8>, d: Luma<u8>| {
+                if a == b && a != c && b != d {
+                    a
+                } else {
+                    center
+                }
+            };
+            let (sx, sy) = (x as u32 * 2, y as u32 * 2);
+            scaled.put_pixel(sx, sy, corner(left, above, below, right));
+            scaled.put_pixel(sx + 1, sy, corner(above, right, left, below));
+            scaled.put_pixel(sx, sy + 1, corner(below, left, right, above));
+            scaled.put_pixel(sx + 1, sy + 1, corner(right, below, above, left));
+        }
+    }
+    scaled
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn image(rows: &[&[u8]]) -> GrayImage {
+        GrayImage::from_fn(rows[0].len() as u32, rows.len() as u32, |x, y| {
+            Luma([rows[y as usize][x as usize]])
+        })
+    }
+
+    #[test]
+    fn scale2x_smooths_a_diagonal() {
+        let scaled = scale2x(&image(&[&[0, 255], &[255, 0]]));
+        let expected = image(&[
+            &[0, 0, 255, 255],
+            &[0, 255, 0, 255],
+            &[255, 0, 255, 0],
+            &[255, 255, 0, 0],
+        ]);
+        assert_eq!(scaled, expected);
+    }
+
+    #[test]
+    fn caps_the_factor() {
+        let scaled = upscale(image(&[&[0, 255]]), Upscaler::Nearest, 1, 100);
+        assert_eq!(scaled.dimensions(), (16, 8));
+    }
+
+    #[test]
+    fn leaves_large_enough_text() {
+        let original = image(&[&[0, 255], &[255, 0]]);
+        for upscaler in [Upscaler::Nearest, Upscaler::Bilinear, Upscaler::Scale2x] {
+            assert_eq!(upscale(original.clone(), upscaler, 20, 20), original);
+            assert_eq!(upscale(original.clone(), upscaler, 30, 20), original);
+        }
+    }
+}